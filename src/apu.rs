@@ -1,14 +1,33 @@
 #[allow(dead_code)]
 use crate::byteop::*;
 use crate::registers::*;
-use crate::{memory::Memory, runtime::Runtime};
+use crate::memory::IoDevice;
+use crate::ppu::DOTS_PER_FRAME;
+use crate::runtime::Runtime;
+use crate::state::{put_f32, put_u16, Reader, Snapshot};
 use sdl2::audio::{AudioCallback, AudioSpecDesired};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 const CHAN_LEFT: usize = 0;
 const CHAN_RIGHT: usize = 1;
+pub const VOICES: usize = 4;
 
 pub struct APU {
     pub spec: AudioSpecDesired,
+    // silences the output without stopping the channels, e.g. while rewinding.
+    pub muted: bool,
+    // per voice mute, ignored while a voice is soloed
+    pub mute: [bool; VOICES],
+    pub solo: Option<usize>,
+    // mono output of each voice in the last audio buffer, muted voices included
+    pub scopes: [Vec<f32>; VOICES],
+    // samples per channel handed to the audio device so far, counted on the audio thread
+    pub played: Arc<AtomicU64>,
+    // one voice of the audio buffer being mixed, allocated once off the audio thread. The apu
+    // belongs to the audio device, the emulation reaches it under the device lock.
+    scratch: Vec<f32>,
 
     master_volume: f32,
     chan_volume: [f32; 2],
@@ -18,6 +37,279 @@ pub struct APU {
     voice4: Voice4,
 }
 
+/// Sound registers and wave RAM (0xFF10-0xFF3F), written by the cpu and read by the apu.
+pub struct Sound {
+    regs: [u8; 0x30],
+    // voices started by a write to NRx4 with bit 7 set, until the apu takes them
+    triggers: u8,
+    // voices playing, set by the apu and reported in NR52
+    pub active: u8,
+    // a register was written since the apu last looked
+    written: bool,
+}
+
+impl Sound {
+    pub fn new() -> Self {
+        return Self {
+            regs: [0; 0x30],
+            triggers: 0,
+            active: 0,
+            written: false,
+        };
+    }
+
+    /// Returns whether a register was written since the last call.
+    pub fn take_written(&mut self) -> bool {
+        return std::mem::take(&mut self.written);
+    }
+
+    /// Returns whether `voice` was triggered since the last call.
+    pub fn take_trigger(&mut self, voice: usize) -> bool {
+        let triggered = get_bit(self.triggers, voice as u8) == 1;
+        self.triggers = set_bit(self.triggers, voice as u8, false);
+        return triggered;
+    }
+
+    /// The register as written, without the voice flags of NR52.
+    pub fn reg(&self, addr: u16) -> u8 {
+        return self.regs[(addr - NR10) as usize];
+    }
+
+    /// Writes back the period of voice 1 after a frequency sweep.
+    pub fn set_period1(&mut self, period: u16) {
+        self.regs[(NR13 - NR10) as usize] = period as u8;
+        let nr14 = self.regs[(NR14 - NR10) as usize];
+        self.regs[(NR14 - NR10) as usize] = (nr14 & !0b111) | (period >> 8) as u8 & 0b111;
+    }
+}
+
+impl IoDevice for Sound {
+    fn read_io(&self, addr: u16) -> u8 {
+        let val = self.regs[(addr - NR10) as usize];
+        return match addr {
+            NR52 => val & 0x80 | self.active,
+            _ => val,
+        };
+    }
+
+    fn write_io(&mut self, addr: u16, val: u8) {
+        self.written = true;
+        let reg = &mut self.regs[(addr - NR10) as usize];
+        match addr {
+            NR14 | NR24 | NR34 | NR44 => {
+                if get_bit(val, 7) == 1 {
+                    let voice = [NR14, NR24, NR34, NR44].iter().position(|&a| a == addr);
+                    self.triggers |= 1 << voice.unwrap();
+                }
+                *reg = val & 0x7F;
+            }
+            // only the power can be switched, the voice flags are read only
+            NR52 => *reg = val & 0x80,
+            _ => *reg = val,
+        }
+    }
+}
+
+impl Snapshot for Sound {
+    fn save(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.regs);
+        out.push(self.triggers);
+        out.push(self.active);
+        out.push(self.written as u8);
+    }
+
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()> {
+        inp.bytes(&mut self.regs)?;
+        self.triggers = inp.u8()?;
+        self.active = inp.u8()?;
+        self.written = inp.bool()?;
+        return Ok(());
+    }
+}
+
+// the voices and volumes, what NR52 and the length counters show to the cpu. The settings of the
+// player (mute, solo) and the audio buffers aren't part of the machine.
+impl Snapshot for APU {
+    fn save(&self, out: &mut Vec<u8>) {
+        put_f32(out, self.master_volume);
+        put_f32(out, self.chan_volume[0]);
+        put_f32(out, self.chan_volume[1]);
+        self.voice1.save(out);
+        self.voice2.save(out);
+        self.voice3.save(out);
+        self.voice4.save(out);
+    }
+
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()> {
+        self.master_volume = inp.f32()?;
+        self.chan_volume[0] = inp.f32()?;
+        self.chan_volume[1] = inp.f32()?;
+        self.voice1.load(inp)?;
+        self.voice2.load(inp)?;
+        self.voice3.load(inp)?;
+        self.voice4.load(inp)?;
+        return Ok(());
+    }
+}
+
+impl Snapshot for Voice1 {
+    fn save(&self, out: &mut Vec<u8>) {
+        put_f32(out, self.phase);
+        out.extend_from_slice(&[
+            self.pace,
+            self.direction,
+            self.step,
+            self.wave_duty,
+            self.length,
+            self.volume,
+            self.envelope,
+            self.sweep,
+        ]);
+        put_u16(out, self.period);
+        out.push(self.period_sweeps);
+        out.push(self.length_enable as u8);
+        put_f32(out, self.chan_volume[0]);
+        put_f32(out, self.chan_volume[1]);
+        out.push(self.on as u8);
+        out.push(self.dac_on as u8);
+        put_u16(out, self.sweep_vol_timer as u16);
+        put_u16(out, self.sweep_len_timer as u16);
+        put_u16(out, self.sweep_freq_timer as u16);
+    }
+
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()> {
+        self.phase = inp.f32()?;
+        self.pace = inp.u8()?;
+        self.direction = inp.u8()?;
+        self.step = inp.u8()?;
+        self.wave_duty = inp.u8()?;
+        self.length = inp.u8()?;
+        self.volume = inp.u8()?;
+        self.envelope = inp.u8()?;
+        self.sweep = inp.u8()?;
+        self.period = inp.u16()?;
+        self.period_sweeps = inp.u8()?;
+        self.length_enable = inp.bool()?;
+        self.chan_volume[0] = inp.f32()?;
+        self.chan_volume[1] = inp.f32()?;
+        self.on = inp.bool()?;
+        self.dac_on = inp.bool()?;
+        self.sweep_vol_timer = inp.u16()? as i16;
+        self.sweep_len_timer = inp.u16()? as i16;
+        self.sweep_freq_timer = inp.u16()? as i16;
+        return Ok(());
+    }
+}
+
+impl Snapshot for Voice2 {
+    fn save(&self, out: &mut Vec<u8>) {
+        put_f32(out, self.phase);
+        out.extend_from_slice(&[
+            self.wave_duty,
+            self.length,
+            self.volume,
+            self.envelope,
+            self.sweep,
+        ]);
+        put_u16(out, self.period);
+        out.push(self.length_enable as u8);
+        out.push(self.trigger as u8);
+        put_f32(out, self.chan_volume[0]);
+        put_f32(out, self.chan_volume[1]);
+        out.push(self.on as u8);
+        out.push(self.dac_on as u8);
+        put_u16(out, self.sweep_len_timer as u16);
+        put_u16(out, self.sweep_vol_timer as u16);
+    }
+
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()> {
+        self.phase = inp.f32()?;
+        self.wave_duty = inp.u8()?;
+        self.length = inp.u8()?;
+        self.volume = inp.u8()?;
+        self.envelope = inp.u8()?;
+        self.sweep = inp.u8()?;
+        self.period = inp.u16()?;
+        self.length_enable = inp.bool()?;
+        self.trigger = inp.bool()?;
+        self.chan_volume[0] = inp.f32()?;
+        self.chan_volume[1] = inp.f32()?;
+        self.on = inp.bool()?;
+        self.dac_on = inp.bool()?;
+        self.sweep_len_timer = inp.u16()? as i16;
+        self.sweep_vol_timer = inp.u16()? as i16;
+        return Ok(());
+    }
+}
+
+impl Snapshot for Voice3 {
+    fn save(&self, out: &mut Vec<u8>) {
+        out.push(self.dac as u8);
+        put_f32(out, self.phase);
+        out.push(self.length_enable as u8);
+        put_u16(out, self.length);
+        put_f32(out, self.volume);
+        put_u16(out, self.period);
+        out.push(self.on as u8);
+        out.push(self.idx);
+        out.extend_from_slice(&self.pattern);
+        put_u16(out, self.sweep_len_timer as u16);
+    }
+
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()> {
+        self.dac = inp.bool()?;
+        self.phase = inp.f32()?;
+        self.length_enable = inp.bool()?;
+        self.length = inp.u16()?;
+        self.volume = inp.f32()?;
+        self.period = inp.u16()?;
+        self.on = inp.bool()?;
+        self.idx = inp.u8()?;
+        inp.bytes(&mut self.pattern)?;
+        self.sweep_len_timer = inp.u16()? as i16;
+        return Ok(());
+    }
+}
+
+impl Snapshot for Voice4 {
+    fn save(&self, out: &mut Vec<u8>) {
+        put_f32(out, self.phase);
+        out.push(self.on as u8);
+        out.extend_from_slice(&[
+            self.length,
+            self.clock_shift,
+            self.lfsr_width,
+            self.clock_div,
+        ]);
+        out.push(self.length_enable as u8);
+        put_u16(out, self.lfsr);
+        out.extend_from_slice(&[self.volume, self.envelope, self.sweep]);
+        put_u16(out, self.sweep_len_timer as u16);
+        put_u16(out, self.sweep_vol_timer as u16);
+        out.push(self.lfsr_bit);
+        out.push(self.dac_on as u8);
+    }
+
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()> {
+        self.phase = inp.f32()?;
+        self.on = inp.bool()?;
+        self.length = inp.u8()?;
+        self.clock_shift = inp.u8()?;
+        self.lfsr_width = inp.u8()?;
+        self.clock_div = inp.u8()?;
+        self.length_enable = inp.bool()?;
+        self.lfsr = inp.u16()?;
+        self.volume = inp.u8()?;
+        self.envelope = inp.u8()?;
+        self.sweep = inp.u8()?;
+        self.sweep_len_timer = inp.u16()? as i16;
+        self.sweep_vol_timer = inp.u16()? as i16;
+        self.lfsr_bit = inp.u8()?;
+        self.dac_on = inp.bool()?;
+        return Ok(());
+    }
+}
+
 fn wave_duty_lookup(value: u8) -> f32 {
     return match value {
         0 => 0.125,
@@ -28,18 +320,41 @@ fn wave_duty_lookup(value: u8) -> f32 {
     };
 }
 const FREQ: f32 = 44100.0;
+/// Samples played during a frame of the ppu, at 4194304 dots per second.
+pub const FRAME_SAMPLES: f32 = FREQ * DOTS_PER_FRAME as f32 / 4194304.0;
+
+/// What a voice is currently playing, for the audio debug view.
+#[derive(Debug, Default, PartialEq)]
+pub struct VoiceStatus {
+    // not available on the noise channel
+    pub period: Option<u16>,
+    // pulse channels only
+    pub duty: Option<u8>,
+    // 0..=15, the output level for the wave channel
+    pub volume: u8,
+    pub length: u16,
+    pub on: bool,
+    pub dac_on: bool,
+}
 
 impl APU {
     pub fn new() -> Self {
         const CHANNELS: u8 = 2;
+        const SAMPLES: u16 = 256; // a power of 2, the audio buffer size in samples
 
         APU {
             spec: AudioSpecDesired {
                 freq: Some(FREQ as i32), // samples per seconds
                 channels: Some(CHANNELS),
-                samples: Some(256), // a power of 2, the audio buffer size in samples
+                samples: Some(SAMPLES),
             },
             chan_volume: [0.0, 0.0],
+            muted: false,
+            mute: [false; VOICES],
+            solo: None,
+            scopes: Default::default(),
+            played: Arc::new(AtomicU64::new(0)),
+            scratch: vec![0.0; SAMPLES as usize * CHANNELS as usize],
 
             master_volume: 0.0,
             voice1: Voice1::default(),
@@ -49,8 +364,10 @@ impl APU {
         }
     }
 
+    /// Steps the voices, when `Runtime::take_apu` says so: they only change on register writes
+    /// and on the ticks of the DIV-APU.
     pub fn update(&mut self, ticks: u8, rt: &mut Runtime) {
-        let nr52 = rt.get(NR52);
+        let nr52 = rt.memory.sound.reg(NR52);
         let audio_on = get_bit(nr52, 7);
 
         if audio_on == 1 {
@@ -59,7 +376,7 @@ impl APU {
             self.master_volume = 0.0;
         }
 
-        let nr50 = rt.get(NR50);
+        let nr50 = rt.memory.sound.reg(NR50);
 
         // volume 0 is 1/8, vol 8 is 1
         self.chan_volume[CHAN_LEFT] = (((nr50 & 0b1110000) >> 4) as f32 + 1.0) / 8.0;
@@ -83,7 +400,45 @@ impl APU {
         if self.voice4.is_active() {
             mask |= 0b1000;
         }
-        rt.set(NR52, rt.get(NR52) | mask);
+        rt.memory.sound.active = mask;
+    }
+
+    pub fn toggle_mute(&mut self, voice: usize) {
+        self.mute[voice] = !self.mute[voice];
+    }
+
+    /// Plays only `voice`, soloing it again goes back to the muted voices.
+    pub fn toggle_solo(&mut self, voice: usize) {
+        self.solo = if self.solo == Some(voice) {
+            None
+        } else {
+            Some(voice)
+        };
+    }
+
+    pub fn is_audible(&self, voice: usize) -> bool {
+        return match self.solo {
+            Some(solo) => solo == voice,
+            None => !self.mute[voice],
+        };
+    }
+
+    pub fn status(&self) -> [VoiceStatus; VOICES] {
+        return [
+            self.voice1.status(),
+            self.voice2.status(),
+            self.voice3.status(),
+            self.voice4.status(),
+        ];
+    }
+
+    fn voice(&mut self, voice: usize) -> &mut dyn BitChannel {
+        return match voice {
+            0 => &mut self.voice1,
+            1 => &mut self.voice2,
+            2 => &mut self.voice3,
+            _ => &mut self.voice4,
+        };
     }
 }
 
@@ -124,6 +479,8 @@ trait BitChannel {
 
     // updates the channel, reading what it should output from memory.
     fn tick(&mut self, ticks: u8, rt: &mut Runtime);
+
+    fn status(&self) -> VoiceStatus;
 }
 
 impl BitChannel for Voice1 {
@@ -132,14 +489,14 @@ impl BitChannel for Voice1 {
     }
 
     fn tick(&mut self, _ticks: u8, rt: &mut Runtime) {
-        self.dac_on = (rt.get(NR12) & 0xf8) != 0;
+        self.dac_on = (rt.memory.sound.reg(NR12) & 0xf8) != 0;
 
         if self.on & self.dac_on {
             if self.length_enable {
                 // DIV_APU increments by one every 16 ticks (every time bit 4 of div goes from 1 to
                 // 0)
                 // sound length occurs every 2 DIV-APU ticks, so it triggers every 16 * 2 ticks
-                self.sweep_len_timer -= rt.timer.delta_div as i16;
+                self.sweep_len_timer -= rt.memory.timer.delta_div as i16;
 
                 while self.sweep_len_timer <= 0 {
                     self.sweep_len_timer += 2;
@@ -152,7 +509,7 @@ impl BitChannel for Voice1 {
             }
 
             if self.pace != 0 {
-                self.sweep_freq_timer -= rt.timer.delta_div as i16;
+                self.sweep_freq_timer -= rt.memory.timer.delta_div as i16;
                 while self.sweep_freq_timer <= 0 {
                     self.sweep_freq_timer += 4 * (self.pace as i16);
                     let delta = self.period / (1 << self.step);
@@ -166,15 +523,12 @@ impl BitChannel for Voice1 {
                     }
 
                     // write back nr14 & nr13
-                    rt.hwset(NR13, (self.period & 0xF) as u8);
-
-                    let mask = (self.period >> 8) as u8 & 0b111;
-                    rt.hwset(NR14, rt.get(NR14) | mask);
+                    rt.memory.sound.set_period1(self.period);
                 }
             }
 
             if self.sweep != 0 {
-                self.sweep_vol_timer -= rt.timer.delta_div as i16;
+                self.sweep_vol_timer -= rt.memory.timer.delta_div as i16;
                 while self.sweep_vol_timer <= 0 {
                     self.sweep_vol_timer += 8 * (self.sweep as i16);
 
@@ -189,14 +543,14 @@ impl BitChannel for Voice1 {
             }
         }
 
-        let nr10 = rt.get(NR10);
-        let nr11 = rt.get(NR11);
-        let nr12 = rt.get(NR12);
-        let nr13 = rt.get(NR13);
-        let nr14 = rt.get(NR14);
-        let nr51 = rt.get(NR51);
+        let nr10 = rt.memory.sound.reg(NR10);
+        let nr11 = rt.memory.sound.reg(NR11);
+        let nr12 = rt.memory.sound.reg(NR12);
+        let nr13 = rt.memory.sound.reg(NR13);
+        let nr14 = rt.memory.sound.reg(NR14);
+        let nr51 = rt.memory.sound.reg(NR51);
 
-        let trigger = get_bit(nr14, 7) == 1;
+        let trigger = rt.memory.sound.take_trigger(0);
 
         self.chan_volume = [get_bit(nr51, 4) as f32, get_bit(nr51, 0) as f32];
 
@@ -228,10 +582,20 @@ impl BitChannel for Voice1 {
             self.sweep_len_timer = 2; // 256hz
 
             self.on = trigger;
-            rt.hwset(NR14, set_bit(nr14, 7, false));
         }
     }
 
+    fn status(&self) -> VoiceStatus {
+        return VoiceStatus {
+            period: Some(self.period),
+            duty: Some(self.wave_duty),
+            volume: self.volume,
+            length: self.length as u16,
+            on: self.on,
+            dac_on: self.dac_on,
+        };
+    }
+
     fn overlap(&mut self, out: &mut [f32], channels: usize) {
         if !self.is_active() {
             return;
@@ -280,6 +644,16 @@ impl BitChannel for Voice2 {
     fn is_active(&self) -> bool {
         self.on && self.dac_on
     }
+    fn status(&self) -> VoiceStatus {
+        return VoiceStatus {
+            period: Some(self.period),
+            duty: Some(self.wave_duty),
+            volume: self.volume,
+            length: self.length as u16,
+            on: self.on,
+            dac_on: self.dac_on,
+        };
+    }
     fn overlap(&mut self, out: &mut [f32], channels: usize) {
         if !self.is_active() {
             return;
@@ -302,14 +676,14 @@ impl BitChannel for Voice2 {
     }
 
     fn tick(&mut self, _ticks: u8, rt: &mut Runtime) {
-        self.dac_on = (rt.get(NR22) & 0xF8) != 0;
+        self.dac_on = (rt.memory.sound.reg(NR22) & 0xF8) != 0;
 
         if self.on {
             if self.length_enable {
                 // DIV_APU increments by one every 16 ticks (every time bit 4 of div goes from 1 to
                 // 0)
                 // sound length occurs every 2 DIV-APU ticks, so it triggers every 16 * 2 ticks
-                self.sweep_len_timer -= rt.timer.delta_div as i16;
+                self.sweep_len_timer -= rt.memory.timer.delta_div as i16;
 
                 while self.sweep_len_timer <= 0 {
                     self.sweep_len_timer += 2;
@@ -322,7 +696,7 @@ impl BitChannel for Voice2 {
             }
 
             if self.sweep != 0 {
-                self.sweep_vol_timer -= rt.timer.delta_div as i16;
+                self.sweep_vol_timer -= rt.memory.timer.delta_div as i16;
                 while self.sweep_vol_timer <= 0 {
                     self.sweep_vol_timer += 8 * (self.sweep as i16);
 
@@ -337,13 +711,13 @@ impl BitChannel for Voice2 {
             }
         }
 
-        let nr21 = rt.get(NR21);
-        let nr22 = rt.get(NR22);
-        let nr23 = rt.get(NR23);
-        let nr24 = rt.get(NR24);
-        let nr51 = rt.get(NR51);
+        let nr21 = rt.memory.sound.reg(NR21);
+        let nr22 = rt.memory.sound.reg(NR22);
+        let nr23 = rt.memory.sound.reg(NR23);
+        let nr24 = rt.memory.sound.reg(NR24);
+        let nr51 = rt.memory.sound.reg(NR51);
 
-        let trigger = get_bit(nr24, 7) == 1;
+        let trigger = rt.memory.sound.take_trigger(1);
 
         self.chan_volume = [get_bit(nr51, 5) as f32, get_bit(nr51, 1) as f32];
         self.wave_duty = (nr21 & 0b11000000) >> 6;
@@ -364,7 +738,6 @@ impl BitChannel for Voice2 {
             self.sweep_len_timer = 2;
 
             self.on = true;
-            rt.hwset(NR24, set_bit(nr24, 7, false));
         }
     }
 }
@@ -409,6 +782,16 @@ impl BitChannel for Voice3 {
     fn is_active(&self) -> bool {
         return self.on && self.dac;
     }
+    fn status(&self) -> VoiceStatus {
+        return VoiceStatus {
+            period: Some(self.period),
+            duty: None,
+            volume: (self.volume * 15.0) as u8,
+            length: self.length,
+            on: self.on,
+            dac_on: self.dac,
+        };
+    }
     fn overlap(&mut self, out: &mut [f32], channels: usize) {
         if !self.is_active() {
             return;
@@ -433,15 +816,15 @@ impl BitChannel for Voice3 {
     }
 
     fn tick(&mut self, _ticks: u8, rt: &mut Runtime) {
-        let nr30 = rt.get(NR30);
-        let nr31 = rt.get(NR31);
-        let nr32 = rt.get(NR32);
-        let nr33 = rt.get(NR33);
-        let nr34 = rt.get(NR34);
+        let nr30 = rt.memory.sound.reg(NR30);
+        let nr31 = rt.memory.sound.reg(NR31);
+        let nr32 = rt.memory.sound.reg(NR32);
+        let nr33 = rt.memory.sound.reg(NR33);
+        let nr34 = rt.memory.sound.reg(NR34);
 
         if self.on {
             if self.length_enable {
-                self.sweep_len_timer -= rt.timer.delta_div as i16;
+                self.sweep_len_timer -= rt.memory.timer.delta_div as i16;
 
                 while self.sweep_len_timer <= 0 {
                     self.sweep_len_timer += 2;
@@ -461,7 +844,7 @@ impl BitChannel for Voice3 {
 
         self.length_enable = get_bit(nr34, 6) == 1;
 
-        let trigger = get_bit(nr34, 7) == 1;
+        let trigger = rt.memory.sound.take_trigger(2);
 
         if trigger {
             self.idx = 0;
@@ -477,14 +860,13 @@ impl BitChannel for Voice3 {
 
             self.on = true;
             // reset the trigger
-            rt.hwset(NR34, set_bit(nr34, 7, false));
             if self.length == 256 {
                 self.length = nr31 as u16;
             }
         }
 
         for (i, addr) in (0xFF30..=0xFF3F).enumerate() {
-            self.pattern[i] = rt.get(addr);
+            self.pattern[i] = rt.memory.sound.reg(addr);
         }
     }
 }
@@ -541,6 +923,17 @@ impl BitChannel for Voice4 {
         return self.on && self.dac_on;
     }
 
+    fn status(&self) -> VoiceStatus {
+        return VoiceStatus {
+            period: None,
+            duty: None,
+            volume: self.volume,
+            length: self.length as u16,
+            on: self.on,
+            dac_on: self.dac_on,
+        };
+    }
+
     fn overlap(&mut self, out: &mut [f32], channels: usize) {
         if !self.is_active() && self.volume > 0{
             return;
@@ -581,15 +974,15 @@ impl BitChannel for Voice4 {
     }
 
     fn tick(&mut self, _ticks: u8, rt: &mut Runtime) {
-        let nr41 = rt.get(NR41);
-        let nr42 = rt.get(NR42);
-        let nr43 = rt.get(NR43);
-        let nr44 = rt.get(NR44);
+        let nr41 = rt.memory.sound.reg(NR41);
+        let nr42 = rt.memory.sound.reg(NR42);
+        let nr43 = rt.memory.sound.reg(NR43);
+        let nr44 = rt.memory.sound.reg(NR44);
         self.dac_on = (nr42 & 0xf8) != 0;
 
         if self.on {
             if self.length_enable {
-                self.sweep_len_timer -= rt.timer.delta_div as i16;
+                self.sweep_len_timer -= rt.memory.timer.delta_div as i16;
 
                 while self.sweep_len_timer <= 0 {
                     self.sweep_len_timer += 2;
@@ -602,7 +995,7 @@ impl BitChannel for Voice4 {
             }
 
             if self.sweep != 0 {
-                self.sweep_vol_timer -= rt.timer.delta_div as i16;
+                self.sweep_vol_timer -= rt.memory.timer.delta_div as i16;
                 while self.sweep_vol_timer <= 0 {
                     self.sweep_vol_timer += 8 * (self.sweep as i16);
 
@@ -617,9 +1010,9 @@ impl BitChannel for Voice4 {
             }
         }
 
-        let trigger = get_bit(nr44, 7);
+        let trigger = rt.memory.sound.take_trigger(3);
         self.length_enable = get_bit(nr44, 6) == 1;
-        if trigger == 1 {
+        if trigger {
             self.length = nr41 & 0x3F;
             self.phase = 0.0;
             self.sweep_len_timer = 2;
@@ -632,7 +1025,6 @@ impl BitChannel for Voice4 {
 
             self.sweep_vol_timer = 8 * self.sweep as i16;
 
-            rt.hwset(NR44, set_bit(nr44, 7, false));
             self.on = true;
             self.lfsr = 0;
             self.lfsr_bit = 0;
@@ -649,23 +1041,138 @@ fn pi_from_period(period: u16) -> f32 {
     return 1.0 / v;
 }
 
-impl AudioCallback for &mut APU {
+impl AudioCallback for APU {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
         let channels = self.spec.channels.unwrap().into();
+        self.played.fetch_add((out.len() / channels) as u64, Ordering::Relaxed);
 
         for x in out.iter_mut() {
             *x = 0.0;
         }
 
-        self.voice1.overlap(out, channels);
-        self.voice2.overlap(out, channels);
-        self.voice3.overlap(out, channels);
-        self.voice4.overlap(out, channels);
+        if self.muted {
+            return;
+        }
+
+        // every voice is rendered for the scopes, only the audible ones are mixed
+        let mut scratch = std::mem::take(&mut self.scratch);
+        if scratch.len() < out.len() {
+            // the device picked bigger buffers than asked for, only happens once
+            scratch.resize(out.len(), 0.0);
+        }
+        let wave = &mut scratch[..out.len()];
+        for voice in 0..VOICES {
+            wave.fill(0.0);
+            self.voice(voice).overlap(wave, channels);
+
+            self.scopes[voice].clear();
+            self.scopes[voice].extend(wave.iter().step_by(channels));
+
+            if self.is_audible(voice) {
+                for (x, w) in out.iter_mut().zip(wave.iter()) {
+                    *x += w;
+                }
+            }
+        }
+        self.scratch = scratch;
 
         for (i, x) in out.iter_mut().enumerate() {
             *x = (*x / 4.0) * self.chan_volume[i % 2 as usize] * self.master_volume;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playing_apu() -> APU {
+        let mut apu = APU::new();
+        apu.master_volume = 1.0;
+        apu.chan_volume = [1.0, 1.0];
+        apu.voice1.on = true;
+        apu.voice1.dac_on = true;
+        apu.voice1.volume = 0xF;
+        apu.voice1.period = 0x700;
+        apu.voice1.wave_duty = 2;
+        return apu;
+    }
+
+    #[test]
+    fn test_status_of_pulse_voice() {
+        let apu = playing_apu();
+        let status = apu.status();
+        assert_eq!(
+            status[0],
+            VoiceStatus {
+                period: Some(0x700),
+                duty: Some(2),
+                volume: 0xF,
+                length: 0,
+                on: true,
+                dac_on: true,
+            }
+        );
+        assert_eq!(status[3].period, None);
+        assert!(!status[3].on);
+    }
+
+    #[test]
+    fn test_trigger_is_latched_until_taken() {
+        let mut sound = Sound::new();
+        sound.write_io(NR24, 0b1100_0011);
+        assert_eq!(sound.read_io(NR24), 0b0100_0011);
+        assert!(!sound.take_trigger(0));
+        assert!(sound.take_trigger(1));
+        assert!(!sound.take_trigger(1));
+
+        // NR52 only switches the power
+        sound.active = 0b0010;
+        sound.write_io(NR52, 0xFF);
+        assert_eq!(sound.read_io(NR52), 0x82);
+    }
+
+    #[test]
+    fn test_snapshot_restores_the_voices() {
+        let mut apu = playing_apu();
+        apu.voice1.length = 12;
+        apu.voice1.length_enable = true;
+        apu.voice3.pattern[3] = 0x5A;
+        apu.voice4.lfsr = 0x1234;
+        let mut out = vec![];
+        apu.save(&mut out);
+
+        let mut loaded = APU::new();
+        loaded.load(&mut Reader::new(&out)).unwrap();
+        assert_eq!(loaded.status(), apu.status());
+        assert_eq!(loaded.voice3.pattern, apu.voice3.pattern);
+        assert_eq!(loaded.voice4.lfsr, 0x1234);
+        let mut again = vec![];
+        loaded.save(&mut again);
+        assert_eq!(again, out);
+    }
+
+    #[test]
+    fn test_muted_voice_still_shows_on_scope() {
+        let mut apu = playing_apu();
+        let mut out = [0.0; 64];
+        apu.callback(&mut out);
+        assert!(out.iter().any(|x| *x != 0.0));
+        assert_eq!(apu.scopes[0].len(), 32);
+
+        apu.toggle_mute(0);
+        apu.callback(&mut out);
+        assert!(out.iter().all(|x| *x == 0.0));
+        assert!(apu.scopes[0].iter().any(|x| *x != 0.0));
+
+        // soloing overrides the mute
+        apu.toggle_solo(0);
+        assert!(apu.is_audible(0));
+        assert!(!apu.is_audible(1));
+        apu.toggle_solo(0);
+        assert!(!apu.is_audible(0));
+        assert!(apu.is_audible(1));
+    }
+}
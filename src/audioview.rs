@@ -0,0 +1,175 @@
+//! Debug view of the audio voices: an oscilloscope of each voice, its period, duty, volume,
+//! length counter and on/DAC state. Muted voices are drawn dimmed.
+use crate::apu::{VoiceStatus, APU, VOICES};
+use crate::vramview::present;
+use sdl2::pixels::Color;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+const SCOPE_WIDTH: usize = 256;
+const SCOPE_HEIGHT: usize = 32;
+const ROW_HEIGHT: usize = SCOPE_HEIGHT + 8;
+const TEXT_X: usize = SCOPE_WIDTH + 8;
+pub const WIDTH: usize = TEXT_X + 16 * 4;
+pub const HEIGHT: usize = ROW_HEIGHT * VOICES;
+
+const BACKGROUND: Color = Color::RGB(40, 40, 60);
+const AXIS: Color = Color::RGB(70, 70, 90);
+const AUDIBLE: Color = Color::RGB(80, 255, 80);
+const SILENCED: Color = Color::RGB(120, 120, 120);
+const TEXT: Color = Color::RGB(255, 255, 255);
+
+/// 3x5 pixels font, a row per byte with the leftmost pixel on bit 2.
+fn glyph(c: char) -> [u8; 5] {
+    return match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        _ => [0; 5],
+    };
+}
+
+/// Text lines shown next to the scope of a voice, numbers are in hex.
+fn describe(voice: usize, status: &VoiceStatus, audible: bool) -> [String; 3] {
+    let mut state = format!("{} {}", voice + 1, if status.on { "ON" } else { "OFF" });
+    if status.dac_on {
+        state.push_str(" DAC");
+    }
+    if !audible {
+        state.push_str(" MUTE");
+    }
+    let period = match status.period {
+        Some(period) => format!("{:03X}", period),
+        None => "-".to_string(),
+    };
+    let duty = match status.duty {
+        Some(duty) => format!("{}", duty),
+        None => "-".to_string(),
+    };
+    return [
+        state,
+        format!("P {} D {}", period, duty),
+        format!("V {:X} L {:02X}", status.volume, status.length),
+    ];
+}
+
+pub struct AudioView {
+    pub pixels: Vec<Color>,
+}
+
+impl AudioView {
+    pub fn new() -> AudioView {
+        AudioView {
+            pixels: vec![BACKGROUND; WIDTH * HEIGHT],
+        }
+    }
+
+    fn set(&mut self, x: usize, y: usize, color: Color) {
+        self.pixels[x + y * WIDTH] = color;
+    }
+
+    fn draw_text(&mut self, text: &str, left: usize, top: usize) {
+        for (i, c) in text.chars().enumerate() {
+            for (y, row) in glyph(c).iter().enumerate() {
+                for x in 0..3 {
+                    if row & (0b100 >> x) != 0 {
+                        self.set(left + i * 4 + x, top + y, TEXT);
+                    }
+                }
+            }
+        }
+    }
+
+    fn draw_scope(&mut self, samples: &[f32], top: usize, color: Color) {
+        let center = top + SCOPE_HEIGHT / 2;
+        for x in 0..SCOPE_WIDTH {
+            self.set(x, center, AXIS);
+        }
+        for (x, sample) in samples.iter().take(SCOPE_WIDTH).enumerate() {
+            let offset = (sample.clamp(-1.0, 1.0) * (SCOPE_HEIGHT / 2 - 1) as f32) as isize;
+            self.set(x, (center as isize - offset) as usize, color);
+        }
+    }
+
+    pub fn update(&mut self, apu: &APU) {
+        self.pixels.fill(BACKGROUND);
+        for (voice, status) in apu.status().iter().enumerate() {
+            let top = voice * ROW_HEIGHT + 4;
+            let audible = apu.is_audible(voice);
+            let color = if audible { AUDIBLE } else { SILENCED };
+            self.draw_scope(&apu.scopes[voice], top, color);
+
+            for (i, line) in describe(voice, status, audible).iter().enumerate() {
+                self.draw_text(line, TEXT_X, top + i * 8);
+            }
+        }
+    }
+
+    pub fn render(&self, canvas: &mut Canvas<Window>) {
+        present(canvas, &self.pixels, WIDTH);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_voice() {
+        let status = VoiceStatus {
+            period: Some(0x713),
+            duty: Some(2),
+            volume: 0xF,
+            length: 0x3F,
+            on: true,
+            dac_on: true,
+        };
+        assert_eq!(
+            describe(0, &status, true),
+            ["1 ON DAC", "P 713 D 2", "V F L 3F"]
+        );
+
+        let noise = VoiceStatus::default();
+        assert_eq!(
+            describe(3, &noise, false),
+            ["4 OFF MUTE", "P - D -", "V 0 L 00"]
+        );
+    }
+
+    #[test]
+    fn test_scope_follows_samples() {
+        let mut apu = APU::new();
+        apu.scopes[0] = vec![1.0, -1.0, 0.0];
+        let mut view = AudioView::new();
+        view.update(&apu);
+
+        let center = 4 + SCOPE_HEIGHT / 2;
+        assert_eq!(view.pixels[(center - 15) * WIDTH], AUDIBLE);
+        assert_eq!(view.pixels[1 + (center + 15) * WIDTH], AUDIBLE);
+        assert_eq!(view.pixels[2 + center * WIDTH], AUDIBLE);
+        assert_eq!(view.pixels[3 + center * WIDTH], AXIS);
+    }
+}
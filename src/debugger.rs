@@ -0,0 +1,526 @@
+use crate::byteop::{b64, split_u16};
+use crate::disasm::decode_at;
+use crate::memory::{Memory, Watch, WatchHit, Watchpoint};
+use crate::runtime::Runtime;
+use crate::symbols::Symbols;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+const HELP: &str = "\
+s, step [n]          execute n instructions (default 1)
+n, next              step over CALL and RST
+c, continue          run until a breakpoint
+f, finish            run until the current routine returns
+b, break <addr>      set a breakpoint, at an address or a label
+d, delete [addr]     remove a breakpoint, or all of them
+watch <range>        pause when a write changes a value in the range
+rwatch <range>       pause on reads of the range
+wwatch <range>       pause on writes to the range
+unwatch [n]          remove watchpoint n, or all of them
+i, info              list the breakpoints and watchpoints
+r, regs              show the registers
+x <addr> [len]       dump memory (default 16 bytes)
+l, list [addr] [n]   disassemble n instructions (default 10 from PC), addr can be a label
+set <reg> <val>      set a register (a f b c d e h l af bc de hl sp pc)
+w, write <addr> <val> write a byte to memory
+bt, backtrace        show the call stack
+q, quit              exit the emulator
+addresses and values are hexadecimal, ranges are `addr` or `start-end`
+an empty line repeats the last command";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Step(u32),
+    Next,
+    Continue,
+    Finish,
+    Break(String),
+    Delete(Option<u16>),
+    Watch(Watchpoint),
+    Unwatch(Option<usize>),
+    Info,
+    Regs,
+    Examine(u16, u16),
+    List(Option<String>, u16),
+    SetReg(String, u16),
+    Write(u16, u8),
+    Backtrace,
+    Quit,
+    Help,
+}
+
+/// What the emulation is doing until the next prompt.
+#[derive(Debug, PartialEq)]
+enum Mode {
+    Paused,
+    Step(u32),
+    // until PC reaches the return address with the stack unwound to `sp`
+    Until { pc: u16, sp: u16 },
+    // until the call stack is shallower than `depth`
+    Finish { depth: usize },
+    Continue,
+}
+
+pub enum Resume {
+    Run,
+    Quit,
+}
+
+/// Hexadecimal number, optionally prefixed with `$` or `0x`.
+fn parse_hex(arg: &str) -> Result<u16, String> {
+    let digits = arg
+        .strip_prefix("0x")
+        .or(arg.strip_prefix('$'))
+        .unwrap_or(arg);
+    return u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid number `{}´", arg));
+}
+
+/// Address range, `addr` or `start-end`.
+fn parse_range(arg: &str) -> Result<(u16, u16), String> {
+    return match arg.split_once('-') {
+        Some((start, end)) => Ok((parse_hex(start)?, parse_hex(end)?)),
+        None => Ok((parse_hex(arg)?, parse_hex(arg)?)),
+    };
+}
+
+fn watchpoint(kind: Watch, range: &str) -> Result<Command, String> {
+    let (start, end) = parse_range(range)?;
+    if start > end {
+        return Err(format!("Empty range `{}´", range));
+    }
+    return Ok(Command::Watch(Watchpoint { kind, start, end }));
+}
+
+fn parse(line: &str) -> Result<Command, String> {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let arg = |i: usize| -> Result<u16, String> {
+        let val = args.get(i).ok_or("Missing argument, see `help´".to_string())?;
+        return parse_hex(val);
+    };
+
+    return match args[..] {
+        ["s" | "step"] => Ok(Command::Step(1)),
+        ["s" | "step", n] => n
+            .parse()
+            .map(Command::Step)
+            .map_err(|_| format!("Invalid count `{}´", n)),
+        ["n" | "next"] => Ok(Command::Next),
+        ["c" | "continue"] => Ok(Command::Continue),
+        ["f" | "finish"] => Ok(Command::Finish),
+        ["b" | "break", location] => Ok(Command::Break(location.to_string())),
+        ["d" | "delete"] => Ok(Command::Delete(None)),
+        ["d" | "delete", _] => Ok(Command::Delete(Some(arg(1)?))),
+        ["watch", range] => watchpoint(Watch::Change, range),
+        ["rwatch", range] => watchpoint(Watch::Read, range),
+        ["wwatch", range] => watchpoint(Watch::Write, range),
+        ["unwatch"] => Ok(Command::Unwatch(None)),
+        ["unwatch", n] => n
+            .parse()
+            .map(|n| Command::Unwatch(Some(n)))
+            .map_err(|_| format!("Invalid watchpoint `{}´", n)),
+        ["i" | "info"] => Ok(Command::Info),
+        ["r" | "regs"] => Ok(Command::Regs),
+        ["x", _] => Ok(Command::Examine(arg(1)?, 16)),
+        ["x", _, _] => Ok(Command::Examine(arg(1)?, arg(2)?)),
+        ["l" | "list"] => Ok(Command::List(None, 10)),
+        ["l" | "list", location] => Ok(Command::List(Some(location.to_string()), 10)),
+        ["l" | "list", location, n] => {
+            let n = n.parse().map_err(|_| format!("Invalid count `{}´", n))?;
+            Ok(Command::List(Some(location.to_string()), n))
+        }
+        ["set", reg, _] => Ok(Command::SetReg(reg.to_lowercase(), arg(2)?)),
+        ["w" | "write", _, val] => {
+            let byte = u8::try_from(arg(2)?).map_err(|_| format!("Invalid byte `{}´", val))?;
+            Ok(Command::Write(arg(1)?, byte))
+        }
+        ["bt" | "backtrace"] => Ok(Command::Backtrace),
+        ["q" | "quit"] => Ok(Command::Quit),
+        ["h" | "help"] => Ok(Command::Help),
+        _ => Err(format!("Unknown command `{}´, see `help´", line.trim())),
+    };
+}
+
+/// PC breakpoint, set on a label it only stops in the rom bank of the label.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Breakpoint {
+    bank: Option<u8>,
+    addr: u16,
+}
+
+impl Breakpoint {
+    fn matches(&self, bank: u8, pc: u16) -> bool {
+        return pc == self.addr && self.bank.is_none_or(|b| b == bank);
+    }
+}
+
+/// Interactive debugger on stdin, checked before every instruction.
+pub struct Debugger {
+    symbols: Option<Rc<Symbols>>,
+    breakpoints: Vec<Breakpoint>,
+    mode: Mode,
+    last: String,
+    // address and opcode of the last instruction let run, reported on watchpoint hits
+    prev: (u16, u8),
+}
+
+impl Debugger {
+    /// The debugger starts paused on the first instruction.
+    pub fn new(symbols: Option<Rc<Symbols>>) -> Debugger {
+        Debugger {
+            symbols,
+            breakpoints: vec![],
+            mode: Mode::Paused,
+            last: String::from("step"),
+            prev: (0, 0),
+        }
+    }
+
+    /// Pauses at the next instruction.
+    pub fn interrupt(&mut self) {
+        self.mode = Mode::Paused;
+    }
+
+    fn should_stop(&mut self, rt: &Runtime) -> bool {
+        let pc = rt.cpu().pc;
+        let bank = rt.memory.bank_of(pc);
+        let hit = self.breakpoints.iter().any(|bp| bp.matches(bank, pc));
+        if hit && self.mode != Mode::Paused {
+            println!("Breakpoint at {}", self.describe(rt, pc));
+            return true;
+        }
+
+        return match &mut self.mode {
+            Mode::Paused => true,
+            Mode::Step(n) => {
+                *n -= 1;
+                *n == 0
+            }
+            Mode::Until { pc: ret, sp } => pc == *ret && rt.cpu().sp >= *sp,
+            Mode::Finish { depth } => rt.calls.len() < *depth,
+            Mode::Continue => false,
+        };
+    }
+
+    // PC doesn't move while halted, only a break or a step stops there
+    fn should_stop_halted(&mut self) -> bool {
+        return match &mut self.mode {
+            Mode::Paused => true,
+            Mode::Step(n) => {
+                *n -= 1;
+                *n == 0
+            }
+            _ => false,
+        };
+    }
+
+    /// Called before every instruction, prompts for commands when the emulation has to stop.
+    pub fn check(&mut self, rt: &mut Runtime) -> Resume {
+        let hit = rt.memory.take_watch_hit();
+        if let Some(hit) = hit {
+            self.report(hit);
+        }
+
+        let stop = if rt.cpu().halted() {
+            self.should_stop_halted()
+        } else {
+            self.should_stop(rt)
+        };
+        if hit.is_none() && !stop {
+            self.prev = (rt.cpu().pc, rt.get(rt.cpu().pc));
+            return Resume::Run;
+        }
+        self.mode = Mode::Paused;
+        rt.sync();
+        self.show_location(rt);
+
+        let stdin = io::stdin();
+        while self.mode == Mode::Paused {
+            print!("(gbc) ");
+            io::stdout().flush().unwrap();
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                return Resume::Quit;
+            }
+            if line.trim().is_empty() {
+                line = self.last.clone();
+            } else {
+                self.last = line.clone();
+            }
+
+            match parse(&line) {
+                Ok(Command::Quit) => return Resume::Quit,
+                Ok(cmd) => self.execute(cmd, rt),
+                Err(msg) => println!("{}", msg),
+            }
+        }
+        self.prev = (rt.cpu().pc, rt.get(rt.cpu().pc));
+        return Resume::Run;
+    }
+
+    fn report(&self, hit: WatchHit) {
+        let (pc, opcode) = self.prev;
+        match hit.kind {
+            Watch::Read => println!(
+                "Read of {} by {} (opcode {}): {}",
+                b64(hit.addr),
+                b64(pc),
+                b64(opcode),
+                b64(hit.old)
+            ),
+            Watch::Write | Watch::Change => println!(
+                "Write to {} by {} (opcode {}): {} -> {}",
+                b64(hit.addr),
+                b64(pc),
+                b64(opcode),
+                b64(hit.old),
+                b64(hit.new)
+            ),
+        }
+    }
+
+    fn execute(&mut self, cmd: Command, rt: &mut Runtime) {
+        match cmd {
+            Command::Step(n) => self.mode = Mode::Step(n.max(1)),
+            Command::Next => {
+                let pc = rt.cpu().pc;
+                let instr = decode_at(rt, pc);
+                self.mode = if instr.text.starts_with("CALL") || instr.text.starts_with("RST") {
+                    Mode::Until {
+                        pc: pc.wrapping_add(instr.len as u16),
+                        sp: rt.cpu().sp,
+                    }
+                } else {
+                    Mode::Step(1)
+                };
+            }
+            Command::Continue => self.mode = Mode::Continue,
+            Command::Finish => {
+                if rt.calls.is_empty() {
+                    println!("Not inside a routine");
+                } else {
+                    self.mode = Mode::Finish {
+                        depth: rt.calls.len(),
+                    };
+                }
+            }
+            Command::Break(location) => {
+                if let Some((bank, addr)) = self.resolve(&location) {
+                    let bp = Breakpoint { bank, addr };
+                    if !self.breakpoints.contains(&bp) {
+                        self.breakpoints.push(bp);
+                    }
+                    println!("Breakpoint at {}", self.describe_breakpoint(rt, bp));
+                }
+            }
+            Command::Delete(None) => self.breakpoints.clear(),
+            Command::Delete(Some(addr)) => self.breakpoints.retain(|bp| bp.addr != addr),
+            Command::Watch(wp) => rt.memory.watchpoints.push(wp),
+            Command::Unwatch(None) => rt.memory.watchpoints.clear(),
+            Command::Unwatch(Some(n)) => {
+                if n >= 1 && n <= rt.memory.watchpoints.len() {
+                    rt.memory.watchpoints.remove(n - 1);
+                } else {
+                    println!("No watchpoint {}", n);
+                }
+            }
+            Command::Info => {
+                for bp in &self.breakpoints {
+                    println!("break {}", self.describe_breakpoint(rt, *bp));
+                }
+                for (i, wp) in rt.memory.watchpoints.iter().enumerate() {
+                    println!(
+                        "{}: {:?} {}-{}",
+                        i + 1,
+                        wp.kind,
+                        b64(wp.start),
+                        b64(wp.end)
+                    );
+                }
+            }
+            Command::Regs => println!("{:?} IME:{}", rt.cpu(), rt.cpu().ime() as u8),
+            Command::Examine(addr, len) => {
+                for row in (0..len).step_by(16) {
+                    let start = addr.wrapping_add(row);
+                    let bytes: Vec<String> = (0..(len - row).min(16))
+                        .map(|i| b64(rt.get(start.wrapping_add(i))))
+                        .collect();
+                    println!("{}: {}", b64(start), bytes.join(" "));
+                }
+            }
+            Command::List(location, n) => {
+                let mut addr = match location {
+                    Some(location) => match self.resolve(&location) {
+                        Some((_, addr)) => addr,
+                        None => return,
+                    },
+                    None => rt.cpu().pc,
+                };
+                for _ in 0..n {
+                    addr = addr.wrapping_add(self.show_instruction(rt, addr) as u16);
+                }
+            }
+            Command::SetReg(reg, val) => {
+                let (h, l) = split_u16(val);
+                let cpu = rt.cpu_mut();
+                match reg.as_str() {
+                    "a" => cpu.ra = l,
+                    "f" => cpu.rf = l & 0xF0,
+                    "b" => cpu.rb = l,
+                    "c" => cpu.rc = l,
+                    "d" => cpu.rd = l,
+                    "e" => cpu.re = l,
+                    "h" => cpu.rh = l,
+                    "l" => cpu.rl = l,
+                    "af" => (cpu.ra, cpu.rf) = (h, l & 0xF0),
+                    "bc" => (cpu.rb, cpu.rc) = (h, l),
+                    "de" => (cpu.rd, cpu.re) = (h, l),
+                    "hl" => (cpu.rh, cpu.rl) = (h, l),
+                    "sp" => cpu.sp = val,
+                    "pc" => cpu.pc = val,
+                    _ => println!("Unknown register `{}´", reg),
+                }
+            }
+            Command::Write(addr, val) => rt.set(addr, val),
+            Command::Backtrace => {
+                println!("#0 {}", b64(rt.cpu().pc));
+                for (i, frame) in rt.calls.iter().rev().enumerate() {
+                    println!(
+                        "#{} {} returns to {}",
+                        i + 1,
+                        self.describe(rt, frame.target),
+                        self.describe(rt, frame.ret)
+                    );
+                }
+            }
+            Command::Help => println!("{}", HELP),
+            Command::Quit => {}
+        }
+    }
+
+    fn show_location(&self, rt: &Runtime) {
+        println!("{:?}", rt.cpu());
+        self.show_instruction(rt, rt.cpu().pc);
+    }
+
+    /// Prints the instruction at `addr` with its label, returns its length.
+    fn show_instruction(&self, rt: &Runtime, addr: u16) -> u8 {
+        let instr = decode_at(rt, addr);
+        let rom_bank = rt.memory.bank_of(0x4000);
+        let text = match &self.symbols {
+            Some(symbols) => {
+                if let Some(label) = symbols.label(rt.memory.bank_of(addr), addr) {
+                    println!("{}:", label);
+                }
+                instr.labelled(symbols, rom_bank)
+            }
+            None => instr.text,
+        };
+        println!("{}: {}", b64(addr), text);
+        return instr.len;
+    }
+
+    /// Address, with the closest label when symbols are loaded: `0153 (Main+3)`.
+    fn describe(&self, rt: &Runtime, addr: u16) -> String {
+        let label = match &self.symbols {
+            Some(symbols) => symbols.describe(rt.memory.bank_of(addr), addr),
+            None => None,
+        };
+        return match label {
+            Some(label) => format!("{} ({})", b64(addr), label),
+            None => b64(addr),
+        };
+    }
+
+    /// Breakpoint in the rom bank of its label, anywhere else.
+    fn describe_breakpoint(&self, rt: &Runtime, bp: Breakpoint) -> String {
+        let label = match (&self.symbols, bp.bank) {
+            (Some(symbols), Some(bank)) => symbols.describe(bank, bp.addr),
+            _ => return self.describe(rt, bp.addr),
+        };
+        return match label {
+            Some(label) => format!("{} ({}, bank {})", b64(bp.addr), label, bp.bank.unwrap()),
+            None => b64(bp.addr),
+        };
+    }
+
+    /// Bank and address of a label, or a hexadecimal address in any bank. Labels like `Add` are
+    /// valid numbers too.
+    fn resolve(&self, location: &str) -> Option<(Option<u8>, u16)> {
+        if let Some((bank, addr)) = self.symbols.as_ref().and_then(|sym| sym.lookup(location)) {
+            return Some((Some(bank), addr));
+        }
+        return match parse_hex(location) {
+            Ok(addr) => Some((None, addr)),
+            Err(_) => {
+                println!("Unknown location `{}´", location);
+                None
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hex_accepts_prefixes() {
+        assert_eq!(parse_hex("C000"), Ok(0xC000));
+        assert_eq!(parse_hex("$ff80"), Ok(0xFF80));
+        assert_eq!(parse_hex("0x150"), Ok(0x150));
+        assert!(parse_hex("xyz").is_err());
+    }
+
+    #[test]
+    fn test_label_breakpoints_stop_in_their_bank_only() {
+        let label = Breakpoint {
+            bank: Some(3),
+            addr: 0x4100,
+        };
+        assert!(label.matches(3, 0x4100));
+        assert!(!label.matches(2, 0x4100));
+        assert!(!label.matches(3, 0x4101));
+
+        let address = Breakpoint {
+            bank: None,
+            addr: 0x4100,
+        };
+        assert!(address.matches(2, 0x4100));
+    }
+
+    #[test]
+    fn test_break_and_step_stop_while_halted() {
+        let mut debugger = Debugger::new(None);
+        assert!(debugger.should_stop_halted());
+        debugger.mode = Mode::Step(2);
+        assert!(!debugger.should_stop_halted());
+        assert!(debugger.should_stop_halted());
+        debugger.mode = Mode::Continue;
+        assert!(!debugger.should_stop_halted());
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(parse("s\n"), Ok(Command::Step(1)));
+        assert_eq!(parse("step 10"), Ok(Command::Step(10)));
+        assert_eq!(parse("b PlayerUpdate"), Ok(Command::Break("PlayerUpdate".into())));
+        assert_eq!(parse("x C000 4"), Ok(Command::Examine(0xC000, 4)));
+        assert_eq!(parse("set HL 1234"), Ok(Command::SetReg("hl".into(), 0x1234)));
+        assert_eq!(parse("w ff80 42"), Ok(Command::Write(0xFF80, 0x42)));
+        assert_eq!(parse("delete"), Ok(Command::Delete(None)));
+        assert_eq!(parse("list 150 4"), Ok(Command::List(Some("150".into()), 4)));
+        assert_eq!(
+            parse("watch C000-C0FF"),
+            Ok(Command::Watch(Watchpoint {
+                kind: Watch::Change,
+                start: 0xC000,
+                end: 0xC0FF
+            }))
+        );
+        assert!(parse("rwatch D000-C000").is_err());
+        assert!(parse("break").is_err());
+        assert!(parse("jump 100").is_err());
+        assert!(parse("w ff80 100").is_err());
+    }
+}
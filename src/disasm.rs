@@ -0,0 +1,524 @@
+//! SM83 disassembler: mnemonic, length and timing of every opcode, `CB` prefixed included.
+use crate::memory::Memory;
+use crate::symbols::{bank_of, Symbols};
+
+/// Opcode of the table, operands are written as placeholders: `d8`/`d16` immediate values,
+/// `a8` offset from 0xFF00, `a16` address, `r8` signed offset.
+pub struct Op {
+    pub mnemonic: &'static str,
+    pub len: u8,
+    // machine cycles, conditional jumps and calls take `cycles_taken` if the branch is taken
+    pub cycles: u8,
+    pub cycles_taken: u8,
+}
+
+const fn op(mnemonic: &'static str, len: u8, cycles: u8, cycles_taken: u8) -> Op {
+    Op {
+        mnemonic,
+        len,
+        cycles,
+        cycles_taken,
+    }
+}
+
+// opcodes not used by the SM83, they lock up the cpu
+const ILLEGAL: Op = op("ILLEGAL", 1, 1, 1);
+
+pub const OPS: [Op; 256] = [
+    // 0x00
+    op("NOP", 1, 1, 1),
+    op("LD BC,d16", 3, 3, 3),
+    op("LD (BC),A", 1, 2, 2),
+    op("INC BC", 1, 2, 2),
+    op("INC B", 1, 1, 1),
+    op("DEC B", 1, 1, 1),
+    op("LD B,d8", 2, 2, 2),
+    op("RLCA", 1, 1, 1),
+    op("LD (a16),SP", 3, 5, 5),
+    op("ADD HL,BC", 1, 2, 2),
+    op("LD A,(BC)", 1, 2, 2),
+    op("DEC BC", 1, 2, 2),
+    op("INC C", 1, 1, 1),
+    op("DEC C", 1, 1, 1),
+    op("LD C,d8", 2, 2, 2),
+    op("RRCA", 1, 1, 1),
+    // 0x10
+    op("STOP", 2, 1, 1),
+    op("LD DE,d16", 3, 3, 3),
+    op("LD (DE),A", 1, 2, 2),
+    op("INC DE", 1, 2, 2),
+    op("INC D", 1, 1, 1),
+    op("DEC D", 1, 1, 1),
+    op("LD D,d8", 2, 2, 2),
+    op("RLA", 1, 1, 1),
+    op("JR r8", 2, 3, 3),
+    op("ADD HL,DE", 1, 2, 2),
+    op("LD A,(DE)", 1, 2, 2),
+    op("DEC DE", 1, 2, 2),
+    op("INC E", 1, 1, 1),
+    op("DEC E", 1, 1, 1),
+    op("LD E,d8", 2, 2, 2),
+    op("RRA", 1, 1, 1),
+    // 0x20
+    op("JR NZ,r8", 2, 2, 3),
+    op("LD HL,d16", 3, 3, 3),
+    op("LD (HL+),A", 1, 2, 2),
+    op("INC HL", 1, 2, 2),
+    op("INC H", 1, 1, 1),
+    op("DEC H", 1, 1, 1),
+    op("LD H,d8", 2, 2, 2),
+    op("DAA", 1, 1, 1),
+    op("JR Z,r8", 2, 2, 3),
+    op("ADD HL,HL", 1, 2, 2),
+    op("LD A,(HL+)", 1, 2, 2),
+    op("DEC HL", 1, 2, 2),
+    op("INC L", 1, 1, 1),
+    op("DEC L", 1, 1, 1),
+    op("LD L,d8", 2, 2, 2),
+    op("CPL", 1, 1, 1),
+    // 0x30
+    op("JR NC,r8", 2, 2, 3),
+    op("LD SP,d16", 3, 3, 3),
+    op("LD (HL-),A", 1, 2, 2),
+    op("INC SP", 1, 2, 2),
+    op("INC (HL)", 1, 3, 3),
+    op("DEC (HL)", 1, 3, 3),
+    op("LD (HL),d8", 2, 3, 3),
+    op("SCF", 1, 1, 1),
+    op("JR C,r8", 2, 2, 3),
+    op("ADD HL,SP", 1, 2, 2),
+    op("LD A,(HL-)", 1, 2, 2),
+    op("DEC SP", 1, 2, 2),
+    op("INC A", 1, 1, 1),
+    op("DEC A", 1, 1, 1),
+    op("LD A,d8", 2, 2, 2),
+    op("CCF", 1, 1, 1),
+    // 0x40
+    op("LD B,B", 1, 1, 1),
+    op("LD B,C", 1, 1, 1),
+    op("LD B,D", 1, 1, 1),
+    op("LD B,E", 1, 1, 1),
+    op("LD B,H", 1, 1, 1),
+    op("LD B,L", 1, 1, 1),
+    op("LD B,(HL)", 1, 2, 2),
+    op("LD B,A", 1, 1, 1),
+    op("LD C,B", 1, 1, 1),
+    op("LD C,C", 1, 1, 1),
+    op("LD C,D", 1, 1, 1),
+    op("LD C,E", 1, 1, 1),
+    op("LD C,H", 1, 1, 1),
+    op("LD C,L", 1, 1, 1),
+    op("LD C,(HL)", 1, 2, 2),
+    op("LD C,A", 1, 1, 1),
+    // 0x50
+    op("LD D,B", 1, 1, 1),
+    op("LD D,C", 1, 1, 1),
+    op("LD D,D", 1, 1, 1),
+    op("LD D,E", 1, 1, 1),
+    op("LD D,H", 1, 1, 1),
+    op("LD D,L", 1, 1, 1),
+    op("LD D,(HL)", 1, 2, 2),
+    op("LD D,A", 1, 1, 1),
+    op("LD E,B", 1, 1, 1),
+    op("LD E,C", 1, 1, 1),
+    op("LD E,D", 1, 1, 1),
+    op("LD E,E", 1, 1, 1),
+    op("LD E,H", 1, 1, 1),
+    op("LD E,L", 1, 1, 1),
+    op("LD E,(HL)", 1, 2, 2),
+    op("LD E,A", 1, 1, 1),
+    // 0x60
+    op("LD H,B", 1, 1, 1),
+    op("LD H,C", 1, 1, 1),
+    op("LD H,D", 1, 1, 1),
+    op("LD H,E", 1, 1, 1),
+    op("LD H,H", 1, 1, 1),
+    op("LD H,L", 1, 1, 1),
+    op("LD H,(HL)", 1, 2, 2),
+    op("LD H,A", 1, 1, 1),
+    op("LD L,B", 1, 1, 1),
+    op("LD L,C", 1, 1, 1),
+    op("LD L,D", 1, 1, 1),
+    op("LD L,E", 1, 1, 1),
+    op("LD L,H", 1, 1, 1),
+    op("LD L,L", 1, 1, 1),
+    op("LD L,(HL)", 1, 2, 2),
+    op("LD L,A", 1, 1, 1),
+    // 0x70
+    op("LD (HL),B", 1, 2, 2),
+    op("LD (HL),C", 1, 2, 2),
+    op("LD (HL),D", 1, 2, 2),
+    op("LD (HL),E", 1, 2, 2),
+    op("LD (HL),H", 1, 2, 2),
+    op("LD (HL),L", 1, 2, 2),
+    op("HALT", 1, 1, 1),
+    op("LD (HL),A", 1, 2, 2),
+    op("LD A,B", 1, 1, 1),
+    op("LD A,C", 1, 1, 1),
+    op("LD A,D", 1, 1, 1),
+    op("LD A,E", 1, 1, 1),
+    op("LD A,H", 1, 1, 1),
+    op("LD A,L", 1, 1, 1),
+    op("LD A,(HL)", 1, 2, 2),
+    op("LD A,A", 1, 1, 1),
+    // 0x80
+    op("ADD A,B", 1, 1, 1),
+    op("ADD A,C", 1, 1, 1),
+    op("ADD A,D", 1, 1, 1),
+    op("ADD A,E", 1, 1, 1),
+    op("ADD A,H", 1, 1, 1),
+    op("ADD A,L", 1, 1, 1),
+    op("ADD A,(HL)", 1, 2, 2),
+    op("ADD A,A", 1, 1, 1),
+    op("ADC A,B", 1, 1, 1),
+    op("ADC A,C", 1, 1, 1),
+    op("ADC A,D", 1, 1, 1),
+    op("ADC A,E", 1, 1, 1),
+    op("ADC A,H", 1, 1, 1),
+    op("ADC A,L", 1, 1, 1),
+    op("ADC A,(HL)", 1, 2, 2),
+    op("ADC A,A", 1, 1, 1),
+    // 0x90
+    op("SUB B", 1, 1, 1),
+    op("SUB C", 1, 1, 1),
+    op("SUB D", 1, 1, 1),
+    op("SUB E", 1, 1, 1),
+    op("SUB H", 1, 1, 1),
+    op("SUB L", 1, 1, 1),
+    op("SUB (HL)", 1, 2, 2),
+    op("SUB A", 1, 1, 1),
+    op("SBC A,B", 1, 1, 1),
+    op("SBC A,C", 1, 1, 1),
+    op("SBC A,D", 1, 1, 1),
+    op("SBC A,E", 1, 1, 1),
+    op("SBC A,H", 1, 1, 1),
+    op("SBC A,L", 1, 1, 1),
+    op("SBC A,(HL)", 1, 2, 2),
+    op("SBC A,A", 1, 1, 1),
+    // 0xA0
+    op("AND B", 1, 1, 1),
+    op("AND C", 1, 1, 1),
+    op("AND D", 1, 1, 1),
+    op("AND E", 1, 1, 1),
+    op("AND H", 1, 1, 1),
+    op("AND L", 1, 1, 1),
+    op("AND (HL)", 1, 2, 2),
+    op("AND A", 1, 1, 1),
+    op("XOR B", 1, 1, 1),
+    op("XOR C", 1, 1, 1),
+    op("XOR D", 1, 1, 1),
+    op("XOR E", 1, 1, 1),
+    op("XOR H", 1, 1, 1),
+    op("XOR L", 1, 1, 1),
+    op("XOR (HL)", 1, 2, 2),
+    op("XOR A", 1, 1, 1),
+    // 0xB0
+    op("OR B", 1, 1, 1),
+    op("OR C", 1, 1, 1),
+    op("OR D", 1, 1, 1),
+    op("OR E", 1, 1, 1),
+    op("OR H", 1, 1, 1),
+    op("OR L", 1, 1, 1),
+    op("OR (HL)", 1, 2, 2),
+    op("OR A", 1, 1, 1),
+    op("CP B", 1, 1, 1),
+    op("CP C", 1, 1, 1),
+    op("CP D", 1, 1, 1),
+    op("CP E", 1, 1, 1),
+    op("CP H", 1, 1, 1),
+    op("CP L", 1, 1, 1),
+    op("CP (HL)", 1, 2, 2),
+    op("CP A", 1, 1, 1),
+    // 0xC0
+    op("RET NZ", 1, 2, 5),
+    op("POP BC", 1, 3, 3),
+    op("JP NZ,a16", 3, 3, 4),
+    op("JP a16", 3, 4, 4),
+    op("CALL NZ,a16", 3, 3, 6),
+    op("PUSH BC", 1, 4, 4),
+    op("ADD A,d8", 2, 2, 2),
+    op("RST $00", 1, 4, 4),
+    op("RET Z", 1, 2, 5),
+    op("RET", 1, 4, 4),
+    op("JP Z,a16", 3, 3, 4),
+    op("PREFIX", 1, 1, 1),
+    op("CALL Z,a16", 3, 3, 6),
+    op("CALL a16", 3, 6, 6),
+    op("ADC A,d8", 2, 2, 2),
+    op("RST $08", 1, 4, 4),
+    // 0xD0
+    op("RET NC", 1, 2, 5),
+    op("POP DE", 1, 3, 3),
+    op("JP NC,a16", 3, 3, 4),
+    ILLEGAL,
+    op("CALL NC,a16", 3, 3, 6),
+    op("PUSH DE", 1, 4, 4),
+    op("SUB d8", 2, 2, 2),
+    op("RST $10", 1, 4, 4),
+    op("RET C", 1, 2, 5),
+    op("RETI", 1, 4, 4),
+    op("JP C,a16", 3, 3, 4),
+    ILLEGAL,
+    op("CALL C,a16", 3, 3, 6),
+    ILLEGAL,
+    op("SBC A,d8", 2, 2, 2),
+    op("RST $18", 1, 4, 4),
+    // 0xE0
+    op("LDH (a8),A", 2, 3, 3),
+    op("POP HL", 1, 3, 3),
+    op("LD (C),A", 1, 2, 2),
+    ILLEGAL,
+    ILLEGAL,
+    op("PUSH HL", 1, 4, 4),
+    op("AND d8", 2, 2, 2),
+    op("RST $20", 1, 4, 4),
+    op("ADD SP,r8", 2, 4, 4),
+    op("JP HL", 1, 1, 1),
+    op("LD (a16),A", 3, 4, 4),
+    ILLEGAL,
+    ILLEGAL,
+    ILLEGAL,
+    op("XOR d8", 2, 2, 2),
+    op("RST $28", 1, 4, 4),
+    // 0xF0
+    op("LDH A,(a8)", 2, 3, 3),
+    op("POP AF", 1, 3, 3),
+    op("LD A,(C)", 1, 2, 2),
+    op("DI", 1, 1, 1),
+    ILLEGAL,
+    op("PUSH AF", 1, 4, 4),
+    op("OR d8", 2, 2, 2),
+    op("RST $30", 1, 4, 4),
+    op("LD HL,SP+r8", 2, 3, 3),
+    op("LD SP,HL", 1, 2, 2),
+    op("LD A,(a16)", 3, 4, 4),
+    op("EI", 1, 1, 1),
+    ILLEGAL,
+    ILLEGAL,
+    op("CP d8", 2, 2, 2),
+    op("RST $38", 1, 4, 4),
+];
+
+const CB_OPS: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+const CB_REGS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+
+/// Decoded instruction, `text` has its operands filled in.
+#[derive(Debug, PartialEq)]
+pub struct Instruction {
+    pub text: String,
+    pub len: u8,
+    pub cycles: u8,
+    pub cycles_taken: u8,
+    // address operand, jump target or memory location
+    pub target: Option<u16>,
+}
+
+impl Instruction {
+    /// Text with the address operand replaced by its label, if any.
+    pub fn labelled(&self, symbols: &Symbols, rom_bank: u8) -> String {
+        if let Some(target) = self.target {
+            if let Some(label) = symbols.label(bank_of(target, rom_bank), target) {
+                return self.text.replace(&format!("${:04X}", target), label);
+            }
+        }
+        return self.text.clone();
+    }
+}
+
+/// Machine cycles of a `CB` prefixed opcode, the prefix included.
+pub fn cb_cycles(opcode: u8) -> u8 {
+    // (HL) operands take 2 more cycles to read and write back, BIT only reads it
+    return match (opcode & 7, opcode >> 6) {
+        (6, 1) => 3,
+        (6, _) => 4,
+        _ => 2,
+    };
+}
+
+fn decode_cb(opcode: u8) -> Instruction {
+    let reg = CB_REGS[(opcode & 7) as usize];
+    let bit = (opcode >> 3) & 7;
+    let text = match opcode >> 6 {
+        0 => format!("{} {}", CB_OPS[bit as usize], reg),
+        1 => format!("BIT {},{}", bit, reg),
+        2 => format!("RES {},{}", bit, reg),
+        _ => format!("SET {},{}", bit, reg),
+    };
+
+    let cycles = cb_cycles(opcode);
+    return Instruction {
+        text,
+        len: 2,
+        cycles,
+        cycles_taken: cycles,
+        target: None,
+    };
+}
+
+/// Decodes the instruction at the start of `bytes`, located at `addr` (for the targets of `JR`).
+/// Missing operand bytes read as 0.
+pub fn decode(bytes: &[u8], addr: u16) -> Instruction {
+    let byte = |i: usize| *bytes.get(i).unwrap_or(&0);
+    let opcode = byte(0);
+    if opcode == 0xCB {
+        return decode_cb(byte(1));
+    }
+
+    let op = &OPS[opcode as usize];
+    let d16 = ((byte(2) as u16) << 8) + byte(1) as u16;
+    let r8 = byte(1) as i8;
+    let signed = if r8 < 0 {
+        format!("-${:02X}", r8.unsigned_abs())
+    } else {
+        format!("+${:02X}", r8)
+    };
+
+    let jr_target = addr.wrapping_add(2).wrapping_add(r8 as u16);
+    let target = if op.mnemonic.starts_with("JR") {
+        Some(jr_target)
+    } else if op.mnemonic.contains("a16") {
+        Some(d16)
+    } else if op.mnemonic.contains("a8") {
+        Some(0xFF00 + byte(1) as u16)
+    } else {
+        None
+    };
+
+    let text = if op.mnemonic.contains("SP+r8") {
+        op.mnemonic.replace("+r8", &signed)
+    } else if op.mnemonic.starts_with("JR") {
+        op.mnemonic.replace("r8", &format!("${:04X}", jr_target))
+    } else {
+        op.mnemonic
+            .replace("r8", &signed)
+            .replace("d8", &format!("${:02X}", byte(1)))
+            .replace("a8", &format!("${:04X}", 0xFF00 + byte(1) as u16))
+            .replace("d16", &format!("${:04X}", d16))
+            .replace("a16", &format!("${:04X}", d16))
+    };
+
+    return Instruction {
+        text,
+        len: op.len,
+        cycles: op.cycles,
+        cycles_taken: op.cycles_taken,
+        target,
+    };
+}
+
+/// Decodes the instruction at `addr` of the address space.
+pub fn decode_at(mem: &impl Memory, addr: u16) -> Instruction {
+    let bytes = [
+        mem.get(addr),
+        mem.get(addr.wrapping_add(1)),
+        mem.get(addr.wrapping_add(2)),
+    ];
+    return decode(&bytes, addr);
+}
+
+/// Listing of a whole rom bank, one `bank:address  bytes  instruction` line per instruction,
+/// preceded by `label:` lines when symbols are given. Bank 0 is mapped at 0x0000, the others at
+/// 0x4000.
+pub fn listing(rom: &[u8], bank: usize, symbols: Option<&Symbols>) -> Result<String, String> {
+    let start = bank * 0x4000;
+    if start >= rom.len() {
+        return Err(format!("Bank {} is out of the rom ({} banks)", bank, rom.len() / 0x4000));
+    }
+    let data = &rom[start..(start + 0x4000).min(rom.len())];
+    let base: u16 = if bank == 0 { 0x0000 } else { 0x4000 };
+
+    let mut out = String::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let addr = base + pos as u16;
+        let instr = decode(&data[pos..], addr);
+        let end = (pos + instr.len as usize).min(data.len());
+        let bytes: Vec<String> = data[pos..end].iter().map(|b| format!("{:02X}", b)).collect();
+
+        let text = match symbols {
+            Some(symbols) => {
+                if let Some(label) = symbols.label(bank as u8, addr) {
+                    out += &format!("{}:\n", label);
+                }
+                instr.labelled(symbols, bank as u8)
+            }
+            None => instr.text,
+        };
+        out += &format!("{:02X}:{:04X}  {:<9} {}\n", bank, addr, bytes.join(" "), text);
+        pos += instr.len as usize;
+    }
+    return Ok(out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_operands() {
+        assert_eq!(decode(&[0x01, 0x34, 0x12], 0).text, "LD BC,$1234");
+        assert_eq!(decode(&[0x3E, 0x42], 0).text, "LD A,$42");
+        assert_eq!(decode(&[0xE0, 0x40], 0).text, "LDH ($FF40),A");
+        assert_eq!(decode(&[0xCD, 0x50, 0x01], 0).text, "CALL $0150");
+        assert_eq!(decode(&[0xE8, 0xFE], 0).text, "ADD SP,-$02");
+        assert_eq!(decode(&[0xF8, 0x05], 0).text, "LD HL,SP+$05");
+        assert_eq!(decode(&[0x76], 0).text, "HALT");
+        assert_eq!(decode(&[0xD3], 0).text, "ILLEGAL");
+    }
+
+    #[test]
+    fn test_decode_relative_jumps() {
+        // jumps are relative to the next instruction
+        assert_eq!(decode(&[0x18, 0xFE], 0x150).text, "JR $0150");
+        assert_eq!(decode(&[0x20, 0x10], 0x150).text, "JR NZ,$0162");
+    }
+
+    #[test]
+    fn test_decode_cb() {
+        let instr = decode(&[0xCB, 0x7C], 0);
+        assert_eq!(instr.text, "BIT 7,H");
+        assert_eq!((instr.len, instr.cycles), (2, 2));
+
+        assert_eq!(decode(&[0xCB, 0x37], 0).text, "SWAP A");
+        assert_eq!(decode(&[0xCB, 0x46], 0).cycles, 3);
+        assert_eq!(decode(&[0xCB, 0xFE], 0).text, "SET 7,(HL)");
+        assert_eq!(decode(&[0xCB, 0xFE], 0).cycles, 4);
+    }
+
+    #[test]
+    fn test_decode_timing() {
+        let instr = decode(&[0xC4, 0, 0], 0);
+        assert_eq!((instr.len, instr.cycles, instr.cycles_taken), (3, 3, 6));
+        assert_eq!(decode(&[0x08, 0, 0], 0).cycles, 5);
+        assert_eq!(decode(&[0x46], 0).cycles, 2);
+    }
+
+    #[test]
+    fn test_listing_maps_banks() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x4000..0x4003].copy_from_slice(&[0xC3, 0x00, 0x40]);
+
+        let listing = listing(&rom, 1, None).unwrap();
+        assert_eq!(listing.lines().next(), Some("01:4000  C3 00 40  JP $4000"));
+        assert!(super::listing(&rom, 2, None).is_err());
+    }
+
+    #[test]
+    fn test_listing_with_labels() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x4000..0x4003].copy_from_slice(&[0xC3, 0x00, 0x40]);
+        rom[0x4003..0x4006].copy_from_slice(&[0xEA, 0x00, 0xC0]);
+        let symbols = Symbols::parse("01:4000 Loop\n00:C000 wCounter\n");
+
+        let listing = listing(&rom, 1, Some(&symbols)).unwrap();
+        let lines: Vec<&str> = listing.lines().take(3).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "Loop:",
+                "01:4000  C3 00 40  JP Loop",
+                "01:4003  EA 00 C0  LD (wCounter),A"
+            ]
+        );
+    }
+}
@@ -0,0 +1,382 @@
+//! GDB remote serial protocol server, for debugger front-ends speaking RSP over TCP.
+//! Registers are exchanged as AF, BC, DE, HL, SP, PC: 16 bits each, little endian.
+//! Memory accesses go through the MMU, writes to 0x0000-0x7FFF reach the mbc as bank switches.
+use crate::byteop::{join_u8, split_u16};
+use crate::debugger::Resume;
+use crate::memory::Memory;
+use crate::runtime::Runtime;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// sent by gdb to interrupt a running target
+const CTRL_C: u8 = 0x03;
+// instructions between two checks for an interrupt from gdb while running
+const POLL_EVERY: u32 = 4096;
+// stopped by SIGTRAP
+const STOP_REPLY: &str = "S05";
+
+#[derive(PartialEq)]
+enum State {
+    Stopped,
+    // stops again after one instruction
+    Step,
+    Running,
+    // gdb disconnected, the emulation runs freely
+    Detached,
+}
+
+/// Checksum and framing of a packet: `$data#xx`.
+fn frame(data: &str) -> String {
+    let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    return format!("${}#{:02x}", data, checksum);
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    return (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect();
+}
+
+fn parse_u16(hex: &str) -> Option<u16> {
+    return u16::from_str_radix(hex, 16).ok();
+}
+
+pub struct GdbStub {
+    stream: TcpStream,
+    breakpoints: Vec<u16>,
+    state: State,
+    polls: u32,
+}
+
+impl GdbStub {
+    /// Waits for gdb to connect on `port` of localhost, the emulation starts stopped.
+    pub fn listen(port: u16) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        println!("Waiting for gdb on 127.0.0.1:{}", port);
+        let (stream, addr) = listener.accept()?;
+        println!("gdb connected from {}", addr);
+        return GdbStub::new(stream);
+    }
+
+    pub fn new(stream: TcpStream) -> io::Result<GdbStub> {
+        stream.set_nodelay(true)?;
+        return Ok(GdbStub {
+            stream,
+            breakpoints: vec![],
+            state: State::Stopped,
+            polls: 0,
+        });
+    }
+
+    /// Called before every instruction, serves gdb while the emulation is stopped.
+    pub fn check(&mut self, rt: &mut Runtime) -> Resume {
+        match self.state {
+            State::Detached => return Resume::Run,
+            State::Running => {
+                if !self.breakpoints.contains(&rt.cpu().pc) && !self.interrupted() {
+                    return Resume::Run;
+                }
+            }
+            State::Step => {}
+            State::Stopped => {}
+        }
+
+        rt.sync();
+        if self.state != State::Stopped {
+            self.state = State::Stopped;
+            if self.send(STOP_REPLY).is_err() {
+                return self.disconnected();
+            }
+        }
+
+        while self.state == State::Stopped {
+            let packet = match self.receive() {
+                Ok(packet) => packet,
+                Err(_) => return self.disconnected(),
+            };
+            let reply = match self.handle(&packet, rt) {
+                Some(reply) => reply,
+                None => return Resume::Quit,
+            };
+            // resuming commands reply once stopped again
+            let resumed = self.state == State::Step || self.state == State::Running;
+            if !resumed && self.send(&reply).is_err() {
+                return self.disconnected();
+            }
+        }
+        return Resume::Run;
+    }
+
+    /// Stops at the next instruction, as after a step.
+    pub fn interrupt(&mut self) {
+        if self.state == State::Running {
+            self.state = State::Step;
+        }
+    }
+
+    fn disconnected(&mut self) -> Resume {
+        println!("gdb disconnected");
+        self.state = State::Detached;
+        return Resume::Run;
+    }
+
+    /// Checks, from time to time, if gdb sent an interrupt while running.
+    fn interrupted(&mut self) -> bool {
+        self.polls += 1;
+        if self.polls < POLL_EVERY {
+            return false;
+        }
+        self.polls = 0;
+
+        let mut byte = [0];
+        self.stream.set_nonblocking(true).unwrap();
+        let read = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false).unwrap();
+        return matches!(read, Ok(1) if byte[0] == CTRL_C);
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte)?;
+        return Ok(byte[0]);
+    }
+
+    /// Reads the next packet and acknowledges it.
+    fn receive(&mut self) -> io::Result<String> {
+        // acks of our replies, and interrupts of an already stopped target
+        while self.read_byte()? != b'$' {}
+
+        let mut data = vec![];
+        loop {
+            match self.read_byte()? {
+                b'#' => break,
+                byte => data.push(byte),
+            }
+        }
+        // checksum, tcp already makes sure the data is intact
+        self.read_byte()?;
+        self.read_byte()?;
+
+        self.stream.write_all(b"+")?;
+        return Ok(String::from_utf8_lossy(&data).to_string());
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        return self.stream.write_all(frame(data).as_bytes());
+    }
+
+    /// Executes a command, returns the reply or None when gdb kills the target.
+    fn handle(&mut self, packet: &str, rt: &mut Runtime) -> Option<String> {
+        let (cmd, args) = packet.split_at(packet.len().min(1));
+
+        let reply = match cmd {
+            "?" => STOP_REPLY.to_string(),
+            "g" => to_hex(&self.registers(rt)),
+            "G" => match from_hex(args) {
+                Some(bytes) if bytes.len() == 12 => {
+                    for (i, pair) in bytes.chunks(2).enumerate() {
+                        self.set_register(rt, i, join_u8(pair[1], pair[0]));
+                    }
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(i) if i < 6 => to_hex(&self.registers(rt)[i * 2..i * 2 + 2]),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let reg = args.split_once('=').and_then(|(i, val)| {
+                    let bytes = from_hex(val)?;
+                    Some((usize::from_str_radix(i, 16).ok()?, bytes))
+                });
+                match reg {
+                    Some((i, bytes)) if i < 6 && bytes.len() == 2 => {
+                        self.set_register(rt, i, join_u8(bytes[1], bytes[0]));
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => {
+                let range = args
+                    .split_once(',')
+                    .and_then(|(addr, len)| Some((parse_u16(addr)?, parse_u16(len)?)));
+                match range {
+                    Some((addr, len)) => {
+                        let bytes: Vec<u8> =
+                            (0..len).map(|i| rt.get(addr.wrapping_add(i))).collect();
+                        to_hex(&bytes)
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "M" => {
+                let write = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, _) = range.split_once(',')?;
+                    Some((parse_u16(addr)?, from_hex(data)?))
+                });
+                match write {
+                    Some((addr, bytes)) => {
+                        for (i, byte) in bytes.iter().enumerate() {
+                            rt.set(addr.wrapping_add(i as u16), *byte);
+                        }
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "Z" | "z" => {
+                // software (0) and hardware (1) breakpoints are both checked on PC
+                let mut fields = args.split(',');
+                let kind = fields.next();
+                let addr = fields.next().and_then(parse_u16);
+                match (kind, addr) {
+                    (Some("0" | "1"), Some(addr)) => {
+                        self.breakpoints.retain(|bp| *bp != addr);
+                        if cmd == "Z" {
+                            self.breakpoints.push(addr);
+                        }
+                        "OK".to_string()
+                    }
+                    _ => String::new(),
+                }
+            }
+            "s" | "c" => {
+                if let Some(addr) = parse_u16(args) {
+                    rt.cpu_mut().pc = addr;
+                }
+                self.state = if cmd == "s" {
+                    State::Step
+                } else {
+                    State::Running
+                };
+                String::new()
+            }
+            "D" => {
+                self.state = State::Detached;
+                "OK".to_string()
+            }
+            "k" => return None,
+            "H" => "OK".to_string(),
+            "q" if args == "Attached" => "1".to_string(),
+            "q" if args.starts_with("Supported") => "PacketSize=4000".to_string(),
+            // unsupported, gdb falls back to the packets above
+            _ => String::new(),
+        };
+        return Some(reply);
+    }
+
+    fn registers(&self, rt: &Runtime) -> Vec<u8> {
+        let cpu = rt.cpu();
+        let pairs = [
+            join_u8(cpu.ra, cpu.rf),
+            join_u8(cpu.rb, cpu.rc),
+            join_u8(cpu.rd, cpu.re),
+            join_u8(cpu.rh, cpu.rl),
+            cpu.sp,
+            cpu.pc,
+        ];
+        return pairs.iter().flat_map(|val| val.to_le_bytes()).collect();
+    }
+
+    fn set_register(&self, rt: &mut Runtime, i: usize, val: u16) {
+        let (h, l) = split_u16(val);
+        let cpu = rt.cpu_mut();
+        match i {
+            0 => (cpu.ra, cpu.rf) = (h, l & 0xF0),
+            1 => (cpu.rb, cpu.rc) = (h, l),
+            2 => (cpu.rd, cpu.re) = (h, l),
+            3 => (cpu.rh, cpu.rl) = (h, l),
+            4 => cpu.sp = val,
+            _ => cpu.pc = val,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_appends_checksum() {
+        assert_eq!(frame("OK"), "$OK#9a");
+        assert_eq!(frame(""), "$#00");
+    }
+
+    /// Sends the packets and returns the replies, skipping the acks.
+    fn client(addr: std::net::SocketAddr, packets: &[&str]) -> Vec<String> {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut replies = vec![];
+        for packet in packets {
+            stream.write_all(frame(packet).as_bytes()).unwrap();
+            if *packet == "k" {
+                break;
+            }
+
+            let mut reply = vec![];
+            let mut byte = [0];
+            while reply.last() != Some(&b'#') {
+                stream.read_exact(&mut byte).unwrap();
+                if byte[0] != b'+' || !reply.is_empty() {
+                    reply.push(byte[0]);
+                }
+            }
+            // checksum
+            stream.read_exact(&mut [0; 2]).unwrap();
+            replies.push(String::from_utf8(reply[1..reply.len() - 1].to_vec()).unwrap());
+        }
+        return replies;
+    }
+
+    #[test]
+    fn test_session_over_tcp() {
+        // NOPs from 0x100
+        let game_rom = vec![0; 0x8000];
+        let bootstrap = vec![0; 0x100];
+        let mut rom = crate::mbc::RomMBC::new(&game_rom);
+        let mut rt = Runtime::noboot(&bootstrap, &mut rom);
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let packets = ["?", "g", "Mc000,2:beef", "mc000,2", "Z0,103,1", "c", "p5", "s", "p5", "k"];
+        let client = std::thread::spawn(move || client(addr, &packets));
+
+        let mut gdb = GdbStub::new(listener.accept().unwrap().0).unwrap();
+        while let Resume::Run = gdb.check(&mut rt) {
+            rt.tick();
+        }
+
+        let replies = client.join().unwrap();
+        assert_eq!(
+            replies,
+            vec![
+                "S05",
+                "b0011300d8004d01feff0001",
+                "OK",
+                "beef",
+                "OK",
+                "S05",
+                "0301",
+                "S05",
+                "0401"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hex_roundtrip() {
+        assert_eq!(to_hex(&[0x01, 0xB0, 0xFF]), "01b0ff");
+        assert_eq!(from_hex("01b0ff"), Some(vec![0x01, 0xB0, 0xFF]));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
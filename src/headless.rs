@@ -0,0 +1,229 @@
+use crate::apu::APU;
+use crate::memory::Memory;
+use crate::runtime::{CpuRegisters, Lockup, Runtime};
+
+// frames emulated after the result is known, test roms keep printing details after it.
+const TRAILING_FRAMES: u64 = 10;
+
+// mooneye's roms signal the end of the test with `LD B,B`, leaving a fibonacci sequence in the
+// registers on success.
+pub const LD_B_B: u8 = 0x40;
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAIL: [u8; 6] = [0x42; 6];
+
+fn mooneye_outcome(cpu: &CpuRegisters) -> Option<Outcome> {
+    let regs = [cpu.rb, cpu.rc, cpu.rd, cpu.re, cpu.rh, cpu.rl];
+    return match regs {
+        MOONEYE_PASS => Some(Outcome::Passed),
+        MOONEYE_FAIL => Some(Outcome::Failed),
+        _ => None,
+    };
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    Timeout,
+    // the rom executed an illegal opcode
+    Lockup(Lockup),
+}
+
+/// Runs the emulator without a window or audio device, for test roms.
+pub struct Headless<'a> {
+    pub rt: Runtime<'a>,
+    pub apu: APU,
+
+    pub frames: u64,
+    // machine cycles
+    pub cycles: u64,
+}
+
+impl<'a> Headless<'a> {
+    pub fn new(mut rt: Runtime<'a>) -> Headless<'a> {
+        rt.memory.capture_serial();
+
+        Headless {
+            rt,
+            apu: APU::new(),
+            frames: 0,
+            cycles: 0,
+        }
+    }
+
+    /// Executes one instruction, returns true if a frame has been completed.
+    pub fn step(&mut self) -> bool {
+        let cc = self.rt.tick();
+        if self.rt.take_apu() {
+            self.apu.update(cc * 4, &mut self.rt);
+        }
+        let frame = self.rt.take_frame();
+
+        self.cycles += cc as u64;
+        if frame {
+            self.frames += 1;
+        }
+        return frame;
+    }
+
+    pub fn serial(&self) -> String {
+        return String::from_utf8_lossy(self.rt.memory.serial_output()).to_string();
+    }
+
+    /// Runs until the rom reports "Passed" or "Failed" on the serial port (blargg) or through
+    /// the registers (mooneye), or either `max_cycles` machine cycles or `max_frames` frames have
+    /// been emulated.
+    pub fn run(&mut self, max_cycles: u64, max_frames: u64) -> Outcome {
+        let mut serial_len = 0;
+
+        while self.cycles < max_cycles && self.frames < max_frames {
+            let ld_b_b = self.rt.get(self.rt.cpu().pc) == LD_B_B;
+            self.step();
+
+            if let Some(lockup) = self.rt.take_lockup() {
+                return Outcome::Lockup(lockup);
+            }
+            if ld_b_b {
+                if let Some(outcome) = mooneye_outcome(self.rt.cpu()) {
+                    return outcome;
+                }
+            }
+
+            let serial = self.rt.memory.serial_output();
+            if serial.len() != serial_len {
+                serial_len = serial.len();
+
+                let text = String::from_utf8_lossy(serial);
+                let outcome = if text.contains("Passed") {
+                    Outcome::Passed
+                } else if text.contains("Failed") {
+                    Outcome::Failed
+                } else {
+                    continue;
+                };
+
+                // let the rom print the rest of the report
+                let until = self.frames + TRAILING_FRAMES;
+                while self.frames < until {
+                    self.step();
+                }
+                return outcome;
+            }
+        }
+        return Outcome::Timeout;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::RomMBC;
+    use std::fs;
+    use std::panic;
+    use std::path::{Path, PathBuf};
+
+    // every rom of the suites completes well within a minute of emulated time.
+    const MAX_FRAMES: u64 = 60 * 60;
+
+    // test suites checked out next to the sources, see README.
+    const SUITES: [&str; 2] = ["gb-test-roms", "mooneye-test-suite"];
+
+    fn run_rom(path: &Path) -> (Outcome, String) {
+        let game_rom = fs::read(path).unwrap();
+        let bootstrap = fs::read("DMG_ROM.bin").unwrap();
+
+        let mut rom = RomMBC::new(&game_rom);
+        let rt = Runtime::load(&bootstrap, &mut rom);
+        let mut emu = Headless::new(rt);
+
+        let outcome = emu.run(u64::MAX, MAX_FRAMES);
+        return (outcome, emu.serial());
+    }
+
+    macro_rules! rom_test {
+        ($name:ident, $path:expr) => {
+            #[test]
+            fn $name() {
+                let (outcome, serial) = run_rom(Path::new($path));
+                assert_eq!(outcome, Outcome::Passed, "{}", serial);
+            }
+        };
+    }
+
+    rom_test!(test_blargg_01_special, "roms/01-special.gb");
+    rom_test!(test_blargg_02_interrupts, "roms/02-interrupts.gb");
+    rom_test!(test_blargg_03_op_sp_hl, "roms/03-op sp,hl.gb");
+    rom_test!(test_blargg_04_op_r_imm, "roms/04-op r,imm.gb");
+    rom_test!(test_blargg_05_op_rp, "roms/05-op rp.gb");
+    rom_test!(test_blargg_06_ld_r_r, "roms/06-ld r,r.gb");
+    rom_test!(test_blargg_07_jr_jp_call_ret_rst, "roms/07-jr,jp,call,ret,rst.gb");
+    rom_test!(test_blargg_08_misc_instrs, "roms/08-misc instrs.gb");
+    rom_test!(test_blargg_09_op_r_r, "roms/09-op r,r.gb");
+    rom_test!(test_blargg_10_bit_ops, "roms/10-bit ops.gb");
+    rom_test!(test_blargg_11_op_a_hl, "roms/11-op a,(hl).gb");
+    rom_test!(test_blargg_instr_timing, "roms/instr_timing.gb");
+
+    #[test]
+    #[ignore = "the rom checks the timings in cgb double speed mode too"]
+    fn test_blargg_interrupt_time() {
+        let (outcome, serial) = run_rom(Path::new("roms/interrupt_time.gb"));
+        assert_eq!(outcome, Outcome::Passed, "{}", serial);
+    }
+
+    #[test]
+    fn test_mooneye_signature() {
+        let mut cpu = CpuRegisters::new();
+        assert_eq!(mooneye_outcome(&cpu), None);
+
+        cpu.rb = 3;
+        cpu.rc = 5;
+        cpu.rd = 8;
+        cpu.re = 13;
+        cpu.rh = 21;
+        cpu.rl = 34;
+        assert_eq!(mooneye_outcome(&cpu), Some(Outcome::Passed));
+    }
+
+    fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                find_roms(&path, roms);
+            } else if path.extension().is_some_and(|ext| ext == "gb") {
+                roms.push(path);
+            }
+        }
+    }
+
+    /// Status report of every rom of the suites, run with:
+    /// `cargo test rom_suites -- --ignored --nocapture`
+    #[test]
+    #[ignore = "status report of the external test suites"]
+    fn test_rom_suites() {
+        let mut roms = vec![];
+        for suite in SUITES {
+            find_roms(Path::new(suite), &mut roms);
+        }
+        roms.sort();
+
+        let mut passed = 0;
+        for path in &roms {
+            let result = panic::catch_unwind(|| run_rom(path));
+            let status = match result {
+                Ok((Outcome::Passed, _)) => "PASS",
+                Ok((Outcome::Failed, _)) => "FAIL",
+                Ok((Outcome::Timeout, _)) => "TIMEOUT",
+                Ok((Outcome::Lockup(_), _)) => "LOCKUP",
+                Err(_) => "PANIC",
+            };
+            if status == "PASS" {
+                passed += 1;
+            }
+            println!("{:<8} {}", status, path.display());
+        }
+        println!("{}/{} roms passed", passed, roms.len());
+    }
+}
@@ -3,29 +3,176 @@ extern crate sdl2;
 mod registers;
 use std::fs;
 use std::io::Read;
+use std::path::Path;
 use std::rc::Rc;
+use std::sync::atomic::Ordering;
 mod runtime;
-use mbc::{Rom, RomMBC3, RomNoMBC};
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use mbc::RomMBC;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use sdl2::VideoSubsystem;
 mod byteop;
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use std::time;
 mod ppu;
 mod timer;
-use crate::apu::APU;
-use crate::ppu::{Display, PPU};
+mod trace;
+use crate::apu::{APU, FRAME_SAMPLES};
+use crate::ppu::DOTS_PER_FRAME;
+use audioview::AudioView;
 use sdl2::pixels::Color;
 mod memory;
 use memory::HWInput;
 mod apu;
+mod audioview;
+mod debugger;
+mod disasm;
+mod gdbstub;
 mod mbc;
+mod profiler;
+mod headless;
+mod movie;
+mod rewind;
+mod scheduler;
+mod serial;
+#[cfg(test)]
+mod screenshot;
+mod state;
+mod symbols;
+mod vramview;
+use byteop::set_bit;
+use debugger::{Debugger, Resume};
+use gdbstub::GdbStub;
+use headless::{Headless, Outcome};
+use movie::{Movie, Session, Start};
+use profiler::Profiler;
+use rewind::Rewind;
+use runtime::Runtime;
+use state::{load_machine, save_machine};
+use symbols::Symbols;
+use trace::Trace;
+use vramview::VramView;
 
 #[derive(Parser)]
-#[command(author, version, about)]
+#[command(
+    author,
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    #[arg()]
-    rom: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(required = true)]
+    rom: Option<String>,
+
+    /// Frames between two rewind snapshots
+    #[arg(long, default_value_t = 2)]
+    rewind_interval: u32,
+
+    /// Memory budget of the rewind buffer, in MB
+    #[arg(long, default_value_t = 64)]
+    rewind_budget: usize,
+
+    /// Save state to load at startup
+    #[arg(long)]
+    state: Option<String>,
+
+    /// Record the joypad inputs into a movie file
+    #[arg(long, conflicts_with = "play")]
+    record: Option<String>,
+
+    /// Replay the joypad inputs of a movie file
+    #[arg(long)]
+    play: Option<String>,
+
+    /// Run without window and audio until the rom reports "Passed" or "Failed" on the serial
+    /// port, exits with status 0 only if passed
+    #[arg(long)]
+    headless: bool,
+
+    /// Headless mode: give up after this many frames
+    #[arg(long, default_value_t = 60 * 60)]
+    frames: u64,
+
+    /// Headless mode: give up after this many machine cycles
+    #[arg(long, default_value_t = u64::MAX)]
+    cycles: u64,
+
+    /// Start paused in the command line debugger, F12 breaks into it while running
+    #[arg(long)]
+    debug: bool,
+
+    /// Wait for a gdb remote connection on this port of localhost, and start stopped
+    #[arg(long, conflicts_with = "debug")]
+    gdb: Option<u16>,
+
+    /// Skip the boot rom, starting at 0x0100 with the registers it leaves
+    #[arg(long)]
+    no_boot: bool,
+
+    /// Write a gameboy-doctor trace of the executed instructions
+    #[arg(long)]
+    trace: Option<String>,
+
+    /// Trace only the instructions with PC in this range, e.g. `0150-3FFF`
+    #[arg(long, requires = "trace", value_parser = trace::parse_pc_range)]
+    trace_pc: Option<(u16, u16)>,
+
+    /// Trace only the instructions within this window of machine cycles, e.g. `0-100000`
+    #[arg(long, requires = "trace", value_parser = trace::parse_cycle_window)]
+    trace_cycles: Option<(u64, u64)>,
+
+    /// End the labelled lines of the trace with ` ; Label`, they no longer match gameboy-doctor
+    #[arg(long, requires = "trace")]
+    trace_symbols: bool,
+
+    /// Symbol file for the debugger and the traces, defaults to the `.sym` next to the rom
+    #[arg(long, conflicts_with = "no_symbols")]
+    symbols: Option<String>,
+
+    /// Don't load the `.sym` next to the rom
+    #[arg(long)]
+    no_symbols: bool,
+
+    /// Count the cycles spent per address and per call, written to this file on exit
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Format of the profile
+    #[arg(long, requires = "profile", value_enum, default_value_t = profiler::Format::Report)]
+    profile_format: profiler::Format,
+
+    /// What paces the emulation of the frames
+    #[arg(long, value_enum, default_value_t = Pacing::Vsync)]
+    sync: Pacing,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Pacing {
+    /// A frame per refresh of the display
+    Vsync,
+    /// The frames keep ahead of the audio playback by a couple of buffers
+    Audio,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the disassembly of a rom bank
+    Disasm {
+        rom: String,
+
+        #[arg(long, default_value_t = 0)]
+        bank: usize,
+
+        /// Symbol file, defaults to the `.sym` next to the rom
+        #[arg(long)]
+        symbols: Option<String>,
+    },
 }
 
 fn load_rom(filename: &str) -> Vec<u8> {
@@ -42,6 +189,63 @@ fn load_rom(filename: &str) -> Vec<u8> {
     return rom;
 }
 
+/// Loads `filename`, or the rgbds symbol file next to the rom if there is one.
+fn load_symbols(rom_file: &str, filename: Option<&str>) -> Option<Rc<Symbols>> {
+    let default = Path::new(rom_file).with_extension("sym");
+    let filename = match filename {
+        Some(filename) => filename,
+        None if default.exists() => default.to_str().unwrap(),
+        None => return None,
+    };
+
+    let symbols =
+        Symbols::load(filename).expect(&format!("Unable to read symbols `{}´", filename));
+    eprintln!("Loaded {} symbols from `{}´", symbols.len(), filename);
+    return Some(Rc::new(symbols));
+}
+
+fn boot<'a>(
+    args: &Args,
+    symbols: &Option<Rc<Symbols>>,
+    bootstrap: &'a Vec<u8>,
+    rom: &'a mut RomMBC<'a>,
+) -> Runtime<'a> {
+    let mut rt = if args.no_boot {
+        Runtime::noboot(bootstrap, rom)
+    } else {
+        Runtime::load(bootstrap, rom)
+    };
+
+    if let Some(filename) = &args.trace {
+        let mut trace =
+            Trace::new(filename).expect(&format!("Unable to create trace `{}´", filename));
+        trace.pc_range = args.trace_pc;
+        trace.cycle_window = args.trace_cycles;
+        if args.trace_symbols {
+            trace.symbols = symbols.clone();
+        }
+        rt.trace = Some(trace);
+    }
+
+    if let Some(filename) = &args.profile {
+        let mut profiler = Profiler::new(filename, args.profile_format);
+        profiler.symbols = symbols.clone();
+        rt.profiler = Some(profiler);
+    }
+    return rt;
+}
+
+/// Opens a debug view window, drawn at twice its size.
+fn open_view(video: &VideoSubsystem, title: &str, width: usize, height: usize) -> Canvas<Window> {
+    let window = video
+        .window(title, width as u32 * 2, height as u32 * 2)
+        .build()
+        .unwrap();
+    let mut canvas = window.into_canvas().build().unwrap();
+    canvas.set_scale(2.0, 2.0).unwrap();
+    return canvas;
+}
+
 fn get_btn(sdl_key: &str) -> Option<HWInput> {
     return match sdl_key {
         "I" => Some(HWInput::ArrUp),
@@ -56,41 +260,48 @@ fn get_btn(sdl_key: &str) -> Option<HWInput> {
     };
 }
 
-enum RomMBC<'a> {
-    RomMBC3(RomMBC3<'a>),
-    RomNoMBC(RomNoMBC<'a>),
-}
+fn main() {
+    let args = Args::parse();
 
-impl Rom<'_> for RomMBC<'_> {
-    fn set(&mut self, addr: u16, val: u8) {
-        return match self {
-            RomMBC::RomMBC3(item) => item.set(addr, val),
-            RomMBC::RomNoMBC(item) => item.set(addr, val),
-        }
-    }
-    fn get(&self, addr: u16) -> u8 {
-        return match self {
-            RomMBC::RomMBC3(item) => item.get(addr),
-            RomMBC::RomNoMBC(item) => item.get(addr),
+    if let Some(Command::Disasm { rom, bank, symbols }) = &args.command {
+        let symbols = load_symbols(rom, symbols.as_deref());
+        match disasm::listing(&load_rom(rom), *bank, symbols.as_deref()) {
+            Ok(listing) => print!("{}", listing),
+            Err(msg) => {
+                eprintln!("{}", msg);
+                std::process::exit(1);
+            }
         }
+        return;
     }
-}
-
-fn main() {
-    let args = Args::parse();
+    let rom_file = args.rom.clone().unwrap();
+    let symbols = match args.no_symbols {
+        true => None,
+        false => load_symbols(&rom_file, args.symbols.as_deref()),
+    };
 
-    let game_rom = load_rom(&args.rom);
+    let game_rom = load_rom(&rom_file);
     let bootstrap = load_rom("DMG_ROM.bin");
 
-    let mbc_type = game_rom[0x0147];
+    let mut rom = RomMBC::new(&game_rom);
 
-    let mut rom: RomMBC = match mbc_type {
-        0x13 => RomMBC::RomMBC3(RomMBC3::new(&game_rom)),
-        0..=3 => RomMBC::RomNoMBC(RomNoMBC{ rom: &game_rom }),
-        code => panic!("Unsupported mbc type {:#x}", code),
-    };
+    if args.headless {
+        let rt = boot(&args, &symbols, &bootstrap, &mut rom);
+        let mut emu = Headless::new(rt);
+        let outcome = emu.run(args.cycles, args.frames);
 
-    let mut rt = runtime::Runtime::load(&bootstrap, &mut rom);
+        println!("{}", emu.serial());
+        let result = match &outcome {
+            Outcome::Lockup(lockup) => lockup.to_string(),
+            _ => format!("{:?}", outcome),
+        };
+        println!("{} after {} frames ({} cycles)", result, emu.frames, emu.cycles);
+        // flushes the trace
+        drop(emu);
+        std::process::exit(if outcome == Outcome::Passed { 0 } else { 1 });
+    }
+
+    let mut rt = boot(&args, &symbols, &bootstrap, &mut rom);
 
     let sdl_context = sdl2::init().unwrap();
     let video = sdl_context.video().unwrap();
@@ -98,11 +309,14 @@ fn main() {
     let width = 160;
     let height = 144;
 
-    let mut apu = APU::new();
+    let apu = APU::new();
+    // read by the pacing while the audio thread plays
+    let played_samples = apu.played.clone();
+    let buffer_samples = apu.spec.samples.unwrap() as f32;
+    let spec = apu.spec.clone();
 
-    let device = audio
-        .open_playback(None, &apu.spec.clone(), |_sample| &mut apu)
-        .unwrap();
+    // the audio thread owns the apu, the emulation only reaches it under the device lock
+    let mut device = audio.open_playback(None, &spec, |_sample| apu).unwrap();
 
     device.resume();
 
@@ -112,21 +326,112 @@ fn main() {
         .build()
         .unwrap();
 
-    let mut canvas = window.into_canvas().build().unwrap();
-    let mut display = Display::from(&canvas);
+    // vsync only paces the frames on a display refreshing close to the 59.73Hz of the gameboy
+    let mut pacing = args.sync;
+    if pacing == Pacing::Vsync {
+        let refresh_rate = window.display_mode().map_or(0, |mode| mode.refresh_rate);
+        if !(59..=61).contains(&refresh_rate) {
+            eprintln!("The display refreshes at {}Hz, pacing on the audio instead", refresh_rate);
+            pacing = Pacing::Audio;
+        }
+    }
+    let mut canvas = match pacing {
+        Pacing::Vsync => window.into_canvas().present_vsync().build().unwrap(),
+        Pacing::Audio => window.into_canvas().build().unwrap(),
+    };
+    // start of the last 60 presented frames, to notice a driver ignoring vsync
+    let mut vsync_check = (time::Instant::now(), 0);
 
     canvas.set_draw_color(Color::RGB(255, 255, 255));
     let mut event_pump = sdl_context.event_pump().unwrap();
 
-    let mut ppu = PPU::new();
+    // F2 opens the vram viewer in a second window
+    let mut vram_view = VramView::new();
+    let mut vram_canvas: Option<Canvas<Window>> = None;
+    // F4 opens the audio voices view
+    let mut audio_view = AudioView::new();
+    let mut audio_canvas: Option<Canvas<Window>> = None;
+
+    // F5 saves the state here, F8 loads it back
+    let state_file = format!("{}.state", rom_file);
 
-    let refresh_target = time::Duration::from_micros(1_000_000 / 60);
-    let clock_target = time::Duration::from_nanos(1_000_000_000 / 4194304).as_nanos();
+    if let Some(filename) = &args.state {
+        let state = fs::read(filename).unwrap_or_else(|e| {
+            eprintln!("Unable to read state `{}´: {}", filename, e);
+            std::process::exit(1);
+        });
+        if let Err(e) = load_machine(&state, &mut rt, &mut device.lock()) {
+            println!("Unable to load state `{}´: {}", filename, e);
+        }
+    }
+
+    let mut session = if let Some(filename) = &args.play {
+        let movie = Movie::load(filename).unwrap_or_else(|e| {
+            eprintln!("Unable to load movie `{}´: {}", filename, e);
+            std::process::exit(1);
+        });
+        if movie.checksum != movie::hash(&game_rom) {
+            eprintln!("Movie `{}´ was recorded on a different rom", filename);
+            std::process::exit(1);
+        }
+        let start = match &movie.start {
+            Start::State(state) => load_machine(state, &mut rt, &mut device.lock()),
+            Start::PowerOn { boot_rom } if *boot_rom == args.no_boot => {
+                let flag = if args.no_boot { "without" } else { "with" };
+                eprintln!(
+                    "Movie `{}´ was recorded from power on, play it {} --no-boot",
+                    filename, flag
+                );
+                std::process::exit(1);
+            }
+            Start::PowerOn { .. } => Ok(()),
+        };
+        if let Err(e) = start {
+            println!("Unable to load the state of movie `{}´: {}", filename, e);
+            None
+        } else {
+            println!("Playing {} frames from `{}´", movie.frames(), filename);
+            Some(Session::Playing {
+                movie,
+                frame: 0,
+                desynced: false,
+            })
+        }
+    } else if args.record.is_some() {
+        let start = match args.state {
+            Some(_) => Start::State(save_machine(&rt, &device.lock())),
+            None => Start::PowerOn {
+                boot_rom: !args.no_boot,
+            },
+        };
+        Some(Session::Recording(Movie::new(&game_rom, start)))
+    } else {
+        None
+    };
+
+    // buttons currently held on the keyboard.
+    let mut joypad: u8 = 0;
+
+    // samples emulated but not played yet, and the count of played ones when last looked
+    let mut queued: f32 = 0.0;
+    let mut played = played_samples.load(Ordering::Relaxed);
+
+    let mut debugger = if args.debug {
+        println!("Type `help´ for the debugger commands");
+        Some(Debugger::new(symbols.clone()))
+    } else {
+        None
+    };
 
-    let mut ft = time::Instant::now();
-    let mut tick = time::Instant::now();
+    let mut gdb = args.gdb.map(|port| {
+        GdbStub::listen(port).unwrap_or_else(|e| {
+            eprintln!("Unable to listen for gdb on port {}: {}", port, e);
+            std::process::exit(1);
+        })
+    });
 
-    let speed = 1;
+    let mut rewind = Rewind::new(args.rewind_interval, args.rewind_budget << 20);
+    let mut rewinding = false;
     'running: loop {
         for event in event_pump.poll_iter() {
             match event {
@@ -136,11 +441,132 @@ fn main() {
                     ..
                 } => break 'running,
 
+                // with the viewer open, closing a window doesn't quit
+                Event::Window {
+                    window_id,
+                    win_event: WindowEvent::Close,
+                    ..
+                } => {
+                    if window_id == canvas.window().id() {
+                        break 'running;
+                    }
+                    for view in [&mut vram_canvas, &mut audio_canvas] {
+                        if view.as_ref().is_some_and(|c| c.window().id() == window_id) {
+                            *view = None;
+                        }
+                    }
+                }
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::F2),
+                    ..
+                } => {
+                    vram_canvas = match vram_canvas {
+                        Some(_) => None,
+                        None => Some(open_view(
+                            &video,
+                            "gbc vram",
+                            vramview::WIDTH,
+                            vramview::HEIGHT,
+                        )),
+                    };
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F4),
+                    ..
+                } => {
+                    audio_canvas = match audio_canvas {
+                        Some(_) => None,
+                        None => Some(open_view(
+                            &video,
+                            "gbc audio",
+                            audioview::WIDTH,
+                            audioview::HEIGHT,
+                        )),
+                    };
+                }
+
+                // 1-4 mute a voice, with shift solo it
+                Event::KeyDown {
+                    keycode:
+                        Some(key @ (Keycode::Num1 | Keycode::Num2 | Keycode::Num3 | Keycode::Num4)),
+                    keymod,
+                    ..
+                } => {
+                    let voice = key as usize - Keycode::Num1 as usize;
+                    if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        device.lock().toggle_solo(voice);
+                    } else {
+                        device.lock().toggle_mute(voice);
+                    }
+                }
+
+                // hold backspace to rewind
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    repeat,
+                    ..
+                } => {
+                    if session.is_some() {
+                        // restoring a snapshot would break the recorded inputs.
+                        continue;
+                    }
+                    if !repeat {
+                        println!("Rewind: {} snapshots, {} KB", rewind.len(), rewind.used() >> 10);
+                    }
+                    rewinding = true;
+                }
+                Event::KeyUp {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => rewinding = false,
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::F3),
+                    ..
+                } => print!("{}", rt.ppu.oam_report()),
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    ..
+                } => {
+                    let state = save_machine(&rt, &device.lock());
+                    fs::write(&state_file, state).unwrap();
+                    println!("State saved to `{}´", state_file);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F8),
+                    ..
+                } => {
+                    if session.is_some() {
+                        continue;
+                    }
+                    if let Ok(state) = fs::read(&state_file) {
+                        match load_machine(&state, &mut rt, &mut device.lock()) {
+                            Ok(()) => println!("State loaded from `{}´", state_file),
+                            Err(e) => println!("Unable to load state `{}´: {}", state_file, e),
+                        }
+                    }
+                }
+
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    ..
+                } => {
+                    if let Some(debugger) = &mut debugger {
+                        debugger.interrupt();
+                    }
+                }
+
                 Event::KeyDown {
                     keycode: Some(key), ..
                 } => {
                     if let Some(btn) = get_btn(&key.name()) {
-                        rt.press_btn(btn);
+                        joypad = set_bit(joypad, btn as u8, true);
+                        // while a movie is active, inputs are applied at the end of the frame.
+                        if session.is_none() {
+                            rt.press_btn(btn);
+                        }
                     }
                 }
 
@@ -148,7 +574,10 @@ fn main() {
                     keycode: Some(key), ..
                 } => {
                     if let Some(btn) = get_btn(&key.name()) {
-                        rt.release_btn(btn);
+                        joypad = set_bit(joypad, btn as u8, false);
+                        if session.is_none() {
+                            rt.release_btn(btn);
+                        }
                     }
                 }
 
@@ -156,25 +585,102 @@ fn main() {
             }
         }
 
-        let ticks = (tick.elapsed().as_nanos() / clock_target) / (speed * 4);
-        for _ in 0..ticks {
-            let cc = rt.tick();
-            rt.tick_timer(cc * 4);
-            ppu.update(&mut rt, cc * 4, &mut display);
-            apu.update(cc * 4, &mut rt);
+        device.lock().muted = rewinding;
+        if rewinding {
+            // emulation is paused, the snapshots are restored at the pace they were taken.
+            if let Some(state) = rewind.on_rewind_frame() {
+                if let Err(e) = load_machine(&state, &mut rt, &mut device.lock()) {
+                    println!("Unable to rewind: {}", e);
+                }
+            }
+        } else {
+            // one frame of the ppu, up to the start of the next vblank
+            let mut dots = 0;
+            while dots < DOTS_PER_FRAME {
+                if let Some(debugger) = &mut debugger {
+                    if let Resume::Quit = debugger.check(&mut rt) {
+                        break 'running;
+                    }
+                }
+                if let Some(gdb) = &mut gdb {
+                    if let Resume::Quit = gdb.check(&mut rt) {
+                        break 'running;
+                    }
+                }
+                let cc = rt.tick();
+                if rt.take_apu() {
+                    device.lock().update(cc * 4, &mut rt);
+                }
+                dots += cc as u32 * 4;
+                if let Some(lockup) = rt.take_lockup() {
+                    println!("{}", lockup);
+                    if let Some(debugger) = &mut debugger {
+                        debugger.interrupt();
+                    }
+                    if let Some(gdb) = &mut gdb {
+                        gdb.interrupt();
+                    }
+                }
+
+                if rt.take_frame() {
+                    if let Some(session) = &mut session {
+                        let next = session.on_frame(joypad, || {
+                            movie::hash(&save_machine(&rt, &device.lock()))
+                        });
+                        movie::set_joypad(&mut rt, next);
+                    } else {
+                        rewind.on_frame(|| save_machine(&rt, &device.lock()));
+                    }
+                    break;
+                }
+            }
+        }
+
+        // presented on vblank, waits for the refresh of the display with vsync
+        canvas.clear();
+        rt.display.render(&mut canvas);
+        canvas.present();
+
+        if let Some(view_canvas) = &mut vram_canvas {
+            vram_view.update(&rt);
+            vram_view.render(view_canvas);
         }
-        if ticks > 0 {
-            tick = time::Instant::now();
+        if let Some(view_canvas) = &mut audio_canvas {
+            audio_view.update(&device.lock());
+            audio_view.render(view_canvas);
         }
 
-        // Refresh 60fps
-        if ft.elapsed() > refresh_target {
-            // println!("Tick: {:?} ~0.25µs ({:?})", tick.elapsed(), clock_target);
-            canvas.clear();
-            ft = time::Instant::now();
-            display.render(&mut canvas);
-            canvas.present();
+        if pacing == Pacing::Vsync {
+            vsync_check.1 += 1;
+            if vsync_check.1 == 60 {
+                if vsync_check.0.elapsed() < time::Duration::from_millis(900) {
+                    eprintln!("Presenting doesn't wait for vsync, pacing on the audio instead");
+                    pacing = Pacing::Audio;
+                }
+                vsync_check = (time::Instant::now(), 0);
+            }
+        }
+        if pacing == Pacing::Audio {
+            // an underrun isn't caught up, the frames just keep on from there
+            queued += FRAME_SAMPLES;
+            loop {
+                let now_played = played_samples.load(Ordering::Relaxed);
+                queued = (queued - (now_played - played) as f32).max(0.0);
+                played = now_played;
+                if queued <= 2.0 * buffer_samples {
+                    break;
+                }
+                std::thread::sleep(time::Duration::from_millis(1));
+            }
         }
     }
+
     drop(rt);
+    if let (Some(filename), Some(Session::Recording(movie))) = (&args.record, &session) {
+        if let Err(e) = movie.save(filename) {
+            eprintln!("Unable to write movie `{}´: {}", filename, e);
+            std::process::exit(1);
+        }
+        println!("Recorded {} frames to `{}´", movie.frames(), filename);
+    }
 }
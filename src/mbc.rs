@@ -1,9 +1,72 @@
 use std::{fs::File, io::Read};
-use std::io::Write;
+use std::io::{self, Write};
+use crate::state::Reader;
 
 pub trait Rom<'b> {
     fn get(&self, addr: u16) -> u8;
     fn set(&mut self, addr: u16, val: u8);
+
+    // rom bank mapped at 0x4000-0x7FFF
+    fn rom_bank(&self) -> u8 {
+        return 1;
+    }
+
+    // banking registers & external ram, roms without an mbc have nothing to save.
+    fn save(&self, _out: &mut Vec<u8>) {}
+    fn load(&mut self, _inp: &mut Reader) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+pub enum RomMBC<'a> {
+    RomMBC3(RomMBC3<'a>),
+    RomNoMBC(RomNoMBC<'a>),
+}
+
+impl RomMBC<'_> {
+    /// Picks the memory bank controller from the cartridge header.
+    pub fn new(rom: &Vec<u8>) -> RomMBC<'_> {
+        let mbc_type = rom[0x0147];
+
+        return match mbc_type {
+            0x13 => RomMBC::RomMBC3(RomMBC3::new(rom)),
+            0..=3 => RomMBC::RomNoMBC(RomNoMBC { rom }),
+            code => panic!("Unsupported mbc type {:#x}", code),
+        };
+    }
+}
+
+impl Rom<'_> for RomMBC<'_> {
+    fn set(&mut self, addr: u16, val: u8) {
+        return match self {
+            RomMBC::RomMBC3(item) => item.set(addr, val),
+            RomMBC::RomNoMBC(item) => item.set(addr, val),
+        }
+    }
+    fn get(&self, addr: u16) -> u8 {
+        return match self {
+            RomMBC::RomMBC3(item) => item.get(addr),
+            RomMBC::RomNoMBC(item) => item.get(addr),
+        }
+    }
+    fn rom_bank(&self) -> u8 {
+        return match self {
+            RomMBC::RomMBC3(item) => item.rom_bank(),
+            RomMBC::RomNoMBC(item) => item.rom_bank(),
+        }
+    }
+    fn save(&self, out: &mut Vec<u8>) {
+        return match self {
+            RomMBC::RomMBC3(item) => item.save(out),
+            RomMBC::RomNoMBC(item) => item.save(out),
+        }
+    }
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()> {
+        return match self {
+            RomMBC::RomMBC3(item) => item.load(inp),
+            RomMBC::RomNoMBC(item) => item.load(inp),
+        }
+    }
 }
 
 pub struct RomNoMBC<'a> {
@@ -107,4 +170,23 @@ impl Rom<'_> for RomMBC3<'_> {
             }
         }
     }
+
+    fn rom_bank(&self) -> u8 {
+        return self.rom_bank;
+    }
+
+    fn save(&self, out: &mut Vec<u8>) {
+        out.push(self.rom_bank);
+        out.push(self.exram_enable as u8);
+        out.push(self.bank_or_rtc);
+        out.extend_from_slice(&self.ram);
+    }
+
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()> {
+        self.rom_bank = inp.u8()?;
+        self.exram_enable = inp.bool()?;
+        self.bank_or_rtc = inp.u8()?;
+        inp.bytes(&mut self.ram)?;
+        return Ok(());
+    }
 }
@@ -1,12 +1,31 @@
+use crate::apu::Sound;
+use crate::ppu::Lcd;
+use crate::registers;
+use crate::serial::Serial;
+use crate::state::{put_u16, Reader, Snapshot};
+use crate::symbols;
+use crate::timer::Timer;
 use crate::{byteop::*, mbc::Rom};
+use std::cell::Cell;
+use std::io;
+
+const OAM_START: usize = 0xFE00;
+// bytes of OAM, copied by a DMA transfer
+const OAM_SIZE: u8 = 160;
 
 pub trait Memory {
     fn get(&self, addr: u16) -> u8;
     fn set(&mut self, addr: u16, value: u8);
-    fn hwset(&mut self, addr: u16, value: u8);
 }
 
-#[derive(Debug)]
+/// Peripheral owning registers of the I/O region, the MMU forwards the accesses to them.
+/// Side effects of the writes (e.g. resetting DIV) belong to the handlers.
+pub trait IoDevice {
+    fn read_io(&self, addr: u16) -> u8;
+    fn write_io(&mut self, addr: u16, val: u8);
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum HWInput {
     BtnA      = 0b100,
     BtnB      = 0b101,
@@ -19,6 +38,43 @@ pub enum HWInput {
     ArrDown   = 0b011,
 }
 
+impl HWInput {
+    pub const ALL: [HWInput; 8] = [
+        HWInput::ArrRight,
+        HWInput::ArrLeft,
+        HWInput::ArrUp,
+        HWInput::ArrDown,
+        HWInput::BtnA,
+        HWInput::BtnB,
+        HWInput::BtnSelect,
+        HWInput::BtnStart,
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Watch {
+    Read,
+    Write,
+    // writes that modify the value
+    Change,
+}
+
+/// Debugger watchpoint over the addresses `start..=end`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub kind: Watch,
+    pub start: u16,
+    pub end: u16,
+}
+
+/// Access that triggered a watchpoint, `old` and `new` are equal for reads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchHit {
+    pub kind: Watch,
+    pub addr: u16,
+    pub old: u8,
+    pub new: u8,
+}
 
 pub struct MMU<'a> {
     boot_rom: &'a Vec<u8>,
@@ -29,7 +85,26 @@ pub struct MMU<'a> {
     hwcfg: u8,
 
     inputs: u8,
-    dma_ticks: u8,
+
+    pub timer: Timer,
+    pub lcd: Lcd,
+    pub sound: Sound,
+    pub serial: Serial,
+
+    // OAM DMA: source of the transfer and bytes copied so far, 160 once done
+    dma_source: u16,
+    dma_index: u8,
+    // transfer requested by a write to DMA, with the cycles left before it replaces the current one
+    dma_start: Option<(u16, u8)>,
+
+    // bytes sent over the serial port, printed to stdout when not captured.
+    sent: Option<Vec<u8>>,
+
+    pub watchpoints: Vec<Watchpoint>,
+    // set while the cpu executes, accesses of the ppu and apu don't trigger watchpoints.
+    pub watching: bool,
+    // first hit since the last `take_watch_hit`, `get` only borrows the MMU.
+    watch_hit: Cell<Option<WatchHit>>,
 }
 
 impl MMU<'_> {
@@ -43,12 +118,22 @@ impl MMU<'_> {
             vram: vec![0; 0x9fff - 0x8000 + 1],
             wram: vec![0; 0xffff - 0x8000 + 1],
             inputs: 0xFF,
-            dma_ticks: 0,
+            timer: Timer::new(),
+            lcd: Lcd::default(),
+            sound: Sound::new(),
+            serial: Serial::new(),
+            dma_source: 0,
+            dma_index: OAM_SIZE,
+            dma_start: None,
+            sent: None,
+            watchpoints: vec![],
+            watching: false,
+            watch_hit: Cell::new(None),
         }
     }
 
     fn boot_rom_disabled(&self) -> bool {
-        return self.get(0xFF50) == 1;
+        return self.read(0xFF50) == 1;
     }
 
     pub fn press(&mut self, btn: HWInput, pressed: bool) {
@@ -56,29 +141,95 @@ impl MMU<'_> {
         self.inputs = set_bit(self.inputs, addr, !pressed);
     }
 
-    // TODO: transfer take 160 machine cycles: 640 dots
+    pub fn capture_serial(&mut self) {
+        self.sent = Some(vec![]);
+    }
+
+    pub fn serial_output(&self) -> &[u8] {
+        return match &self.sent {
+            Some(out) => out,
+            None => &[],
+        };
+    }
+
+    /// Starts an OAM DMA transfer after a machine cycle of setup, a transfer in progress goes on
+    /// meanwhile.
     fn dma(&mut self, addr: u8) {
-        let source = (addr as u16) << 8;
-        let dest = 0xFE00;
+        self.dma_start = Some(((addr as u16) << 8, 2));
+    }
 
-        for i in 0x00..=0x9Fu16 {
-            let byte = self.get(source + i);
-            self.set(dest + i, byte);
+    /// Advances the OAM DMA by `ticks` machine cycles, one byte is copied per cycle.
+    pub fn tick(&mut self, ticks: u8) {
+        for _ in 0..ticks {
+            if self.dma_active() {
+                let byte = self.read(self.dma_source + self.dma_index as u16);
+                self.wram[OAM_START - 0xA000 + self.dma_index as usize] = byte;
+                self.dma_index += 1;
+            }
+            if let Some((source, delay)) = self.dma_start {
+                if delay == 1 {
+                    self.dma_source = source;
+                    self.dma_index = 0;
+                    self.dma_start = None;
+                } else {
+                    self.dma_start = Some((source, delay - 1));
+                }
+            }
         }
-        self.dma_ticks = 160;
     }
 
-    pub fn tick(&mut self, ticks: u8) {
-        if self.dma_ticks > ticks {
-            self.dma_ticks -= ticks;
-        } else {
-            self.dma_ticks = 0;
+    /// Sets `bit` of IF, without triggering watchpoints.
+    pub fn request_interrupt(&mut self, bit: u8) {
+        let interrupt_flag = self.read(registers::IF);
+        self.write(registers::IF, set_bit(interrupt_flag, bit, true));
+    }
+
+    pub fn dma_active(&self) -> bool {
+        return self.dma_index < OAM_SIZE;
+    }
+
+    /// During an OAM DMA the cpu only reaches the registers and HRAM, other reads return 0xFF.
+    pub fn dma_blocks(&self, addr: u16) -> bool {
+        return self.dma_active() && addr < 0xFF00;
+    }
+
+    /// Bank of `addr`, as numbered in symbol files.
+    pub fn bank_of(&self, addr: u16) -> u8 {
+        return symbols::bank_of(addr, self.rom_bank());
+    }
+
+    /// Rom bank mapped at 0x4000-0x7FFF.
+    pub fn rom_bank(&self) -> u8 {
+        return self.rom.rom_bank();
+    }
+
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        return self.watch_hit.take();
+    }
+
+    fn check_watchpoints(&self, addr: u16, old: u8, new: u8, is_read: bool) {
+        if self.watch_hit.get().is_some() {
+            return;
+        }
+        for wp in &self.watchpoints {
+            let triggered = match wp.kind {
+                Watch::Read => is_read,
+                Watch::Write => !is_read,
+                Watch::Change => !is_read && old != new,
+            };
+            if triggered && wp.start <= addr && addr <= wp.end {
+                self.watch_hit.set(Some(WatchHit {
+                    kind: wp.kind,
+                    addr,
+                    old,
+                    new,
+                }));
+                return;
+            }
         }
     }
-}
 
-impl Memory for MMU<'_> {
-    fn get(&self, addr: u16) -> u8 {
+    fn read(&self, addr: u16) -> u8 {
         return match addr {
             0x0000..=0x00FF => {
                 if self.boot_rom_disabled() {
@@ -96,64 +247,65 @@ impl Memory for MMU<'_> {
             }
 
             0xE000..=0xFDFF => {
-                // mirror of 0xCD00-0xDDFF
+                // echo of 0xC000-0xDDFF
                 self.wram[addr as usize - 0xA000 - 0x2000]
             }
 
             0xFF00 => {
-                let read_mask = self.wram[addr as usize - 0xA000];
-                get_inputs(read_mask, self.inputs)
+                let select = self.wram[addr as usize - 0xA000];
+                get_inputs(select, self.inputs)
+            }
+            registers::SB..=registers::SC => self.serial.read_io(addr),
+            registers::DIV..=registers::TAC => self.timer.read_io(addr),
+            registers::NR10..=0xFF3F => self.sound.read_io(addr),
+            registers::LCDC..=registers::LYC | registers::BGP..=registers::WX => {
+                self.lcd.read_io(addr)
             }
             0xA000..=0xBFFF => self.rom.get(addr),
+            // OAM is taken by the DMA, the ppu sees it as inaccessible too
+            0xFE00..=0xFEFF if self.dma_active() => 0xFF,
+            // not usable, reads 0 on DMG
+            0xFEA0..=0xFEFF => 0x00,
             0xC000..=0xFFFF => self.wram[(addr - 0xA000) as usize],
-            _ => {
-                panic!("Memory access out of bounds! {}", b64(addr));
-            }
         };
     }
 
-    fn hwset(&mut self, addr: u16, val: u8) -> () {
-        match addr {
-            0xFF26 => {
-                self.wram[addr as usize - 0xA000] = val
-            }
-            0xFF04 => {
-                self.wram[addr as usize - 0xA000] = val
-            }
-            _ => panic!("Unhandled address {} for hwset", b64(addr)),
-        }
-    }
-
-    fn set(&mut self, addr: u16, val: u8) -> () {
+    fn write(&mut self, addr: u16, val: u8) {
         match addr {
             0x0000..=0x7FFF => {
                 self.rom.set(addr, val);
             }
             0x8000..=0x9FFF => self.vram[(addr - 0x8000) as usize] = val,
             0xE000..=0xFDFF => {
-                // mirror of 0xCD00-0xDDFF
+                // echo of 0xC000-0xDDFF
                 self.wram[addr as usize - 0xA000 - 0x2000] = val;
             }
+            0xFEA0..=0xFEFF => {
+                // not usable
+            }
             0xFF00 => {
-                let read_mask = (val & 0x30) + 0x60;
-                self.wram[addr as usize - 0xA000] = read_mask;
-            }
-            0xFF01 => {
-                print!("{}", val as char);
-            }
-            0xFF26 => {
-                // only the first bit of this register can be set by games,
-                // this register can only be modified via hwset
-                let current_value = self.get(0xFF26) ;
-                let new_value: u8 = set_bit(
-                    current_value,
-                    7,
-                    get_bit(val, 7) == 1,
-                );
-                self.hwset(addr, new_value);
-            }
-            0xFF04 => {
-                self.hwset(addr, 0);
+                // only the selection of the buttons or the dpad is written
+                self.wram[addr as usize - 0xA000] = val & 0x30;
+            }
+            registers::SB..=registers::SC => {
+                self.serial.write_io(addr, val);
+                if addr == registers::SC && self.serial.next_event().is_some() {
+                    // the byte is sent as the transfer starts
+                    let byte = self.serial.read_io(registers::SB);
+                    match &mut self.sent {
+                        Some(out) => out.push(byte),
+                        None => print!("{}", byte as char),
+                    }
+                }
+            }
+            registers::DIV..=registers::TAC => self.timer.write_io(addr, val),
+            registers::NR10..=0xFF3F => self.sound.write_io(addr, val),
+            registers::LCDC..=registers::LYC | registers::BGP..=registers::WX => {
+                self.lcd.write_io(addr, val)
+            }
+            registers::BOOT => {
+                // once unmapped, the boot rom stays unmapped until the next reset
+                self.wram[addr as usize - 0xA000] |= val & 0x01;
             }
             0xFF46 => {
                 self.wram[(addr - 0xA000) as usize] = val;
@@ -163,15 +315,67 @@ impl Memory for MMU<'_> {
             0xC000..=0xFFFF => {
                 self.wram[(addr - 0xA000) as usize] = val
             },
-            _ => {
-                // panic!("Access to unknown memory region {}", addr)
-            }
         }
     }
 }
 
-fn get_inputs(mask: u8, inputs: u8) -> u8 {
-    let upper = (mask & 0x30) + 0x60;
+impl Memory for MMU<'_> {
+    fn get(&self, addr: u16) -> u8 {
+        let val = self.read(addr);
+        if self.watching && !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, val, val, true);
+        }
+        return val;
+    }
+
+    fn set(&mut self, addr: u16, val: u8) -> () {
+        if self.watching && !self.watchpoints.is_empty() {
+            let old = self.read(addr);
+            self.write(addr, val);
+            self.check_watchpoints(addr, old, self.read(addr), false);
+        } else {
+            self.write(addr, val);
+        }
+    }
+}
+
+impl Snapshot for MMU<'_> {
+    fn save(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.vram);
+        out.extend_from_slice(&self.wram);
+        out.push(self.inputs);
+        self.timer.save(out);
+        self.lcd.save(out);
+        self.sound.save(out);
+        self.serial.save(out);
+        put_u16(out, self.dma_source);
+        out.push(self.dma_index);
+        let (start, delay) = self.dma_start.unwrap_or((0, 0));
+        put_u16(out, start);
+        out.push(delay);
+        self.rom.save(out);
+    }
+
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()> {
+        inp.bytes(&mut self.vram)?;
+        inp.bytes(&mut self.wram)?;
+        self.inputs = inp.u8()?;
+        self.timer.load(inp)?;
+        self.lcd.load(inp)?;
+        self.sound.load(inp)?;
+        self.serial.load(inp)?;
+        self.dma_source = inp.u16()?;
+        self.dma_index = inp.u8()?;
+        let start = inp.u16()?;
+        let delay = inp.u8()?;
+        self.dma_start = if delay > 0 { Some((start, delay)) } else { None };
+        self.rom.load(inp)?;
+        return Ok(());
+    }
+}
+
+fn get_inputs(select: u8, inputs: u8) -> u8 {
+    let upper = 0xC0 | (select & 0x30);
     let lower = if get_bit(upper, 4) == 0 {
         // dpad
         inputs & 0b1111
@@ -181,5 +385,72 @@ fn get_inputs(mask: u8, inputs: u8) -> u8 {
         0xF
     };
 
-    return upper + lower;
+    return upper | lower;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::RomMBC;
+
+    #[test]
+    fn test_dma_copies_a_byte_per_cycle() {
+        let game_rom = vec![0; 0x8000];
+        let bootstrap = vec![0; 0x100];
+        let mut rom = RomMBC::new(&game_rom);
+        let mut mmu = MMU::new(&bootstrap, &mut rom);
+        mmu.set(0xC000, 0x12);
+        mmu.set(0xC09F, 0x34);
+
+        mmu.set(0xFF46, 0xC0);
+        // setup
+        mmu.tick(1);
+        assert!(!mmu.dma_active());
+        mmu.tick(1);
+        assert!(mmu.dma_active());
+
+        mmu.tick(159);
+        assert!(mmu.dma_active());
+        assert_eq!(mmu.get(0xFE00), 0xFF);
+        mmu.tick(1);
+        assert!(!mmu.dma_active());
+        assert_eq!(mmu.get(0xFE00), 0x12);
+        assert_eq!(mmu.get(0xFE9F), 0x34);
+    }
+
+    #[test]
+    fn test_dma_restart_takes_over_the_transfer() {
+        let game_rom = vec![0; 0x8000];
+        let bootstrap = vec![0; 0x100];
+        let mut rom = RomMBC::new(&game_rom);
+        let mut mmu = MMU::new(&bootstrap, &mut rom);
+        mmu.set(0xC000, 0x12);
+        mmu.set(0xC100, 0x34);
+
+        mmu.set(0xFF46, 0xC0);
+        mmu.tick(20);
+        mmu.set(0xFF46, 0xC1);
+        // the first transfer goes on during the setup of the second one
+        mmu.tick(1);
+        assert!(mmu.dma_blocks(0xC000));
+        mmu.tick(1 + 159);
+        assert!(mmu.dma_active());
+        mmu.tick(1);
+        assert!(!mmu.dma_blocks(0xC000));
+        assert_eq!(mmu.get(0xFE00), 0x34);
+    }
+
+    #[test]
+    fn test_boot_rom_unmap_is_sticky() {
+        let game_rom = vec![0x02; 0x8000];
+        let bootstrap = vec![0x11; 0x100];
+        let mut rom = RomMBC::new(&game_rom);
+        let mut mmu = MMU::new(&bootstrap, &mut rom);
+        assert_eq!(mmu.get(0x0000), 0x11);
+
+        mmu.set(registers::BOOT, 0x01);
+        assert_eq!(mmu.get(0x0000), 0x02);
+        mmu.set(registers::BOOT, 0x00);
+        assert_eq!(mmu.get(0x0000), 0x02);
+    }
 }
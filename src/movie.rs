@@ -0,0 +1,289 @@
+use crate::byteop::get_bit;
+use crate::memory::HWInput;
+use crate::runtime::Runtime;
+use std::fs;
+use std::io;
+
+const MAGIC: &[u8; 4] = b"GBCM";
+const VERSION: u8 = 2;
+
+// frames between two state hashes, used to detect a desync on playback.
+const HASH_EVERY: u32 = 60;
+
+pub enum Start {
+    // started with the boot rom, or at 0x0100 with `--no-boot`
+    PowerOn { boot_rom: bool },
+    State(Vec<u8>),
+}
+
+/// Joypad state of every frame, one byte per frame with bit `HWInput as u8` set when pressed.
+pub struct Movie {
+    pub checksum: u64,
+    pub start: Start,
+    inputs: Vec<u8>,
+    hashes: Vec<(u32, u64)>,
+}
+
+/// FNV-1a, used both for the rom checksum and for the state hashes.
+pub fn hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in data {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    return h;
+}
+
+fn read_u32(data: &[u8], pos: &mut usize) -> io::Result<u32> {
+    let bytes = data
+        .get(*pos..*pos + 4)
+        .ok_or(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated movie"))?;
+    *pos += 4;
+    return Ok(u32::from_le_bytes(bytes.try_into().unwrap()));
+}
+
+fn read_u64(data: &[u8], pos: &mut usize) -> io::Result<u64> {
+    let l = read_u32(data, pos)? as u64;
+    let h = read_u32(data, pos)? as u64;
+    return Ok((h << 32) + l);
+}
+
+fn read_bytes<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> io::Result<&'a [u8]> {
+    let bytes = data
+        .get(*pos..*pos + len)
+        .ok_or(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated movie"))?;
+    *pos += len;
+    return Ok(bytes);
+}
+
+impl Movie {
+    pub fn new(rom: &[u8], start: Start) -> Movie {
+        Movie {
+            checksum: hash(rom),
+            start,
+            inputs: vec![],
+            hashes: vec![],
+        }
+    }
+
+    pub fn frames(&self) -> usize {
+        return self.inputs.len();
+    }
+
+    pub fn save(&self, filename: &str) -> io::Result<()> {
+        let mut out = vec![];
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&self.checksum.to_le_bytes());
+
+        match &self.start {
+            Start::PowerOn { boot_rom } => out.extend_from_slice(&[0, *boot_rom as u8]),
+            Start::State(state) => {
+                out.push(1);
+                out.extend_from_slice(&(state.len() as u32).to_le_bytes());
+                out.extend_from_slice(state);
+            }
+        }
+
+        out.extend_from_slice(&(self.inputs.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.inputs);
+
+        out.extend_from_slice(&(self.hashes.len() as u32).to_le_bytes());
+        for (frame, h) in &self.hashes {
+            out.extend_from_slice(&frame.to_le_bytes());
+            out.extend_from_slice(&h.to_le_bytes());
+        }
+
+        return fs::write(filename, out);
+    }
+
+    pub fn load(filename: &str) -> io::Result<Movie> {
+        let data = fs::read(filename)?;
+        let mut pos = 0;
+
+        let header = read_bytes(&data, &mut pos, 5)?;
+        if &header[..4] != MAGIC || header[4] != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("`{}´ is not a movie file", filename),
+            ));
+        }
+
+        let checksum = read_u64(&data, &mut pos)?;
+        let start = match read_bytes(&data, &mut pos, 1)?[0] {
+            0 => Start::PowerOn {
+                boot_rom: read_bytes(&data, &mut pos, 1)?[0] != 0,
+            },
+            _ => {
+                let len = read_u32(&data, &mut pos)? as usize;
+                Start::State(read_bytes(&data, &mut pos, len)?.to_vec())
+            }
+        };
+
+        let len = read_u32(&data, &mut pos)? as usize;
+        let inputs = read_bytes(&data, &mut pos, len)?.to_vec();
+
+        let mut hashes = vec![];
+        for _ in 0..read_u32(&data, &mut pos)? {
+            let frame = read_u32(&data, &mut pos)?;
+            let h = read_u64(&data, &mut pos)?;
+            hashes.push((frame, h));
+        }
+
+        return Ok(Movie {
+            checksum,
+            start,
+            inputs,
+            hashes,
+        });
+    }
+}
+
+pub enum Session {
+    Recording(Movie),
+    Playing { movie: Movie, frame: u32, desynced: bool },
+}
+
+impl Session {
+    /// Called at the end of every frame, returns the joypad state to use for the next frame.
+    /// `joypad` is the live state of the keyboard, used when recording and once playback ends.
+    pub fn on_frame(&mut self, joypad: u8, state_hash: impl FnOnce() -> u64) -> u8 {
+        match self {
+            Session::Recording(movie) => {
+                let frame = movie.inputs.len() as u32;
+                if frame.is_multiple_of(HASH_EVERY) {
+                    movie.hashes.push((frame, state_hash()));
+                }
+                movie.inputs.push(joypad);
+                return joypad;
+            }
+            Session::Playing {
+                movie,
+                frame,
+                desynced,
+            } => {
+                let current = *frame;
+                if current as usize > movie.inputs.len() {
+                    return joypad;
+                }
+                *frame += 1;
+
+                if current.is_multiple_of(HASH_EVERY) && !*desynced {
+                    if let Some((_, expected)) = movie.hashes.iter().find(|(f, _)| *f == current) {
+                        let got = state_hash();
+                        if got != *expected {
+                            println!(
+                                "Movie desync at frame {}: expected state {:016x}, got {:016x}",
+                                current, expected, got
+                            );
+                            *desynced = true;
+                        }
+                    }
+                }
+
+                return match movie.inputs.get(current as usize) {
+                    Some(input) => *input,
+                    None => {
+                        println!("Movie ended after {} frames", current);
+                        joypad
+                    }
+                };
+            }
+        }
+    }
+}
+
+/// Presses and releases the buttons of the runtime to match `joypad`.
+pub fn set_joypad(rt: &mut Runtime, joypad: u8) {
+    for btn in HWInput::ALL {
+        if get_bit(joypad, btn as u8) == 1 {
+            rt.press_btn(btn);
+        } else {
+            rt.release_btn(btn);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_playback_returns_recorded_inputs() {
+        let mut rec = Session::Recording(Movie::new(&[1, 2, 3], Start::PowerOn { boot_rom: true }));
+        for i in 0..130u32 {
+            rec.on_frame(i as u8, || i as u64);
+        }
+        let movie = match rec {
+            Session::Recording(movie) => movie,
+            _ => unreachable!(),
+        };
+        assert_eq!(movie.hashes, vec![(0, 0), (60, 60), (120, 120)]);
+
+        let mut play = Session::Playing {
+            movie,
+            frame: 0,
+            desynced: false,
+        };
+        for i in 0..130u32 {
+            assert_eq!(play.on_frame(0xFF, || i as u64), i as u8);
+        }
+        assert_eq!(play.on_frame(0xAB, || 0), 0xAB);
+    }
+
+    #[test]
+    fn test_playback_detects_desync() {
+        let mut movie = Movie::new(&[], Start::PowerOn { boot_rom: true });
+        movie.inputs = vec![0; 100];
+        movie.hashes = vec![(0, 1), (60, 2)];
+
+        let mut play = Session::Playing {
+            movie,
+            frame: 0,
+            desynced: false,
+        };
+        for i in 0..100 {
+            play.on_frame(0, || if i < 60 { 1 } else { 3 });
+        }
+        match play {
+            Session::Playing { desynced, .. } => assert!(desynced),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_power_on_keeps_the_boot_mode() {
+        let movie = Movie::new(&[0xAA; 16], Start::PowerOn { boot_rom: false });
+        let path = std::env::temp_dir().join("gbc_test_movie_noboot.gbm");
+        let path = path.to_str().unwrap();
+        movie.save(path).unwrap();
+        let loaded = Movie::load(path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        match loaded.start {
+            Start::PowerOn { boot_rom } => assert!(!boot_rom),
+            Start::State(_) => panic!("expected a power on"),
+        }
+    }
+
+    #[test]
+    fn test_save_load_roundtrip() {
+        let mut movie = Movie::new(&[0xAA; 16], Start::State(vec![1, 2, 3]));
+        movie.inputs = vec![0, 1, 0x80];
+        movie.hashes = vec![(0, 0xDEADBEEF)];
+
+        let path = std::env::temp_dir().join("gbc_test_movie.gbm");
+        let path = path.to_str().unwrap();
+        movie.save(path).unwrap();
+        let loaded = Movie::load(path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded.checksum, movie.checksum);
+        assert_eq!(loaded.inputs, movie.inputs);
+        assert_eq!(loaded.hashes, movie.hashes);
+        match loaded.start {
+            Start::State(state) => assert_eq!(state, vec![1, 2, 3]),
+            Start::PowerOn { .. } => panic!("expected a save state"),
+        }
+    }
+}
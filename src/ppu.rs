@@ -1,12 +1,17 @@
 use crate::byteop::*;
-use crate::memory::Memory;
+use crate::memory::{IoDevice, Memory, MMU};
 use crate::registers;
+use crate::state::{put_u16, Reader, Snapshot};
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
 use sdl2::render::Canvas;
 use std::collections::VecDeque;
+use std::io;
 use std::option::Option;
 
+/// Dots of a frame, 154 lines of 456 dots.
+pub const DOTS_PER_FRAME: u32 = 154 * 456;
+
 pub struct PPU {
     x: u8,
 
@@ -24,6 +29,8 @@ pub struct PPU {
     sprites: Vec<Sprite>,
 
     ppu_state: u8,
+    // the STAT interrupt is requested when one of its sources becomes true
+    stat_line: bool,
 
     wait: u16,
     waited: u16,
@@ -54,6 +61,64 @@ struct Sprite {
     flags: u8,
 }
 
+/// LCD registers, the cpu writes them and the ppu reports LY and its mode through them.
+#[derive(Default)]
+pub struct Lcd {
+    pub lcdc: u8,
+    // interrupt sources of STAT (bits 3-6), the rest is computed on read
+    pub stat: u8,
+    pub scy: u8,
+    pub scx: u8,
+    pub ly: u8,
+    pub lyc: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+    pub wy: u8,
+    pub wx: u8,
+    pub mode: u8,
+}
+
+impl IoDevice for Lcd {
+    fn read_io(&self, addr: u16) -> u8 {
+        return match addr {
+            registers::LCDC => self.lcdc,
+            registers::STAT => {
+                0x80 | self.stat | ((self.ly == self.lyc) as u8) << 2 | self.mode
+            }
+            registers::SCY => self.scy,
+            registers::SCX => self.scx,
+            registers::LY => self.ly,
+            registers::LYC => self.lyc,
+            registers::BGP => self.bgp,
+            registers::OBP0 => self.obp0,
+            registers::OBP1 => self.obp1,
+            registers::WY => self.wy,
+            registers::WX => self.wx,
+            _ => 0xFF,
+        };
+    }
+
+    fn write_io(&mut self, addr: u16, val: u8) {
+        match addr {
+            registers::LCDC => self.lcdc = val,
+            // the coincidence flag and the mode are read only
+            registers::STAT => self.stat = val & 0x78,
+            registers::SCY => self.scy = val,
+            registers::SCX => self.scx = val,
+            // read only
+            registers::LY => {}
+            registers::LYC => self.lyc = val,
+            registers::BGP => self.bgp = val,
+            registers::OBP0 => self.obp0 = val,
+            registers::OBP1 => self.obp1 = val,
+            registers::WY => self.wy = val,
+            registers::WX => self.wx = val,
+            _ => {}
+        }
+    }
+}
+
 const PALETTE: [Color; 4] = [
     Color::RGB(255, 255, 255),
     Color::RGB(169, 169, 169),
@@ -61,7 +126,7 @@ const PALETTE: [Color; 4] = [
     Color::RGB(0, 0, 0),
 ];
 
-fn color_from_code(code: usize) -> Color {
+pub fn color_from_code(code: usize) -> Color {
     return PALETTE[code];
 }
 
@@ -105,6 +170,27 @@ impl Sprite {
     fn palette(&self) -> u8 {
         return get_bit(self.flags, 4);
     }
+
+    /// One line of the oam report: position, tile and decoded flags.
+    fn describe(&self) -> String {
+        let flip = match (get_bit(self.flags, 5), get_bit(self.flags, 6)) {
+            (0, 0) => "-",
+            (1, 0) => "X",
+            (0, _) => "Y",
+            _ => "XY",
+        };
+        let priority = if get_bit(self.flags, 7) == 0 { "obj" } else { "bg" };
+        return format!(
+            "{:04X}  {:3} {:3}   {:02X}  OBP{}  {:<4}  {}",
+            self.addr,
+            self.x,
+            self.y,
+            self.tile,
+            self.palette(),
+            flip,
+            priority
+        );
+    }
 }
 
 impl PPU {
@@ -130,6 +216,7 @@ impl PPU {
             sprites,
 
             ppu_state: 1,
+            stat_line: false,
             waited: 0,
 
             filtered_sprites: Vec::with_capacity(10),
@@ -138,25 +225,58 @@ impl PPU {
         }
     }
 
+    /// Lists the 40 sprites as of the last oam scan. While the current line is being drawn,
+    /// marks the ones selected for it and the visible ones dropped by the 10 sprites limit.
+    pub fn oam_report(&self) -> String {
+        // the selection belongs to the current line only in mode 3 and the following hblank
+        let scanned = self.ppu_state == 3 || self.ppu_state == 0;
+        let obj_size = get_bit(self.r_control, 2);
+
+        let mut out = format!("OAM at line {}, mode {}\n", self.ly, self.ppu_state);
+        out.push_str(" #  addr    x   y  tile  pal  flip  priority\n");
+        for (i, s) in self.sprites.iter().enumerate() {
+            let selected = self.filtered_sprites.iter().any(|f| f.addr == s.addr);
+            let mark = if !scanned {
+                ""
+            } else if selected {
+                "  < selected"
+            } else if s.is_visible(self.ly, obj_size) {
+                "  < dropped"
+            } else {
+                ""
+            };
+            out.push_str(&format!("{:2}  {}{}\n", i, s.describe(), mark));
+        }
+        return out;
+    }
+
     fn get_color(&self, id: u8, palette: u8) -> u8 {
         let shift = id * 2;
         let color = (palette & (0b11 << shift)) >> shift;
         return color;
     }
 
-    pub fn update(&mut self, rt: &mut impl Memory, dots: u8, display: &mut Display) {
-        self.r_control = rt.get(registers::LCDC);
-        self.r_status = rt.get(registers::STAT);
-        self.scy = rt.get(registers::SCY);
-        self.scx = rt.get(registers::SCX);
-        self.ly = rt.get(registers::LY);
-        self.lyc = rt.get(registers::LYC); // 0..=153
-        self.bgp = rt.get(registers::BGP);
-        self.obp0 = rt.get(registers::OBP0);
-        self.obp1 = rt.get(registers::OBP1);
-        self.wy = rt.get(registers::WY);
-        self.wx = rt.get(registers::WX);
-        let mut dots: u16 = dots.into();
+    /// Dots until the end of the current step, when `update` has work to do.
+    pub fn next_event(&self) -> u32 {
+        return self.wait as u32;
+    }
+
+    /// Advances the ppu by `dots`, returns true when a frame has been completed (VBLANK started).
+    pub fn update(&mut self, rt: &mut MMU, dots: u32, display: &mut Display) -> bool {
+        let lcd = &rt.lcd;
+        self.r_control = lcd.lcdc;
+        self.r_status = lcd.stat;
+        self.scy = lcd.scy;
+        self.scx = lcd.scx;
+        self.ly = lcd.ly;
+        self.lyc = lcd.lyc; // 0..=153
+        self.bgp = lcd.bgp;
+        self.obp0 = lcd.obp0;
+        self.obp1 = lcd.obp1;
+        self.wy = lcd.wy;
+        self.wx = lcd.wx;
+        let mut dots = dots;
+        let mut frame = false;
 
         //        // mode 2. OAM scan, read values from RAM
         //        if self.ppu_state == 2 {
@@ -165,22 +285,23 @@ impl PPU {
         //            }
         //        }
 
-        while dots > 0 {
+        // the next step starts as soon as the current one is waited
+        while dots > 0 || self.wait == 0 {
             if self.wait > 0 {
-                if self.wait >= dots {
-                    self.wait -= dots;
-                    dots = 0;
-                } else {
-                    dots -= self.wait;
-                    self.wait = 0;
-                }
+                let waited = dots.min(self.wait as u32);
+                self.wait -= waited as u16;
+                dots -= waited;
             } else {
+                let state = self.ppu_state;
                 self.render(rt, display);
+                let vblank = state == 0 && self.ppu_state == 1;
+                frame |= vblank;
+                self.update_registers(rt, vblank);
             }
         }
-        self.update_registers(rt);
-
-        assert!(rt.get(registers::IF) & 0b1 == (self.ly >= 144) as u8);
+        // LYC or the STAT sources may have been written
+        self.update_registers(rt, false);
+        return frame;
     }
 
     fn render(&mut self, rt: &mut impl Memory, display: &mut Display) {
@@ -274,14 +395,13 @@ impl PPU {
         }
     }
 
-    fn update_registers(&mut self, rt: &mut impl Memory) {
-        let mut r_status = self.r_status;
+    fn update_registers(&mut self, rt: &mut MMU, vblank: bool) {
+        let r_status = self.r_status;
 
         // setting the interrupt flags
         let mut reg_if = rt.get(registers::IF);
 
-        reg_if = set_bit(reg_if, 0, self.ppu_state == 1);
-        reg_if = set_bit(reg_if, 1, self.ly == self.lyc);
+        reg_if |= vblank as u8;
 
         // https://gbdev.io/pandocs/STAT.html#ff41--stat-lcd-status
         let mut stat_int = false;
@@ -301,15 +421,14 @@ impl PPU {
             stat_int |= true;
         }
 
-        r_status = set_bit(r_status, 2, self.ly == self.lyc);
-        r_status &= 0b11111100;
-        r_status |= self.ppu_state;
-
-        reg_if = set_bit(reg_if, 1, stat_int);
+        if stat_int && !self.stat_line {
+            reg_if = set_bit(reg_if, 1, true);
+        }
+        self.stat_line = stat_int;
 
         rt.set(registers::IF, reg_if);
-        rt.set(registers::STAT, r_status); // stat register
-        rt.set(registers::LY, self.ly); // current horizontal line
+        rt.lcd.mode = self.ppu_state;
+        rt.lcd.ly = self.ly; // current horizontal line
     }
 
     fn tile_offset(&self, id: u8) -> u16 {
@@ -593,6 +712,138 @@ impl PPU {
     }
 }
 
+impl Snapshot for Sprite {
+    fn save(&self, out: &mut Vec<u8>) {
+        put_u16(out, self.addr);
+        out.extend_from_slice(&[self.x, self.y, self.tile, self.flags]);
+    }
+
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()> {
+        self.addr = inp.u16()?;
+        self.x = inp.u8()?;
+        self.y = inp.u8()?;
+        self.tile = inp.u8()?;
+        self.flags = inp.u8()?;
+        return Ok(());
+    }
+}
+
+impl Snapshot for Lcd {
+    fn save(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0,
+            self.obp1, self.wy, self.wx, self.mode,
+        ]);
+    }
+
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()> {
+        self.lcdc = inp.u8()?;
+        self.stat = inp.u8()?;
+        self.scy = inp.u8()?;
+        self.scx = inp.u8()?;
+        self.ly = inp.u8()?;
+        self.lyc = inp.u8()?;
+        self.bgp = inp.u8()?;
+        self.obp0 = inp.u8()?;
+        self.obp1 = inp.u8()?;
+        self.wy = inp.u8()?;
+        self.wx = inp.u8()?;
+        self.mode = inp.u8()?;
+        return Ok(());
+    }
+}
+
+impl Snapshot for PPU {
+    fn save(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[
+            self.x,
+            self.r_control,
+            self.r_status,
+            self.scx,
+            self.scy,
+            self.ly,
+            self.lyc,
+            self.obp0,
+            self.obp1,
+            self.wx,
+            self.wy,
+            self.bgp,
+            self.ppu_state,
+        ]);
+        out.push(self.stat_line as u8);
+        put_u16(out, self.wait);
+        put_u16(out, self.waited);
+
+        for s in &self.sprites {
+            s.save(out);
+        }
+
+        out.push(self.filtered_sprites.len() as u8);
+        for s in &self.filtered_sprites {
+            s.save(out);
+        }
+
+        out.push(self.pixel_fifo_bg.len() as u8);
+        for px in &self.pixel_fifo_bg {
+            match px.source {
+                FIFOPixelSource::BACKGROUND => out.push(0),
+                FIFOPixelSource::WINDOW => out.push(1),
+                FIFOPixelSource::SPRITE(s) => {
+                    out.push(2);
+                    s.save(out);
+                }
+            }
+            out.push(px.color_id);
+        }
+    }
+
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()> {
+        self.x = inp.u8()?;
+        self.r_control = inp.u8()?;
+        self.r_status = inp.u8()?;
+        self.scx = inp.u8()?;
+        self.scy = inp.u8()?;
+        self.ly = inp.u8()?;
+        self.lyc = inp.u8()?;
+        self.obp0 = inp.u8()?;
+        self.obp1 = inp.u8()?;
+        self.wx = inp.u8()?;
+        self.wy = inp.u8()?;
+        self.bgp = inp.u8()?;
+        self.ppu_state = inp.u8()?;
+        self.stat_line = inp.bool()?;
+        self.wait = inp.u16()?;
+        self.waited = inp.u16()?;
+
+        for s in &mut self.sprites {
+            Snapshot::load(s, inp)?;
+        }
+
+        self.filtered_sprites.clear();
+        for _ in 0..inp.u8()? {
+            let mut s = Sprite::default();
+            Snapshot::load(&mut s, inp)?;
+            self.filtered_sprites.push(s);
+        }
+
+        self.pixel_fifo_bg.clear();
+        for _ in 0..inp.u8()? {
+            let source = match inp.u8()? {
+                0 => FIFOPixelSource::BACKGROUND,
+                1 => FIFOPixelSource::WINDOW,
+                _ => {
+                    let mut s = Sprite::default();
+                    Snapshot::load(&mut s, inp)?;
+                    FIFOPixelSource::SPRITE(s)
+                }
+            };
+            let color_id = inp.u8()?;
+            self.pixel_fifo_bg.push_back(FIFOPixel { source, color_id });
+        }
+        return Ok(());
+    }
+}
+
 /// Calculate tile number from X, SCY, LY, SCY
 fn get_tile_addr(x: u8, scx: u8, ly: u8, scy: u8) -> u16 {
     let ly = ly as u16;
@@ -616,17 +867,31 @@ pub struct Display {
 }
 
 impl Display {
-    pub fn from(canvas: &Canvas<sdl2::video::Window>) -> Display {
-        let (width, height) = canvas.window().drawable_size();
-
+    pub fn new(width: u8, height: u8) -> Display {
         let size = width as usize * height as usize;
         Display {
             pixels: vec![0; size],
-            width: width as u8,
-            height: height as u8,
+            width,
+            height,
         }
     }
 
+    /// Color codes (0..=3) of the frame, row by row.
+    #[cfg(test)]
+    pub fn pixels(&self) -> &[u8] {
+        return &self.pixels;
+    }
+
+    #[cfg(test)]
+    pub fn width(&self) -> u8 {
+        return self.width;
+    }
+
+    #[cfg(test)]
+    pub fn height(&self) -> u8 {
+        return self.height;
+    }
+
     fn get_pixel(&self, x: u8, y: u8) -> u8 {
         let addr = x as usize + y as usize * self.width as usize;
         return self.pixels[addr];
@@ -657,7 +922,18 @@ impl Display {
     }
 }
 
-fn tile_addr(tile_id: u8, signed_mode: bool) -> u16 {
+impl Snapshot for Display {
+    fn save(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.pixels);
+    }
+
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()> {
+        inp.bytes(&mut self.pixels)?;
+        return Ok(());
+    }
+}
+
+pub fn tile_addr(tile_id: u8, signed_mode: bool) -> u16 {
     let b0 = 0x8000;
     let b1 = 0x8800;
     let b2 = 0x9000;
@@ -691,6 +967,51 @@ fn tile_addr(tile_id: u8, signed_mode: bool) -> u16 {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_oam_report_marks_selected_and_dropped_sprites() {
+        let mut ppu = PPU::new();
+        ppu.ly = 20;
+        ppu.ppu_state = 3;
+        // 11 sprites on line 20, only the first 10 fit
+        for (i, s) in ppu.sprites.iter_mut().enumerate().take(11) {
+            s.x = 8 + i as u8 * 8;
+            s.y = 32;
+            s.flags = 0b1011_0000;
+        }
+        ppu.filtered_sprites = ppu.sprites[..10].to_vec();
+
+        let report = ppu.oam_report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 42);
+        assert_eq!(lines[2], " 0  FE00    8  32   00  OBP1  X     bg  < selected");
+        assert!(lines[11].ends_with("< selected"));
+        assert!(lines[12].ends_with("< dropped"));
+        assert_eq!(lines[13], "11  FE2C    0   0   00  OBP0  -     obj");
+
+        // during vblank the selection is stale
+        ppu.ppu_state = 1;
+        assert!(!ppu.oam_report().contains("<"));
+    }
+
+    #[test]
+    fn test_cpu_writes_keep_the_ppu_bits_of_stat_and_ly() {
+        let mut lcd = Lcd {
+            ly: 42,
+            lyc: 42,
+            mode: 3,
+            ..Default::default()
+        };
+
+        lcd.write_io(registers::LY, 0);
+        lcd.write_io(registers::STAT, 0xFF);
+        assert_eq!(lcd.read_io(registers::LY), 42);
+        assert_eq!(lcd.read_io(registers::STAT), 0xFF);
+
+        lcd.write_io(registers::STAT, 0);
+        lcd.write_io(registers::LYC, 0);
+        assert_eq!(lcd.read_io(registers::STAT), 0x83);
+    }
+
     #[test]
     fn test_signed_mode_128_returns_8800() {
         let got = tile_addr(128, true);
@@ -0,0 +1,180 @@
+//! Execution profiler: machine cycles spent at every PC and within every called routine.
+//! Written out when dropped, as a sorted report or as folded stacks for flamegraph tools.
+use crate::runtime::CallFrame;
+use crate::symbols::{bank_of, Symbols};
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::rc::Rc;
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Format {
+    /// Addresses and call targets sorted by cycles
+    Report,
+    /// One `Caller;Callee cycles` line per call stack, for inferno or flamegraph.pl
+    Folded,
+}
+
+// stack of the code that isn't within any call
+const TOPLEVEL: &str = "(toplevel)";
+
+pub struct Profiler {
+    filename: String,
+    format: Format,
+    pub symbols: Option<Rc<Symbols>>,
+    // machine cycles per (bank, pc)
+    pcs: HashMap<(u8, u16), u64>,
+    // machine cycles per (bank, target) of the calls, including the called routines
+    calls: HashMap<(u8, u16), u64>,
+    // machine cycles per call stack, outermost first
+    stacks: HashMap<Vec<(u8, u16)>, u64>,
+    total: u64,
+}
+
+impl Profiler {
+    pub fn new(filename: &str, format: Format) -> Profiler {
+        return Profiler {
+            filename: filename.to_string(),
+            format,
+            symbols: None,
+            pcs: HashMap::new(),
+            calls: HashMap::new(),
+            stacks: HashMap::new(),
+            total: 0,
+        };
+    }
+
+    /// Accounts the `cycles` of the instruction at `pc`, executed within the `calls`.
+    pub fn record(&mut self, pc: u16, rom_bank: u8, calls: &[CallFrame], cycles: u8) {
+        let cycles = cycles as u64;
+        self.total += cycles;
+        *self.pcs.entry((bank_of(pc, rom_bank), pc)).or_default() += cycles;
+
+        let stack: Vec<(u8, u16)> = calls
+            .iter()
+            .map(|frame| (frame.bank, frame.target))
+            .collect();
+        for (i, target) in stack.iter().enumerate() {
+            // recursive calls are counted once
+            if !stack[..i].contains(target) {
+                *self.calls.entry(*target).or_default() += cycles;
+            }
+        }
+        *self.stacks.entry(stack).or_default() += cycles;
+    }
+
+    fn name(&self, (bank, addr): (u8, u16)) -> String {
+        let label = self.symbols.as_ref().and_then(|s| s.describe(bank, addr));
+        return match label {
+            Some(label) => format!("{:02X}:{:04X} {}", bank, addr, label),
+            None => format!("{:02X}:{:04X}", bank, addr),
+        };
+    }
+
+    fn section(&self, out: &mut String, title: &str, cycles: &HashMap<(u8, u16), u64>) {
+        let mut sorted: Vec<_> = cycles.iter().collect();
+        sorted.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+        writeln!(out, "{}", title).unwrap();
+        for (addr, cycles) in sorted {
+            let share = *cycles as f64 * 100.0 / self.total.max(1) as f64;
+            writeln!(out, "{:>12} {:>6.2}%  {}", cycles, share, self.name(*addr)).unwrap();
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let mut out = format!("{} machine cycles\n\n", self.total);
+        self.section(&mut out, "Cycles per address:", &self.pcs);
+        out.push('\n');
+        self.section(&mut out, "Cycles per call target, callees included:", &self.calls);
+        return out;
+    }
+
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|(stack, cycles)| {
+                // `;` separates the frames, spaces the count
+                let frames: Vec<String> = stack
+                    .iter()
+                    .map(|frame| self.name(*frame).replace([';', ' '], "_"))
+                    .collect();
+                let stack = match frames.is_empty() {
+                    true => TOPLEVEL.to_string(),
+                    false => frames.join(";"),
+                };
+                format!("{} {}", stack, cycles)
+            })
+            .collect();
+        lines.sort();
+        return lines.iter().map(|line| line.clone() + "\n").collect();
+    }
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        let out = match self.format {
+            Format::Report => self.report(),
+            Format::Folded => self.folded(),
+        };
+        match fs::write(&self.filename, out) {
+            Ok(_) => eprintln!("Profile written to `{}´", self.filename),
+            Err(e) => eprintln!("Unable to write profile `{}´: {}", self.filename, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(bank: u8, target: u16) -> CallFrame {
+        return CallFrame {
+            target,
+            bank,
+            ret: 0,
+            sp: 0,
+        };
+    }
+
+    fn profile() -> Profiler {
+        let mut profiler = Profiler::new("/dev/null", Format::Report);
+        profiler.symbols = Some(Rc::new(Symbols::parse("00:0200 Main\n02:4000 Draw\n")));
+        profiler.record(0x0150, 1, &[], 4);
+        profiler.record(0x0200, 1, &[frame(0, 0x200)], 2);
+        profiler.record(0x4000, 2, &[frame(0, 0x200), frame(2, 0x4000)], 3);
+        profiler.record(0x4001, 2, &[frame(0, 0x200), frame(2, 0x4000)], 3);
+        return profiler;
+    }
+
+    #[test]
+    fn test_report_sorts_by_cycles() {
+        let report = profile().report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "12 machine cycles");
+        assert_eq!(lines[3], "           4  33.33%  00:0150");
+        assert_eq!(lines[4], "           3  25.00%  02:4000 Draw");
+        assert_eq!(lines[5], "           3  25.00%  02:4001 Draw+1");
+        assert_eq!(lines[9], "           8  66.67%  00:0200 Main");
+        assert_eq!(lines[10], "           6  50.00%  02:4000 Draw");
+    }
+
+    #[test]
+    fn test_callee_keeps_the_bank_it_was_called_in() {
+        let mut profiler = Profiler::new("/dev/null", Format::Report);
+        // the routine of bank 2 switched to bank 5 and runs code of bank 0
+        profiler.record(0x0100, 5, &[frame(2, 0x4000)], 4);
+        assert_eq!(profiler.calls.get(&(2, 0x4000)), Some(&4));
+        assert_eq!(profiler.calls.get(&(5, 0x4000)), None);
+    }
+
+    #[test]
+    fn test_folded_stacks() {
+        assert_eq!(
+            profile().folded(),
+            "(toplevel) 4\n00:0200_Main 2\n00:0200_Main;02:4000_Draw 6\n"
+        );
+    }
+}
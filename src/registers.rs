@@ -1,5 +1,10 @@
 #![allow(dead_code)]
 
+// joypad & serial
+pub const P1: u16 = 0xFF00;
+pub const SB: u16 = 0xFF01;
+pub const SC: u16 = 0xFF02;
+
 // timer
 pub const DIV: u16 = 0xFF04;
 pub const TIMA: u16 = 0xFF05;
@@ -51,3 +56,145 @@ pub const OBP1: u16 = 0xFF49;
 pub const WY: u16 = 0xFF4A;
 pub const WX: u16 = 0xFF4B;
 
+// unmaps the boot rom
+pub const BOOT: u16 = 0xFF50;
+
+/// I/O register as seen by the cpu: the bits reading as 1, unused or write only, and the bits
+/// it can write.
+pub struct IoRegister {
+    pub addr: u16,
+    pub name: &'static str,
+    pub unused: u8,
+    pub writable: u8,
+}
+
+const fn reg(addr: u16, name: &'static str, unused: u8, writable: u8) -> IoRegister {
+    IoRegister {
+        addr,
+        name,
+        unused,
+        writable,
+    }
+}
+
+/// I/O registers of the DMG, https://gbdev.io/pandocs/Hardware_Reg_List.html
+/// The addresses of 0xFF00-0xFF7F missing from the table read 0xFF and ignore writes.
+pub const IO_REGISTERS: [IoRegister; 44] = [
+    reg(P1, "P1", 0xC0, 0x30),
+    reg(SB, "SB", 0x00, 0xFF),
+    reg(SC, "SC", 0x7E, 0x81),
+    reg(DIV, "DIV", 0x00, 0xFF),
+    reg(TIMA, "TIMA", 0x00, 0xFF),
+    reg(TMA, "TMA", 0x00, 0xFF),
+    reg(TAC, "TAC", 0xF8, 0x07),
+    reg(IF, "IF", 0xE0, 0x1F),
+    reg(NR10, "NR10", 0x80, 0x7F),
+    reg(NR11, "NR11", 0x3F, 0xFF),
+    reg(NR12, "NR12", 0x00, 0xFF),
+    reg(NR13, "NR13", 0xFF, 0xFF),
+    reg(NR14, "NR14", 0xBF, 0xC7),
+    reg(NR21, "NR21", 0x3F, 0xFF),
+    reg(NR22, "NR22", 0x00, 0xFF),
+    reg(NR23, "NR23", 0xFF, 0xFF),
+    reg(NR24, "NR24", 0xBF, 0xC7),
+    reg(NR30, "NR30", 0x7F, 0x80),
+    reg(NR31, "NR31", 0xFF, 0xFF),
+    reg(NR32, "NR32", 0x9F, 0x60),
+    reg(NR33, "NR33", 0xFF, 0xFF),
+    reg(NR34, "NR34", 0xBF, 0xC7),
+    reg(NR41, "NR41", 0xFF, 0x3F),
+    reg(NR42, "NR42", 0x00, 0xFF),
+    reg(NR43, "NR43", 0x00, 0xFF),
+    reg(NR44, "NR44", 0xBF, 0xC0),
+    reg(NR50, "NR50", 0x00, 0xFF),
+    reg(NR51, "NR51", 0x00, 0xFF),
+    // the status of the channels is set by the apu
+    reg(NR52, "NR52", 0x70, 0x80),
+    reg(LCDC, "LCDC", 0x00, 0xFF),
+    // the mode and the LY=LYC flag are set by the ppu
+    reg(STAT, "STAT", 0x80, 0x78),
+    reg(SCY, "SCY", 0x00, 0xFF),
+    reg(SCX, "SCX", 0x00, 0xFF),
+    reg(LY, "LY", 0x00, 0x00),
+    reg(LYC, "LYC", 0x00, 0xFF),
+    reg(DMA, "DMA", 0x00, 0xFF),
+    reg(BGP, "BGP", 0x00, 0xFF),
+    reg(OBP0, "OBP0", 0x00, 0xFF),
+    reg(OBP1, "OBP1", 0x00, 0xFF),
+    reg(WY, "WY", 0x00, 0xFF),
+    reg(WX, "WX", 0x00, 0xFF),
+    reg(BOOT, "BOOT", 0xFE, 0x01),
+    // 0xFF30-0xFF3F, looked up by `io_register`
+    WAVE_RAM,
+    reg(IE, "IE", 0x00, 0xFF),
+];
+
+const WAVE_RAM: IoRegister = reg(0xFF30, "WAVE", 0x00, 0xFF);
+
+// position in IO_REGISTERS of the registers of 0xFF00-0xFF7F
+const IO_INDEX: [Option<u8>; 0x80] = {
+    let mut index = [None; 0x80];
+    let mut i = 0;
+    while i < IO_REGISTERS.len() {
+        let addr = IO_REGISTERS[i].addr;
+        if addr < 0xFF80 {
+            index[(addr - 0xFF00) as usize] = Some(i as u8);
+        }
+        i += 1;
+    }
+    index
+};
+
+/// Register of the table at `addr`, None for the unmapped addresses of the I/O region and the
+/// memory out of it.
+pub fn io_register(addr: u16) -> Option<&'static IoRegister> {
+    return match addr {
+        0xFF30..=0xFF3F => Some(&WAVE_RAM),
+        0xFF00..=0xFF7F => IO_INDEX[(addr - 0xFF00) as usize].map(|i| &IO_REGISTERS[i as usize]),
+        IE => Some(&IO_REGISTERS[IO_REGISTERS.len() - 1]),
+        _ => None,
+    };
+}
+
+/// Bits of `addr` reading as 1 for the cpu.
+pub fn read_mask(addr: u16) -> u8 {
+    return match io_register(addr) {
+        Some(reg) => reg.unused,
+        None if (0xFF00..0xFF80).contains(&addr) => 0xFF,
+        None => 0x00,
+    };
+}
+
+/// Bits of `addr` the cpu can write.
+pub fn write_mask(addr: u16) -> u8 {
+    return match io_register(addr) {
+        Some(reg) => reg.writable,
+        None if (0xFF00..0xFF80).contains(&addr) => 0x00,
+        None => 0xFF,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_registers_are_looked_up_by_address() {
+        assert_eq!(io_register(STAT).unwrap().name, "STAT");
+        assert_eq!(io_register(0xFF3A).unwrap().name, "WAVE");
+        assert_eq!(io_register(IE).unwrap().name, "IE");
+        assert!(io_register(0xFF03).is_none());
+        assert!(io_register(0xC000).is_none());
+    }
+
+    #[test]
+    fn test_masks() {
+        assert_eq!(read_mask(IF), 0xE0);
+        assert_eq!(read_mask(0xFF4C), 0xFF);
+        assert_eq!(read_mask(0xC000), 0x00);
+        assert_eq!(write_mask(LY), 0x00);
+        assert_eq!(write_mask(0xFF4C), 0x00);
+        assert_eq!(write_mask(0xC000), 0xFF);
+    }
+}
+
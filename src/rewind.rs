@@ -0,0 +1,272 @@
+use std::collections::VecDeque;
+
+// a new keyframe is stored every KEYFRAME_EVERY snapshots, the others are stored as deltas
+// against it.
+const KEYFRAME_EVERY: usize = 60;
+
+struct Group {
+    keyframe: Vec<u8>,
+    deltas: Vec<Vec<u8>>,
+}
+
+impl Group {
+    fn size(&self) -> usize {
+        return self.keyframe.len() + self.deltas.iter().map(|d| d.len()).sum::<usize>();
+    }
+}
+
+/// Ring buffer of machine snapshots, taken every `interval` frames.
+/// Snapshots are compressed as the xor against the previous keyframe, run length encoded.
+/// When the memory budget is exceeded, the oldest keyframe and its deltas are dropped.
+pub struct Rewind {
+    interval: u32,
+    budget: usize,
+    frame: u32,
+    used: usize,
+    groups: VecDeque<Group>,
+}
+
+impl Rewind {
+    pub fn new(interval: u32, budget: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            frame: 0,
+            used: 0,
+            groups: VecDeque::new(),
+        }
+    }
+
+    /// To be called once per emulated frame, `snapshot` is invoked only when a snapshot is due.
+    pub fn on_frame(&mut self, snapshot: impl FnOnce() -> Vec<u8>) {
+        self.frame += 1;
+        if self.frame >= self.interval {
+            self.frame = 0;
+            self.push(snapshot());
+        }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        let new_group = match self.groups.back() {
+            Some(group) => group.deltas.len() + 1 >= KEYFRAME_EVERY,
+            None => true,
+        };
+
+        if new_group {
+            let keyframe = encode(&[], &state);
+            self.used += keyframe.len();
+            self.groups.push_back(Group {
+                keyframe,
+                deltas: vec![],
+            });
+        } else {
+            let group = self.groups.back_mut().unwrap();
+            let base = decode(&[], &group.keyframe);
+            let delta = encode(&base, &state);
+            self.used += delta.len();
+            group.deltas.push(delta);
+        }
+
+        // always keep at least the group that was just written.
+        while self.used > self.budget && self.groups.len() > 1 {
+            let group = self.groups.pop_front().unwrap();
+            self.used -= group.size();
+        }
+    }
+
+    /// Removes and returns the most recent snapshot.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let group = self.groups.back_mut()?;
+        let base = decode(&[], &group.keyframe);
+
+        let state = if let Some(delta) = group.deltas.pop() {
+            self.used -= delta.len();
+            decode(&base, &delta)
+        } else {
+            self.used -= group.keyframe.len();
+            self.groups.pop_back();
+            base
+        };
+
+        self.frame = 0;
+        return Some(state);
+    }
+
+    /// To be called once per frame while rewinding, a snapshot is popped every `interval` frames
+    /// so that the game plays backwards at its own pace.
+    pub fn on_rewind_frame(&mut self) -> Option<Vec<u8>> {
+        if self.frame > 0 {
+            self.frame -= 1;
+            return None;
+        }
+        let state = self.pop()?;
+        self.frame = self.interval - 1;
+        return Some(state);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.groups.iter().map(|g| g.deltas.len() + 1).sum();
+    }
+
+    pub fn used(&self) -> usize {
+        return self.used;
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut val: usize) {
+    while val >= 0x80 {
+        out.push((val as u8 & 0x7F) | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn get_varint(inp: &[u8], pos: &mut usize) -> usize {
+    let mut val = 0;
+    let mut shift = 0;
+    loop {
+        let byte = inp[*pos];
+        *pos += 1;
+        val |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return val;
+        }
+        shift += 7;
+    }
+}
+
+/// Encodes `data` as the xor against `base` (missing bytes of base count as 0),
+/// stored as a sequence of (zero run length, literal length, literals).
+pub fn encode(base: &[u8], data: &[u8]) -> Vec<u8> {
+    let xored: Vec<u8> = data
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ base.get(i).unwrap_or(&0))
+        .collect();
+
+    let mut out = vec![];
+    put_varint(&mut out, xored.len());
+
+    let mut i = 0;
+    while i < xored.len() {
+        let zeros = xored[i..].iter().take_while(|b| **b == 0).count();
+        i += zeros;
+
+        // literals run until the next pair of zeros, a single zero is cheaper as a literal.
+        let mut end = i;
+        while end < xored.len() && !(xored[end] == 0 && xored.get(end + 1) == Some(&0)) {
+            end += 1;
+        }
+
+        put_varint(&mut out, zeros);
+        put_varint(&mut out, end - i);
+        out.extend_from_slice(&xored[i..end]);
+        i = end;
+    }
+    return out;
+}
+
+pub fn decode(base: &[u8], enc: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = get_varint(enc, &mut pos);
+    let mut out: Vec<u8> = (0..len).map(|i| *base.get(i).unwrap_or(&0)).collect();
+
+    let mut i = 0;
+    while pos < enc.len() {
+        i += get_varint(enc, &mut pos);
+        let literals = get_varint(enc, &mut pos);
+        for _ in 0..literals {
+            out[i] ^= enc[pos];
+            pos += 1;
+            i += 1;
+        }
+    }
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode_roundtrip() {
+        let base: Vec<u8> = (0..1000).map(|i| (i % 7) as u8).collect();
+        let mut data = base.clone();
+        data[3] = 0xFF;
+        data[500] = 0x01;
+        data[501] = 0x02;
+        data[999] = 0xAA;
+
+        let enc = encode(&base, &data);
+        assert!(enc.len() < 32);
+        assert_eq!(decode(&base, &enc), data);
+    }
+
+    #[test]
+    fn test_encode_without_base() {
+        let data = vec![0, 0, 0, 1, 0, 2, 3, 0, 0];
+        assert_eq!(decode(&[], &encode(&[], &data)), data);
+    }
+
+    #[test]
+    fn test_pop_returns_latest_snapshot_first() {
+        let mut rewind = Rewind::new(1, 1 << 20);
+        for i in 0..100u8 {
+            rewind.on_frame(|| vec![i; 64]);
+        }
+        assert_eq!(rewind.len(), 100);
+
+        for i in (0..100u8).rev() {
+            assert_eq!(rewind.pop(), Some(vec![i; 64]));
+        }
+        assert_eq!(rewind.pop(), None);
+        assert_eq!(rewind.used(), 0);
+    }
+
+    #[test]
+    fn test_snapshot_taken_every_interval() {
+        let mut rewind = Rewind::new(4, 1 << 20);
+        for i in 0..16u8 {
+            rewind.on_frame(|| vec![i]);
+        }
+        assert_eq!(rewind.len(), 4);
+        assert_eq!(rewind.pop(), Some(vec![15]));
+    }
+
+    #[test]
+    fn test_rewind_pops_every_interval() {
+        let mut rewind = Rewind::new(3, 1 << 20);
+        for i in 0..9u8 {
+            rewind.on_frame(|| vec![i]);
+        }
+        let popped: Vec<_> = (0..7).map(|_| rewind.on_rewind_frame()).collect();
+        assert_eq!(
+            popped,
+            vec![Some(vec![8]), None, None, Some(vec![5]), None, None, Some(vec![2])]
+        );
+    }
+
+    #[test]
+    fn test_drops_oldest_keyframe_over_budget() {
+        // every keyframe, and every delta, encodes to the same size
+        let state = |i: usize| {
+            let mut state = vec![0x55; 1024];
+            state[0] = (i % 250) as u8 + 1;
+            state
+        };
+        let mut probe = Rewind::new(1, usize::MAX);
+        for i in 0..KEYFRAME_EVERY {
+            probe.push(state(i));
+        }
+        let group = probe.used();
+
+        // room for two groups and a half
+        let mut rewind = Rewind::new(1, group * 2 + group / 2);
+        for i in 0..(KEYFRAME_EVERY * 3) {
+            rewind.push(state(i));
+        }
+        assert_eq!(rewind.len(), KEYFRAME_EVERY * 2);
+        assert_eq!(rewind.used(), group * 2);
+        assert_eq!(decode(&[], &rewind.groups[0].keyframe), state(KEYFRAME_EVERY));
+    }
+}
@@ -1,25 +1,48 @@
-use crate::{byteop::*, registers};
+use crate::{byteop::*, disasm, registers};
 use crate::mbc::Rom;
 use crate::memory::{HWInput, Memory, MMU};
+use crate::ppu::{Display, PPU};
 use crate::registers::IF;
-use crate::timer::Timer;
+use crate::scheduler::{Event, Scheduler};
+use crate::state::{put_u16, Reader, Snapshot};
+use crate::profiler::Profiler;
+use crate::trace::Trace;
 use std::fmt;
-
-struct CpuRegisters {
-    ra: u8,
-    rf: u8,
-    rb: u8,
-    rc: u8,
-    rd: u8,
-    re: u8,
-    rh: u8,
-    rl: u8,
-    sp: u16,
-    pc: u16,
+use std::io;
+
+pub struct CpuRegisters {
+    pub ra: u8,
+    pub rf: u8,
+    pub rb: u8,
+    pub rc: u8,
+    pub rd: u8,
+    pub re: u8,
+    pub rh: u8,
+    pub rl: u8,
+    pub sp: u16,
+    pub pc: u16,
 
     ime: bool,
-    debug: bool,
+    // EI enables the interrupts after the following instruction
+    ei_delay: bool,
     halt: bool,
+    // HALT with IME off and an interrupt pending: the next opcode is fetched twice
+    halt_bug: bool,
+    // an illegal opcode hangs the cpu until reset, interrupts included
+    locked: bool,
+}
+
+/// Illegal opcode executed by the cpu, which locked up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lockup {
+    pub pc: u16,
+    pub opcode: u8,
+}
+
+impl fmt::Display for Lockup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CPU locked up on illegal opcode {} at {}", b64(self.opcode), b64(self.pc))
+    }
 }
 
 enum CFlag {
@@ -49,7 +72,7 @@ impl fmt::Debug for CpuRegisters {
 }
 
 impl CpuRegisters {
-    fn new() -> CpuRegisters {
+    pub fn new() -> CpuRegisters {
         CpuRegisters {
             ra: 0,
             rf: 0,
@@ -62,8 +85,10 @@ impl CpuRegisters {
             pc: 0,
             sp: 0,
             ime: false,
-            debug: false,
+            ei_delay: false,
             halt: false,
+            halt_bug: false,
+            locked: false,
         }
     }
 
@@ -80,10 +105,22 @@ impl CpuRegisters {
             pc: 0x0100,
             sp: 0xFFFE,
             ime: false,
-            debug: true,
+            ei_delay: false,
             halt: false,
+            halt_bug: false,
+            locked: false,
         }
     }
+    pub fn ime(&self) -> bool {
+        return self.ime;
+    }
+    pub fn halted(&self) -> bool {
+        return self.halt;
+    }
+    pub fn locked(&self) -> bool {
+        return self.locked;
+    }
+
     fn set_flag(&mut self, flag: CFlag, val: u8) {
         self.rf = set_bit(self.rf, flag as u8, val == 1);
     }
@@ -104,6 +141,64 @@ impl CpuRegisters {
         return join_u8(self.ra, self.rf);
     }
 
+    /// Register pair `idx` of the BC, DE, HL, SP encoding.
+    fn r16(&self, idx: u8) -> u16 {
+        return match idx {
+            0 => self.bc(),
+            1 => self.de(),
+            2 => self.hl(),
+            _ => self.sp,
+        };
+    }
+    fn set_r16(&mut self, idx: u8, val: u16) {
+        match idx {
+            0 => self.set_bc(val),
+            1 => self.set_de(val),
+            2 => self.set_hl(val),
+            _ => self.sp = val,
+        }
+    }
+
+    /// Register pair `idx` of PUSH and POP: BC, DE, HL, AF.
+    fn r16stk(&self, idx: u8) -> u16 {
+        return match idx {
+            3 => self.af(),
+            _ => self.r16(idx),
+        };
+    }
+    fn set_r16stk(&mut self, idx: u8, val: u16) {
+        match idx {
+            3 => self.set_af(val),
+            _ => self.set_r16(idx, val),
+        }
+    }
+
+    /// Condition `idx` of the jumps, calls and returns: NZ, Z, NC, C.
+    fn condition(&self, idx: u8) -> bool {
+        return match idx {
+            0 => self.get_flag(CFlag::Z) == 0,
+            1 => self.get_flag(CFlag::Z) == 1,
+            2 => self.get_flag(CFlag::CY) == 0,
+            _ => self.get_flag(CFlag::CY) == 1,
+        };
+    }
+
+    /// Operation `idx` on A: ADD, ADC, SUB, SBC, AND, XOR, OR, CP.
+    fn alu(&mut self, idx: u8, val: u8) {
+        match idx {
+            0 => self.add_ra(val),
+            1 => self.adc_ra(val),
+            2 => self.ra = self.sub_ra(val),
+            3 => self.ra = self.sbc_ra(val),
+            4 => self.and_ra(val),
+            5 => self.xor_ra(val),
+            6 => self.or_ra(val),
+            _ => {
+                self.sub_ra(val);
+            }
+        }
+    }
+
     fn daa(&mut self) {
         // blindly implemented & tested following:
         // https://ehaskins.com/2018-01-30%20Z80%20DAA/
@@ -245,7 +340,7 @@ impl CpuRegisters {
     }
 
     // cpu arithmetic instructions
-    fn add_ra(&mut self, val: u8) -> u8 {
+    fn add_ra(&mut self, val: u8) {
         // https://gist.github.com/meganesu/9e228b6b587decc783aa9be34ae27841
         let half_carry = (((self.ra & 0xF) + (val & 0xF)) & 0x10) == 0x10;
 
@@ -256,7 +351,6 @@ impl CpuRegisters {
         self.set_flag(CFlag::S, 0);
         self.set_flag(CFlag::CY, get_bit(res, 8));
         self.set_flag(CFlag::H, half_carry as u8);
-        return 1;
     }
 
     fn sub_ra(&mut self, val: u8) -> u8 {
@@ -289,7 +383,7 @@ impl CpuRegisters {
     }
 
     // cpu arithmetic instructions
-    fn adc_ra(&mut self, val: u8) -> u8 {
+    fn adc_ra(&mut self, val: u8) {
         let rem = (self.ra & 0b1111) + (val & 0b1111) + self.get_flag(CFlag::CY);
         let res = self.ra as u16 + val as u16 + self.get_flag(CFlag::CY) as u16;
 
@@ -298,30 +392,22 @@ impl CpuRegisters {
         self.set_flag(CFlag::S, 0);
         self.set_flag(CFlag::CY, get_bit(res, 8));
         self.set_flag(CFlag::H, (rem & 0b10000) >> 4);
-        return 1;
     }
 
-    fn or_ra(&mut self, val: u8) -> u8 {
+    fn or_ra(&mut self, val: u8) {
         self.ra |= val;
         self.set_flag(CFlag::Z, (self.ra == 0) as u8);
         self.set_flag(CFlag::S, 0);
         self.set_flag(CFlag::H, 0);
         self.set_flag(CFlag::CY, 0);
-        return 1;
-    }
-
-    fn cp_ra(&mut self, val: u8) -> u8 {
-        self.sub_ra(val);
-        1
     }
 
-    fn xor_ra(&mut self, val: u8) -> u8 {
+    fn xor_ra(&mut self, val: u8) {
         self.ra ^= val;
         self.set_flag(CFlag::Z, (self.ra == 0) as u8);
         self.set_flag(CFlag::S, 0);
         self.set_flag(CFlag::H, 0);
         self.set_flag(CFlag::CY, 0);
-        1
     }
     fn rr(&mut self, val: u8) -> u8 {
         // rotate right through CY
@@ -344,18 +430,12 @@ impl CpuRegisters {
         self.set_flag(CFlag::H, 0);
         return res;
     }
-    fn and_ra(&mut self, val: u8) -> u8 {
+    fn and_ra(&mut self, val: u8) {
         self.ra &= val;
         self.set_flag(CFlag::Z, (self.ra == 0) as u8);
         self.set_flag(CFlag::S, 0);
         self.set_flag(CFlag::H, 1);
         self.set_flag(CFlag::CY, 0);
-        1
-    }
-
-    fn ld_ra(&mut self, val: u8) -> u8 {
-        self.ra = val;
-        1
     }
 
     fn swap(&mut self, val: u8) -> u8 {
@@ -382,11 +462,72 @@ impl CpuRegisters {
     }
 }
 
+impl Snapshot for CpuRegisters {
+    fn save(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[
+            self.ra, self.rf, self.rb, self.rc, self.rd, self.re, self.rh, self.rl,
+        ]);
+        put_u16(out, self.sp);
+        put_u16(out, self.pc);
+        out.push(self.ime as u8);
+        out.push(self.halt as u8);
+        out.push(self.ei_delay as u8);
+        out.push(self.halt_bug as u8);
+        out.push(self.locked as u8);
+    }
+
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()> {
+        self.ra = inp.u8()?;
+        self.rf = inp.u8()?;
+        self.rb = inp.u8()?;
+        self.rc = inp.u8()?;
+        self.rd = inp.u8()?;
+        self.re = inp.u8()?;
+        self.rh = inp.u8()?;
+        self.rl = inp.u8()?;
+        self.sp = inp.u16()?;
+        self.pc = inp.u16()?;
+        self.ime = inp.bool()?;
+        self.halt = inp.bool()?;
+        self.ei_delay = inp.bool()?;
+        self.halt_bug = inp.bool()?;
+        self.locked = inp.bool()?;
+        return Ok(());
+    }
+}
+
+// deepest call stack tracked, the oldest frames are dropped past it.
+const MAX_CALLS: usize = 256;
+
+/// Routine called by CALL, RST or an interrupt, tracked for the debugger.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CallFrame {
+    pub target: u16,
+    // rom bank of the target when it was called
+    pub bank: u8,
+    // return address
+    pub ret: u16,
+    // stack pointer after the return address was pushed
+    pub sp: u16,
+}
+
 pub struct Runtime<'a> {
     pub memory: MMU<'a>,
     cpu: CpuRegisters,
-
-    pub timer: Timer,
+    pub calls: Vec<CallFrame>,
+
+    pub ppu: PPU,
+    pub display: Display,
+    scheduler: Scheduler,
+    pub trace: Option<Trace>,
+    pub profiler: Option<Profiler>,
+
+    // machine cycles elapsed in the current instruction
+    elapsed: u8,
+    // a frame has been completed since the last `take_frame`
+    frame: bool,
+    // illegal opcode executed since the last `take_lockup`
+    lockup: Option<Lockup>,
 }
 
 impl Memory for Runtime<'_> {
@@ -395,10 +536,43 @@ impl Memory for Runtime<'_> {
     }
 
     fn set(&mut self, addr: u16, val: u8) -> () {
-        self.memory.set(addr, val);
+        match addr {
+            registers::DIV..=registers::TAC => {
+                self.sync_timer();
+                self.memory.set(addr, val);
+                self.sync_timer();
+            }
+            registers::LCDC..=registers::LYC => {
+                self.memory.set(addr, val);
+                self.sync_ppu();
+            }
+            registers::SB..=registers::SC => {
+                self.sync_serial();
+                self.memory.set(addr, val);
+                self.sync_serial();
+            }
+            _ => self.memory.set(addr, val),
+        }
     }
-    fn hwset(&mut self, addr: u16, val: u8) -> () {
-        self.memory.set(addr, val);
+}
+
+impl Snapshot for Runtime<'_> {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.cpu.save(out);
+        self.memory.save(out);
+        self.scheduler.save(out);
+        self.ppu.save(out);
+        self.display.save(out);
+    }
+
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()> {
+        self.cpu.load(inp)?;
+        self.memory.load(inp)?;
+        self.scheduler.load(inp)?;
+        self.ppu.load(inp)?;
+        self.display.load(inp)?;
+        self.calls.clear();
+        return Ok(());
     }
 }
 
@@ -408,7 +582,15 @@ impl Runtime<'_> {
         let rt = Runtime {
             cpu: CpuRegisters::new(),
             memory: MMU::new(&bootstrap, rom),
-            timer: Timer::new(),
+            calls: vec![],
+            ppu: PPU::new(),
+            display: Display::new(160, 144),
+            scheduler: Scheduler::new(),
+            trace: None,
+            profiler: None,
+            elapsed: 0,
+            frame: false,
+            lockup: None,
         };
 
         // https://b13rg.github.io/Gameboy-MBC-Analysis/#cart-1
@@ -419,7 +601,15 @@ impl Runtime<'_> {
         let mut rt = Runtime {
             cpu: CpuRegisters::atboot(),
             memory: MMU::new(&bootstrap, rom),
-            timer: Timer::new(),
+            calls: vec![],
+            ppu: PPU::new(),
+            display: Display::new(160, 144),
+            scheduler: Scheduler::new(),
+            trace: None,
+            profiler: None,
+            elapsed: 0,
+            frame: false,
+            lockup: None,
         };
 
         rt.memory.set(0xFF50, 1);
@@ -428,16 +618,148 @@ impl Runtime<'_> {
         return rt;
     }
 
-    pub fn tick_timer(&mut self, ticks: u8) {
-        self.timer.tick(&mut self.memory, ticks);
-        self.memory.tick(ticks / 4);
+    /// Advances the system by one machine cycle, the dma and the components with an event due.
+    fn cycle(&mut self) {
+        // the hardware doesn't trigger watchpoints
+        let watching = self.memory.watching;
+        self.memory.watching = false;
+
+        self.scheduler.now += 4;
+        self.memory.tick(1);
+        while let Some(event) = self.scheduler.pop() {
+            match event {
+                Event::Ppu => self.sync_ppu(),
+                // the timer counts the ticks of the DIV-APU for the apu
+                Event::Timer | Event::Apu => self.sync_timer(),
+                Event::Serial => self.sync_serial(),
+            }
+        }
+
+        self.memory.watching = watching;
+        self.elapsed += 1;
+    }
+
+    /// Catches the ppu up with the current cycle.
+    fn sync_ppu(&mut self) {
+        let watching = std::mem::replace(&mut self.memory.watching, false);
+        let dots = self.scheduler.sync(Event::Ppu);
+        self.frame |= self.ppu.update(&mut self.memory, dots, &mut self.display);
+        self.scheduler.schedule(Event::Ppu, Some(self.ppu.next_event()));
+        self.memory.watching = watching;
+    }
+
+    /// Catches the timer up with the current cycle.
+    fn sync_timer(&mut self) {
+        let dots = self.scheduler.sync(Event::Timer);
+        if self.memory.timer.tick(dots) {
+            self.memory.request_interrupt(2);
+        }
+        self.scheduler.schedule(Event::Timer, self.memory.timer.next_event());
+        self.scheduler.schedule(Event::Apu, Some(self.memory.timer.next_div_apu()));
+    }
+
+    /// Catches the serial transfer up with the current cycle.
+    fn sync_serial(&mut self) {
+        let dots = self.scheduler.sync(Event::Serial);
+        if self.memory.serial.tick(dots) {
+            self.memory.request_interrupt(3);
+        }
+        self.scheduler.schedule(Event::Serial, self.memory.serial.next_event());
+    }
+
+    /// Returns whether the apu has to step: the DIV-APU ticked or a sound register was written
+    /// during the last instruction.
+    pub fn take_apu(&mut self) -> bool {
+        return self.memory.sound.take_written() || self.memory.timer.delta_div > 0;
+    }
+
+    /// Catches the timer up with the current cycle, for the debuggers reading its registers.
+    pub fn sync(&mut self) {
+        self.sync_timer();
+    }
+
+    /// Memory read of the cpu, the rest of the system advances before the byte is read. An OAM
+    /// DMA leaves only the registers and HRAM to the cpu.
+    fn read(&mut self, addr: u16) -> u8 {
+        self.cycle();
+        if self.memory.dma_blocks(addr) {
+            return 0xFF;
+        }
+        if (registers::DIV..=registers::TAC).contains(&addr) {
+            self.sync_timer();
+        }
+        if (registers::SB..=registers::SC).contains(&addr) {
+            self.sync_serial();
+        }
+        return self.get(addr) | registers::read_mask(addr);
+    }
+
+    /// Memory write of the cpu, the rest of the system advances before the byte is written.
+    /// Read only bits of the registers keep their value.
+    fn write(&mut self, addr: u16, val: u8) {
+        self.cycle();
+        let writable = registers::write_mask(addr);
+        if writable == 0 || self.memory.dma_blocks(addr) {
+            return;
+        }
+        if writable != 0xFF {
+            // not an access of the program, no watchpoint
+            let watching = std::mem::replace(&mut self.memory.watching, false);
+            let old = self.get(addr);
+            self.memory.watching = watching;
+            self.set(addr, (val & writable) | (old & !writable));
+        } else {
+            self.set(addr, val);
+        }
+    }
+
+    /// True once after each frame completed by the ppu (VBLANK started).
+    pub fn take_frame(&mut self) -> bool {
+        return std::mem::take(&mut self.frame);
     }
+
+    /// The illegal opcode which locked the cpu up, once.
+    pub fn take_lockup(&mut self) -> Option<Lockup> {
+        return self.lockup.take();
+    }
+
     fn next_opcode(&mut self) -> u8 {
-        let opcode = self.get(self.cpu.pc);
-        self.cpu.pc += 1;
+        let opcode = self.read(self.cpu.pc);
+        if self.cpu.halt_bug {
+            self.cpu.halt_bug = false;
+        } else {
+            self.cpu.pc += 1;
+        }
         return opcode;
     }
 
+    fn pending_interrupts(&self) -> u8 {
+        return self.get(registers::IE) & self.get(registers::IF) & 0x1F;
+    }
+
+    /// Jumps to the handler of the highest priority interrupt: two idle cycles (the second one
+    /// taken by the push), PC pushed on the stack, then one more cycle to set PC. 5 machine cycles
+    /// in total.
+    fn dispatch(&mut self, interrupts: u8) -> u8 {
+        self.cpu.ime = false;
+        self.cycle();
+
+        // priority goes from lsb to msb
+        let bit = interrupts.trailing_zeros() as u8;
+        self.call(0x40 + bit as u16 * 8);
+        let interrupt_flag = self.get(IF);
+        self.set(IF, set_bit(interrupt_flag, bit, false));
+        return 5;
+    }
+
+    pub fn cpu(&self) -> &CpuRegisters {
+        return &self.cpu;
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CpuRegisters {
+        return &mut self.cpu;
+    }
+
     pub fn press_btn(&mut self, btn: HWInput) {
         self.memory.press(btn, true);
     }
@@ -445,168 +767,214 @@ impl Runtime<'_> {
         self.memory.press(btn, false);
     }
 
+    /// Executes one instruction, or dispatches an interrupt, returns the machine cycles taken.
+    /// The rest of the system advances along, on each memory access of the cpu.
     pub fn tick(&mut self) -> u8 {
-        let interrupts = self.get(registers::IE) & self.get(registers::IF);
+        let (pc, rom_bank, depth) = (self.cpu.pc, self.memory.rom_bank(), self.calls.len());
+        self.elapsed = 0;
+        self.memory.timer.delta_div = 0;
+
+        // only the accesses of the cpu trigger watchpoints
+        self.memory.watching = true;
+        let cycles = self.execute();
+        self.memory.watching = false;
+
+        // the memory accesses of an instruction fit in its timing
+        debug_assert!(self.elapsed <= cycles, "{} accesses in {} cycles", self.elapsed, cycles);
+        // the remaining internal cycles, without memory accesses, end the instruction. The ones
+        // before the stack accesses of PUSH, CALL, RST and RET cc are taken in place.
+        while self.elapsed < cycles {
+            self.cycle();
+        }
+        let cycles = self.elapsed;
+
+        if let Some(trace) = &mut self.trace {
+            trace.cycles += cycles as u64;
+        }
+        if let Some(profiler) = &mut self.profiler {
+            // the cycles of a CALL belong to the caller
+            let calls = &self.calls[..depth.min(self.calls.len())];
+            profiler.record(pc, rom_bank, calls, cycles);
+        }
+        return cycles;
+    }
+
+    fn execute(&mut self) -> u8 {
+        if self.cpu.locked {
+            return 1;
+        }
+        let interrupts = self.pending_interrupts();
         if self.cpu.halt {
             if interrupts == 0 {
-                // println!("wait for interrupt! {:b}", self.get(registers::IE));
                 return 1;
-            } else {
-                self.cpu.halt = false;
             }
+            // a pending interrupt wakes the cpu up, even with IME off, waking up takes a cycle
+            self.cpu.halt = false;
+            return 1;
         }
 
         if self.cpu.ime && interrupts != 0 {
-            self.cpu.ime = false;
-            self.stack_push_u16(self.cpu.pc);
-
-            let interrupt_flag = self.get(0xFF0F);
-            // priority goes from lsb to msb
-            if get_bit(interrupts, 0) == 1 {
-                self.cpu.pc = 0x40;
-                self.set(IF, set_bit(interrupt_flag, 0, false));
-            } else if get_bit(interrupts, 1) == 1 {
-                self.cpu.pc = 0x48;
-                self.set(IF, set_bit(interrupt_flag, 1, false));
-            } else if get_bit(interrupts, 2) == 1 {
-                self.cpu.pc = 0x50;
-                self.set(IF, set_bit(interrupt_flag, 2, false));
-            } else if get_bit(interrupts, 3) == 1 {
-                self.cpu.pc = 0x58;
-                self.set(IF, set_bit(interrupt_flag, 3, false));
-            } else if get_bit(interrupts, 4) == 1 {
-                self.cpu.pc = 0x60;
-                self.set(IF, set_bit(interrupt_flag, 4, false));
-            }
+            return self.dispatch(interrupts);
+        }
+        if self.cpu.ei_delay {
+            self.cpu.ei_delay = false;
+            self.cpu.ime = true;
         }
 
-        if self.cpu.debug {
-            println!(
-                "{:?} PCMEM:{},{},{},{}",
-                self.cpu,
-                b64(self.get(self.cpu.pc + 0)),
-                b64(self.get(self.cpu.pc + 1)),
-                b64(self.get(self.cpu.pc + 2)),
-                b64(self.get(self.cpu.pc + 3)),
-            );
+        if let Some(trace) = &mut self.trace {
+            // reads of the trace don't trigger watchpoints
+            self.memory.watching = false;
+            trace.log(&self.cpu, &self.memory);
+            self.memory.watching = true;
         }
         let opcode = self.next_opcode();
+        if opcode == 0xCB {
+            let opcode = self.next_opcode();
+            self.eval_cb(opcode);
+            return disasm::cb_cycles(opcode);
+        }
 
-        // https://meganesu.github.io/generate-gb-opcodes/
-        return match opcode {
-            0x00 => {
-                // NOP
-                1
-            }
-            0x01 => {
-                // LD BC, u16
-                self.cpu.rc = self.next_opcode();
-                self.cpu.rb = self.next_opcode();
-                3
+        // the timings come from the table of the disassembler
+        let op = &disasm::OPS[opcode as usize];
+        return match self.eval(opcode) {
+            true => op.cycles_taken,
+            false => op.cycles,
+        };
+    }
+
+    /// Operand `idx` of the B, C, D, E, H, L, (HL), A encoding, (HL) is a memory access.
+    fn get_r8(&mut self, idx: u8) -> u8 {
+        return match idx {
+            0 => self.cpu.rb,
+            1 => self.cpu.rc,
+            2 => self.cpu.rd,
+            3 => self.cpu.re,
+            4 => self.cpu.rh,
+            5 => self.cpu.rl,
+            6 => self.read(self.cpu.hl()),
+            _ => self.cpu.ra,
+        };
+    }
+
+    fn set_r8(&mut self, idx: u8, val: u8) {
+        match idx {
+            0 => self.cpu.rb = val,
+            1 => self.cpu.rc = val,
+            2 => self.cpu.rd = val,
+            3 => self.cpu.re = val,
+            4 => self.cpu.rh = val,
+            5 => self.cpu.rl = val,
+            6 => self.write(self.cpu.hl(), val),
+            _ => self.cpu.ra = val,
+        }
+    }
+
+    fn next_u16(&mut self) -> u16 {
+        let l = self.next_opcode();
+        let h = self.next_opcode();
+        return join_u8(h, l);
+    }
+
+    /// Address of `LD (rr),A` and `LD A,(rr)`: BC, DE, HL incremented after, HL decremented after.
+    fn indirect(&mut self, idx: u8) -> u16 {
+        let hl = self.cpu.hl();
+        return match idx {
+            0 => self.cpu.bc(),
+            1 => self.cpu.de(),
+            2 => {
+                self.cpu.set_hl(hl.wrapping_add(1));
+                hl
             }
-            0x02 => {
-                self.set(self.cpu.bc(), self.cpu.ra);
-                2
+            _ => {
+                self.cpu.set_hl(hl.wrapping_sub(1));
+                hl
             }
-            0x03 => {
-                self.cpu.set_bc(self.cpu.bc().wrapping_add(1));
-                2
+        };
+    }
+
+    /// Executes `opcode`, decoded from its `xxyyyzzz` bit fields:
+    /// https://gbdev.io/gb-opcodes/optables/octal
+    /// Returns true when a conditional jump, call or return is taken.
+    fn eval(&mut self, opcode: u8) -> bool {
+        let (x, y, z) = (opcode >> 6, (opcode >> 3) & 7, opcode & 7);
+        // `y` as a register pair and a variant
+        let (p, q) = (y >> 1, y & 1);
+
+        match (x, y, z) {
+            (0, 0, 0) => {
+                // NOP
             }
-            0x04 => {
-                self.cpu.rb = self.cpu.inc(self.cpu.rb);
-                1
+            (0, 1, 0) => {
+                // LD (a16),SP
+                let addr = self.next_u16();
+                let (h, l) = split_u16(self.cpu.sp);
+                self.write(addr, l);
+                self.write(addr.wrapping_add(1), h);
             }
-            0x05 => {
-                // DEC B
-                self.cpu.rb = self.cpu.dec(self.cpu.rb);
-                1
+            (0, 2, 0) => {
+                // STOP is 2 bytes long, the second one is skipped within its single cycle
+                self.cpu.pc = self.cpu.pc.wrapping_add(1);
+                println!("STOP instruction, enter CPU low power mode");
             }
-            0x06 => {
-                self.cpu.rb = self.next_opcode();
-                2
+            (0, 3, 0) => {
+                let raddr = self.next_opcode() as i8;
+                self.cpu.jr(raddr);
             }
-            0x07 => {
-                self.cpu.ra = self.cpu.rlc(self.cpu.ra);
-                self.cpu.set_flag(CFlag::Z, 0);
-                1
+            (0, _, 0) => {
+                // JR cc,r8
+                let raddr = self.next_opcode() as i8;
+                if self.cpu.condition(y - 4) {
+                    self.cpu.jr(raddr);
+                    return true;
+                }
             }
-            0x08 => {
-                let (h, l) = split_u16(self.cpu.sp);
-                let al = self.next_opcode();
-                let ah = self.next_opcode();
-                let addr = join_u8(ah, al);
-                self.set(addr, l);
-                self.set(addr + 1, h);
-                5
+            (0, _, 1) if q == 0 => {
+                // LD rr,d16
+                let val = self.next_u16();
+                self.cpu.set_r16(p, val);
             }
-            0x09 => {
-                let (cy, h, res) = add_u16(self.cpu.hl(), self.cpu.bc());
-
+            (0, _, 1) => {
+                // ADD HL,rr
+                let (cy, h, res) = add_u16(self.cpu.hl(), self.cpu.r16(p));
                 self.cpu.set_hl(res);
-
-                // self.cpu.set_flag(CFlag::Z, (res == 0) as u8);
                 self.cpu.set_flag(CFlag::S, 0);
                 self.cpu.set_flag(CFlag::CY, cy);
                 self.cpu.set_flag(CFlag::H, h);
-                2
             }
-            0x0A => {
-                self.cpu.ra = self.get(self.cpu.bc());
-                2
+            (0, _, 2) if q == 0 => {
+                let addr = self.indirect(p);
+                self.write(addr, self.cpu.ra);
             }
-            0x0B => {
-                self.cpu.set_bc(self.cpu.bc().wrapping_sub(1));
-                2
+            (0, _, 2) => {
+                let addr = self.indirect(p);
+                self.cpu.ra = self.read(addr);
             }
-            0x0C => {
-                self.cpu.rc = self.cpu.inc(self.cpu.rc);
-                1
+            (0, _, 3) if q == 0 => self.cpu.set_r16(p, self.cpu.r16(p).wrapping_add(1)),
+            (0, _, 3) => self.cpu.set_r16(p, self.cpu.r16(p).wrapping_sub(1)),
+            (0, _, 4) => {
+                let val = self.get_r8(y);
+                let val = self.cpu.inc(val);
+                self.set_r8(y, val);
             }
-            0x0D => {
-                self.cpu.rc = self.cpu.dec(self.cpu.rc);
-                1
+            (0, _, 5) => {
+                let val = self.get_r8(y);
+                let val = self.cpu.dec(val);
+                self.set_r8(y, val);
             }
-            0x0E => {
-                self.cpu.rc = self.next_opcode();
-                2
+            (0, _, 6) => {
+                // LD r,d8
+                let val = self.next_opcode();
+                self.set_r8(y, val);
             }
-            0x0F => {
-                self.cpu.ra = self.cpu.rrc(self.cpu.ra);
+            (0, 0, 7) => {
+                self.cpu.ra = self.cpu.rlc(self.cpu.ra);
                 self.cpu.set_flag(CFlag::Z, 0);
-                1
-            }
-            0x10 => {
-                println!("STOP instruction, enter CPU low power mode");
-                1 // panic!("STOP!");
-            }
-            0x11 => {
-                let l = self.next_opcode();
-                let h = self.next_opcode();
-                self.cpu.set_de(join_u8(h, l));
-                3
             }
-            0x12 => {
-                self.set(self.cpu.de(), self.cpu.ra);
-                2
-            }
-            0x13 => {
-                // INC DE
-                self.cpu.set_de(self.cpu.de().wrapping_add(1));
-                2
-            }
-            0x14 => {
-                self.cpu.rd = self.cpu.inc(self.cpu.rd);
-                1
-            }
-            0x15 => {
-                self.cpu.rd = self.cpu.dec(self.cpu.rd);
-                1
-            }
-            0x16 => {
-                self.cpu.rd = self.next_opcode();
-                2
+            (0, 1, 7) => {
+                self.cpu.ra = self.cpu.rrc(self.cpu.ra);
+                self.cpu.set_flag(CFlag::Z, 0);
             }
-            0x17 => {
+            (0, 2, 7) => {
                 // RLA
                 let (cy, ra) = rl(self.cpu.get_flag(CFlag::CY), self.cpu.ra);
                 self.cpu.ra = ra;
@@ -614,1708 +982,187 @@ impl Runtime<'_> {
                 self.cpu.set_flag(CFlag::S, 0);
                 self.cpu.set_flag(CFlag::H, 0);
                 self.cpu.set_flag(CFlag::Z, 0);
-                1
-            }
-            0x18 => {
-                let raddr = self.next_opcode() as i8;
-                self.cpu.jr(raddr);
-                3
-            }
-            0x19 => {
-                let (cy, h, res) = add_u16(self.cpu.hl(), self.cpu.de());
-
-                self.cpu.set_hl(res);
-                self.cpu.set_flag(CFlag::S, 0);
-                self.cpu.set_flag(CFlag::CY, cy as u8);
-                self.cpu.set_flag(CFlag::H, h as u8);
-                2
-            }
-            0x1A => {
-                self.cpu.ra = self.get(self.cpu.de());
-                2
-            }
-            0x1B => {
-                self.cpu.set_de(self.cpu.de().wrapping_sub(1));
-                2
-            }
-            0x1C => {
-                self.cpu.re = self.cpu.inc(self.cpu.re);
-                1
             }
-            0x1D => {
-                self.cpu.re = self.cpu.dec(self.cpu.re);
-                1
-            }
-            0x1E => {
-                self.cpu.re = self.next_opcode();
-                2
-            }
-            0x1F => {
+            (0, 3, 7) => {
                 self.cpu.ra = self.cpu.rr(self.cpu.ra);
                 self.cpu.set_flag(CFlag::Z, 0);
-                1
-            }
-            0x20 => {
-                let addr = self.next_opcode() as i8;
-                if self.cpu.get_flag(CFlag::Z) == 0 {
-                    self.cpu.jr(addr);
-                    3
-                } else {
-                    2
-                }
-            }
-            0x21 => {
-                self.cpu.rl = self.next_opcode();
-                self.cpu.rh = self.next_opcode();
-                3
-            }
-            0x22 => {
-                // LD (HL+), A
-                self.set(self.cpu.hl(), self.cpu.ra);
-                self.cpu.set_hl(self.cpu.hl().wrapping_add(1));
-                2
-            }
-            0x23 => {
-                // INC HL
-                self.cpu.set_hl(self.cpu.hl().wrapping_add(1));
-                2
             }
-            0x24 => {
-                self.cpu.rh = self.cpu.inc(self.cpu.rh);
-                1
-            }
-            0x25 => {
-                self.cpu.rh = self.cpu.dec(self.cpu.rh);
-                1
-            }
-            0x26 => {
-                self.cpu.rh = self.next_opcode();
-                2
-            }
-            0x27 => {
-                self.cpu.daa();
-                1
-            }
-            0x28 => {
-                let raddr = self.next_opcode() as i8;
-                if self.cpu.get_flag(CFlag::Z) == 1 {
-                    self.cpu.jr(raddr);
-                    3
-                } else {
-                    2
-                }
-            }
-            0x29 => {
-                let (cy, h, res) = add_u16(self.cpu.hl(), self.cpu.hl());
-                self.cpu.set_hl(res);
-                self.cpu.set_flag(CFlag::S, 0);
-                self.cpu.set_flag(CFlag::CY, cy);
-                self.cpu.set_flag(CFlag::H, h);
-                2
-            }
-            0x2A => {
-                self.cpu.ra = self.get(self.cpu.hl());
-                self.cpu.set_hl(self.cpu.hl() + 1);
-                2
-            }
-            0x2B => {
-                self.cpu.set_hl(self.cpu.hl().wrapping_sub(1));
-                2
-            }
-            0x2C => {
-                self.cpu.rl = self.cpu.inc(self.cpu.rl);
-                1
-            }
-            0x2D => {
-                self.cpu.rl = self.cpu.dec(self.cpu.rl);
-                1
-            }
-            0x2E => {
-                self.cpu.rl = self.next_opcode();
-                2
-            }
-            0x2F => {
+            (0, 4, 7) => self.cpu.daa(),
+            (0, 5, 7) => {
+                // CPL
                 self.cpu.ra = !self.cpu.ra;
                 self.cpu.set_flag(CFlag::S, 1);
                 self.cpu.set_flag(CFlag::H, 1);
-                1
-            }
-            0x30 => {
-                let rel_addr = self.next_opcode();
-                let cy = self.cpu.get_flag(CFlag::CY);
-                if cy == 0 {
-                    self.cpu.jr(rel_addr as i8);
-                    3
-                } else {
-                    2
-                }
-            }
-            0x31 => {
-                // LD SP, d16
-                let l = self.next_opcode();
-                let h = self.next_opcode();
-                self.cpu.sp = join_u8(h, l);
-                3
-            }
-            0x32 => {
-                let hl = self.cpu.hl();
-                self.set(hl, self.cpu.ra);
-                self.cpu.set_hl(hl - 1);
-                2
-            }
-            0x33 => {
-                self.cpu.sp = self.cpu.sp.wrapping_add(1);
-                2
-            }
-            0x34 => {
-                let val = self.get(self.cpu.hl());
-                let val = self.cpu.inc(val);
-                self.set(self.cpu.hl(), val);
-                3
-            }
-            0x35 => {
-                let val = self.get(self.cpu.hl());
-                let val = self.cpu.dec(val);
-                self.set(self.cpu.hl(), val);
-                3
-            }
-            0x36 => {
-                let val = self.next_opcode();
-                self.set(self.cpu.hl(), val);
-                3
             }
-            0x37 => {
+            (0, 6, 7) => {
+                // SCF
                 self.cpu.set_flag(CFlag::CY, 1);
                 self.cpu.set_flag(CFlag::H, 0);
                 self.cpu.set_flag(CFlag::S, 0);
-                1
             }
-            0x38 => {
-                let raddr = self.next_opcode();
-                if self.cpu.get_flag(CFlag::CY) == 1 {
-                    self.cpu.jr(raddr as i8);
-                    3
-                } else {
-                    2
-                }
-            }
-            0x39 => {
-                let (cy, hc, res) = add_u16(self.cpu.sp, self.cpu.hl());
-                self.cpu.set_hl(res);
-                self.cpu.set_flag(CFlag::S, 0);
-                self.cpu.set_flag(CFlag::H, hc);
-                self.cpu.set_flag(CFlag::CY, cy);
-
-                2
-            }
-            0x3A => {
-                self.cpu.ra = self.get(self.cpu.hl());
-                self.cpu.set_hl(self.cpu.hl().wrapping_sub(1));
-                2
-            }
-            0x3B => {
-                self.cpu.sp = self.cpu.sp.wrapping_sub(1);
-                2
-            }
-            0x3C => {
-                self.cpu.ra = self.cpu.inc(self.cpu.ra);
-                1
-            }
-            0x3D => {
-                self.cpu.ra = self.cpu.dec(self.cpu.ra);
-                1
-            }
-            0x3E => {
-                // LD A, d8
-                self.cpu.ra = self.next_opcode();
-                2
-            }
-            0x3F => {
+            (0, _, 7) => {
+                // CCF
                 let cy = self.cpu.get_flag(CFlag::CY);
                 self.cpu.set_flag(CFlag::CY, cy ^ 0x1);
                 self.cpu.set_flag(CFlag::S, 0);
                 self.cpu.set_flag(CFlag::H, 0);
-                1
-            }
-            0x40 => {
-                self.cpu.rb = self.cpu.rb;
-                1
-            }
-            0x41 => {
-                self.cpu.rb = self.cpu.rc;
-                1
-            }
-            0x42 => {
-                self.cpu.rb = self.cpu.rd;
-                1
-            }
-            0x43 => {
-                self.cpu.rb = self.cpu.re;
-                1
-            }
-            0x44 => {
-                self.cpu.rb = self.cpu.rh;
-                1
-            }
-            0x45 => {
-                self.cpu.rb = self.cpu.rl;
-                1
-            }
-            0x46 => {
-                self.cpu.rb = self.get(self.cpu.hl());
-                2
-            }
-            0x47 => {
-                self.cpu.rb = self.cpu.ra;
-                1
-            }
-            0x48 => {
-                self.cpu.rc = self.cpu.rb;
-                1
-            }
-            0x49 => {
-                self.cpu.rc = self.cpu.rc;
-                1
-            }
-            0x4A => {
-                self.cpu.rc = self.cpu.rd;
-                1
-            }
-            0x4B => {
-                self.cpu.rc = self.cpu.re;
-                1
-            }
-            0x4C => {
-                self.cpu.rc = self.cpu.rh;
-                1
-            }
-            0x4D => {
-                self.cpu.rc = self.cpu.rl;
-                1
-            }
-            0x4E => {
-                self.cpu.rc = self.get(self.cpu.hl());
-                2
-            }
-            0x4F => {
-                self.cpu.rc = self.cpu.ra;
-                1
-            }
-            0x50 => {
-                self.cpu.rd = self.cpu.rb;
-                1
-            }
-            0x51 => {
-                self.cpu.rd = self.cpu.rc;
-                1
-            }
-            0x52 => {
-                self.cpu.rd = self.cpu.rd;
-                1
             }
-            0x53 => {
-                self.cpu.rd = self.cpu.re;
-                1
-            }
-            0x54 => {
-                self.cpu.rd = self.cpu.rh;
-                1
-            }
-            0x55 => {
-                self.cpu.rd = self.cpu.rl;
-                1
-            }
-            0x56 => {
-                self.cpu.rd = self.get(self.cpu.hl());
-                2
-            }
-            0x57 => {
-                self.cpu.rd = self.cpu.ra;
-                1
-            }
-            0x58 => {
-                self.cpu.re = self.cpu.rb;
-                1
-            }
-            0x59 => {
-                self.cpu.re = self.cpu.rc;
-                1
-            }
-            0x5A => {
-                self.cpu.re = self.cpu.rd;
-                1
+            (1, 6, 6) => {
+                // HALT
+                if !self.cpu.ime && self.pending_interrupts() != 0 {
+                    self.cpu.halt_bug = true;
+                } else {
+                    self.cpu.halt = true;
+                }
             }
-            0x5B => {
-                self.cpu.re = self.cpu.re;
-                1
+            (1, _, _) => {
+                // LD r,r
+                let val = self.get_r8(z);
+                self.set_r8(y, val);
+            }
+            (2, _, _) => {
+                let val = self.get_r8(z);
+                self.cpu.alu(y, val);
+            }
+            (3, 0..=3, 0) => {
+                // RET cc, the condition is checked during an internal cycle
+                self.cycle();
+                if self.cpu.condition(y) {
+                    self.ret();
+                    return true;
+                }
             }
-            0x5C => {
-                self.cpu.re = self.cpu.rh;
-                1
+            (3, 4, 0) => {
+                let addr = 0xFF00 + self.next_opcode() as u16;
+                self.write(addr, self.cpu.ra);
             }
-            0x5D => {
-                self.cpu.re = self.cpu.rl;
-                1
+            (3, 5, 0) => {
+                // ADD SP,r8
+                let op = self.next_opcode() as i8;
+                self.cpu.sp = self.cpu.add_u16_i8(self.cpu.sp, op);
+                self.cpu.set_flag(CFlag::Z, 0);
             }
-            0x5E => {
-                self.cpu.re = self.get(self.cpu.hl());
-                2
+            (3, 6, 0) => {
+                let addr = 0xFF00 + self.next_opcode() as u16;
+                self.cpu.ra = self.read(addr);
             }
-            0x5F => {
-                self.cpu.re = self.cpu.ra;
-                1
+            (3, 7, 0) => {
+                // LD HL,SP+r8
+                let op = self.next_opcode() as i8;
+                let res = self.cpu.add_u16_i8(self.cpu.sp, op);
+                self.cpu.set_hl(res);
+                self.cpu.set_flag(CFlag::Z, 0);
             }
-            0x60 => {
-                self.cpu.rh = self.cpu.rb;
-                1
+            (3, _, 1) if q == 0 => {
+                let val = self.stack_pop_u16();
+                self.cpu.set_r16stk(p, val);
             }
-            0x61 => {
-                self.cpu.rh = self.cpu.rc;
-                1
+            (3, 1, 1) => self.ret(),
+            (3, 3, 1) => {
+                // RETI
+                self.ret();
+                self.cpu.ime = true;
             }
-            0x62 => {
-                self.cpu.rh = self.cpu.rd;
-                1
+            (3, 5, 1) => self.cpu.pc = self.cpu.hl(),
+            (3, 7, 1) => self.cpu.sp = self.cpu.hl(),
+            (3, 0..=3, 2) => {
+                // JP cc,a16
+                let addr = self.next_u16();
+                if self.cpu.condition(y) {
+                    self.cpu.pc = addr;
+                    return true;
+                }
             }
-            0x63 => {
-                self.cpu.rh = self.cpu.re;
-                1
+            (3, 4, 2) => {
+                let addr = 0xFF00 + self.cpu.rc as u16;
+                self.write(addr, self.cpu.ra);
             }
-            0x64 => {
-                self.cpu.rh = self.cpu.rh;
-                1
+            (3, 5, 2) => {
+                let addr = self.next_u16();
+                self.write(addr, self.cpu.ra);
             }
-            0x65 => {
-                self.cpu.rh = self.cpu.rl;
-                1
+            (3, 6, 2) => {
+                let addr = 0xFF00 + self.cpu.rc as u16;
+                self.cpu.ra = self.read(addr);
             }
-            0x66 => {
-                self.cpu.rh = self.get(self.cpu.hl());
-                2
+            (3, 7, 2) => {
+                let addr = self.next_u16();
+                self.cpu.ra = self.read(addr);
             }
-            0x67 => {
-                self.cpu.rh = self.cpu.ra;
-                1
+            (3, 0, 3) => self.cpu.pc = self.next_u16(),
+            (3, 6, 3) => {
+                // DI
+                self.cpu.ime = false;
+                self.cpu.ei_delay = false;
             }
-            0x68 => {
-                self.cpu.rl = self.cpu.rb;
-                1
+            (3, 7, 3) => {
+                // EI
+                self.cpu.ei_delay = true;
+            }
+            (3, 0..=3, 4) => {
+                // CALL cc,a16
+                let addr = self.next_u16();
+                if self.cpu.condition(y) {
+                    self.call(addr);
+                    return true;
+                }
             }
-            0x69 => {
-                self.cpu.rl = self.cpu.rc;
-                1
+            (3, _, 5) if q == 0 => self.stack_push_u16(self.cpu.r16stk(p)),
+            (3, 1, 5) => {
+                let addr = self.next_u16();
+                self.call(addr);
             }
-            0x6A => {
-                self.cpu.rl = self.cpu.rd;
-                1
+            (3, _, 6) => {
+                let val = self.next_opcode();
+                self.cpu.alu(y, val);
             }
-            0x6B => {
-                self.cpu.rl = self.cpu.re;
-                1
+            (3, _, 7) => {
+                // RST
+                self.call(y as u16 * 8);
             }
-            0x6C => {
-                self.cpu.rl = self.cpu.rh;
-                1
+            _ => {
+                // illegal opcodes: D3, DB, DD, E3, E4, EB, EC, ED, F4, FC and FD
+                self.cpu.locked = true;
+                self.lockup = Some(Lockup {
+                    pc: self.cpu.pc.wrapping_sub(1),
+                    opcode,
+                });
             }
-            0x6D => {
-                self.cpu.rl = self.cpu.rl;
-                1
+        }
+        return false;
+    }
+
+    /// Executes a `CB` prefixed opcode: an operation `xx`, a bit or rotation `yyy` and an
+    /// operand `zzz`.
+    fn eval_cb(&mut self, opcode: u8) {
+        let (x, y, z) = (opcode >> 6, (opcode >> 3) & 7, opcode & 7);
+        let mut val = self.get_r8(z);
+        match x {
+            0 => {
+                val = match y {
+                    0 => self.cpu.rlc(val),
+                    1 => self.cpu.rrc(val),
+                    2 => self.cpu.rl(val),
+                    3 => self.cpu.rr(val),
+                    4 => self.cpu.sla(val),
+                    5 => self.cpu.sra(val),
+                    6 => self.cpu.swap(val),
+                    _ => self.cpu.srl(val),
+                };
+            }
+            1 => {
+                // BIT only reads its operand
+                self.cpu.bit(val, y);
+                return;
+            }
+            2 => {
+                res(&mut val, y);
             }
-            0x6E => {
-                self.cpu.rl = self.get(self.cpu.hl());
-                2
+            _ => {
+                set(&mut val, y);
             }
-            0x6F => {
-                self.cpu.rl = self.cpu.ra;
-                1
-            }
-            0x70 => {
-                self.set(self.cpu.hl(), self.cpu.rb);
-                2
-            }
-            0x71 => {
-                self.set(self.cpu.hl(), self.cpu.rc);
-                2
-            }
-            0x72 => {
-                self.set(self.cpu.hl(), self.cpu.rd);
-                2
-            }
-            0x73 => {
-                self.set(self.cpu.hl(), self.cpu.re);
-                2
-            }
-            0x74 => {
-                self.set(self.cpu.hl(), self.cpu.rh);
-                2
-            }
-            0x75 => {
-                self.set(self.cpu.hl(), self.cpu.rl);
-                2
-            }
-            0x76 => {
-                self.cpu.halt = true;
-                1
-            }
-
-            0x77 => {
-                self.set(self.cpu.hl(), self.cpu.ra);
-                2
-            }
-
-            0x78 => self.cpu.ld_ra(self.cpu.rb),
-            0x79 => self.cpu.ld_ra(self.cpu.rc),
-            0x7A => self.cpu.ld_ra(self.cpu.rd),
-            0x7B => self.cpu.ld_ra(self.cpu.re),
-            0x7C => self.cpu.ld_ra(self.cpu.rh),
-            0x7D => self.cpu.ld_ra(self.cpu.rl),
-            0x7E => self.cpu.ld_ra(self.get(self.cpu.hl())) * 2,
-            0x7F => self.cpu.ld_ra(self.cpu.ra),
-
-            0x80 => self.cpu.add_ra(self.cpu.rb),
-            0x81 => self.cpu.add_ra(self.cpu.rc),
-            0x82 => self.cpu.add_ra(self.cpu.rd),
-            0x83 => self.cpu.add_ra(self.cpu.re),
-            0x84 => self.cpu.add_ra(self.cpu.rh),
-            0x85 => self.cpu.add_ra(self.cpu.rl),
-            0x86 => self.cpu.add_ra(self.get(self.cpu.hl())) * 2,
-            0x87 => self.cpu.add_ra(self.cpu.ra),
-
-            0x88 => self.cpu.adc_ra(self.cpu.rb),
-            0x89 => self.cpu.adc_ra(self.cpu.rc),
-            0x8A => self.cpu.adc_ra(self.cpu.rd),
-            0x8B => self.cpu.adc_ra(self.cpu.re),
-            0x8C => self.cpu.adc_ra(self.cpu.rh),
-            0x8D => self.cpu.adc_ra(self.cpu.rl),
-            0x8E => self.cpu.adc_ra(self.get(self.cpu.hl())) * 2,
-            0x8F => self.cpu.adc_ra(self.cpu.ra),
-
-            0x90 => {
-                self.cpu.ra = self.cpu.sub_ra(self.cpu.rb);
-                1
-            }
-            0x91 => {
-                self.cpu.ra = self.cpu.sub_ra(self.cpu.rc);
-                1
-            }
-            0x92 => {
-                self.cpu.ra = self.cpu.sub_ra(self.cpu.rd);
-                1
-            }
-            0x93 => {
-                self.cpu.ra = self.cpu.sub_ra(self.cpu.re);
-                1
-            }
-            0x94 => {
-                self.cpu.ra = self.cpu.sub_ra(self.cpu.rh);
-                1
-            }
-            0x95 => {
-                self.cpu.ra = self.cpu.sub_ra(self.cpu.rl);
-                1
-            }
-            0x96 => {
-                self.cpu.ra = self.cpu.sub_ra(self.get(self.cpu.hl()));
-                2
-            }
-            0x97 => {
-                self.cpu.ra = self.cpu.sub_ra(self.cpu.ra);
-                1
-            }
-
-            0x98 => {
-                self.cpu.ra = self.cpu.sbc_ra(self.cpu.rb);
-                1
-            }
-            0x99 => {
-                self.cpu.ra = self.cpu.sbc_ra(self.cpu.rc);
-                1
-            }
-            0x9A => {
-                self.cpu.ra = self.cpu.sbc_ra(self.cpu.rd);
-                1
-            }
-            0x9B => {
-                self.cpu.ra = self.cpu.sbc_ra(self.cpu.re);
-                1
-            }
-            0x9C => {
-                self.cpu.ra = self.cpu.sbc_ra(self.cpu.rh);
-                1
-            }
-            0x9D => {
-                self.cpu.ra = self.cpu.sbc_ra(self.cpu.rl);
-                1
-            }
-            0x9E => {
-                self.cpu.ra = self.cpu.sbc_ra(self.get(self.cpu.hl()));
-                2
-            }
-            0x9F => {
-                self.cpu.ra = self.cpu.sbc_ra(self.cpu.ra);
-                1
-            }
-
-            0xA0 => self.cpu.and_ra(self.cpu.rb),
-            0xA1 => self.cpu.and_ra(self.cpu.rc),
-            0xA2 => self.cpu.and_ra(self.cpu.rd),
-            0xA3 => self.cpu.and_ra(self.cpu.re),
-            0xA4 => self.cpu.and_ra(self.cpu.rh),
-            0xA5 => self.cpu.and_ra(self.cpu.rl),
-            0xA6 => self.cpu.and_ra(self.get(self.cpu.hl())) * 2,
-            0xA7 => self.cpu.and_ra(self.cpu.ra),
-
-            0xA8 => self.cpu.xor_ra(self.cpu.rb),
-            0xA9 => self.cpu.xor_ra(self.cpu.rc),
-            0xAA => self.cpu.xor_ra(self.cpu.rd),
-            0xAB => self.cpu.xor_ra(self.cpu.re),
-            0xAC => self.cpu.xor_ra(self.cpu.rh),
-            0xAD => self.cpu.xor_ra(self.cpu.rl),
-            0xAE => self.cpu.xor_ra(self.get(self.cpu.hl())) * 2,
-            0xAF => self.cpu.xor_ra(self.cpu.ra),
-
-            0xB0 => self.cpu.or_ra(self.cpu.rb),
-            0xB1 => self.cpu.or_ra(self.cpu.rc),
-            0xB2 => self.cpu.or_ra(self.cpu.rd),
-            0xB3 => self.cpu.or_ra(self.cpu.re),
-            0xB4 => self.cpu.or_ra(self.cpu.rh),
-            0xB5 => self.cpu.or_ra(self.cpu.rl),
-            0xB6 => self.cpu.or_ra(self.get(self.cpu.hl())) * 2,
-            0xB7 => self.cpu.or_ra(self.cpu.ra),
-
-            0xB8 => self.cpu.cp_ra(self.cpu.rb),
-            0xB9 => self.cpu.cp_ra(self.cpu.rc),
-            0xBA => self.cpu.cp_ra(self.cpu.rd),
-            0xBB => self.cpu.cp_ra(self.cpu.re),
-            0xBC => self.cpu.cp_ra(self.cpu.rh),
-            0xBD => self.cpu.cp_ra(self.cpu.rl),
-            0xBE => self.cpu.cp_ra(self.get(self.cpu.hl())) * 2,
-            0xBF => self.cpu.cp_ra(self.cpu.ra),
-
-            0xC0 => {
-                if self.cpu.get_flag(CFlag::Z) == 0 {
-                    self.cpu.pc = self.stack_pop_u16();
-                    5
-                } else {
-                    2
-                }
-            }
-            0xC1 => {
-                let val = self.stack_pop_u16();
-                self.cpu.set_bc(val);
-                3
-            }
-            0xC2 => {
-                let l = self.next_opcode();
-                let h = self.next_opcode();
-
-                if self.cpu.get_flag(CFlag::Z) == 0 {
-                    self.cpu.pc = join_u8(h, l);
-                    4
-                } else {
-                    3
-                }
-            }
-            0xC3 => {
-                let l = self.next_opcode();
-                let h = self.next_opcode();
-                self.cpu.pc = join_u8(h, l);
-                4
-            }
-            0xC4 => {
-                let l = self.next_opcode();
-                let h = self.next_opcode();
-
-                if self.cpu.get_flag(CFlag::Z) == 0 {
-                    self.stack_push_u16(self.cpu.pc);
-                    self.cpu.pc = join_u8(h, l);
-                    6
-                } else {
-                    3
-                }
-            }
-            0xC5 => {
-                // PUSH BC
-                self.stack_push_u16(self.cpu.bc());
-                4
-            }
-            0xC6 => {
-                let imm = self.next_opcode();
-                self.cpu.add_ra(imm);
-                2
-            }
-            0xC7 => {
-                // RST 0
-                self.stack_push_u16(self.cpu.pc);
-                self.cpu.pc = 0x00;
-                4
-            }
-            0xC8 => {
-                if self.cpu.get_flag(CFlag::Z) == 1 {
-                    self.cpu.pc = self.stack_pop_u16();
-                    5
-                } else {
-                    2
-                }
-            }
-            0xC9 => {
-                self.cpu.pc = self.stack_pop_u16();
-                4
-            }
-            0xCA => {
-                let l = self.next_opcode();
-                let h = self.next_opcode();
-                if self.cpu.get_flag(CFlag::Z) == 1 {
-                    self.cpu.pc = join_u8(h, l);
-                    4
-                } else {
-                    3
-                }
-            }
-            0xCB => {
-                let opnext = self.next_opcode();
-                self.eval_cb(opnext)
-            }
-            0xCC => {
-                let l = self.next_opcode();
-                let h = self.next_opcode();
-
-                if self.cpu.get_flag(CFlag::Z) == 1 {
-                    self.stack_push_u16(self.cpu.pc);
-                    self.cpu.pc = join_u8(h, l);
-                    6
-                } else {
-                    3
-                }
-            }
-            0xCD => {
-                // CALL a16
-                let l = self.next_opcode();
-                let h = self.next_opcode();
-                self.stack_push_u16(self.cpu.pc); // save PC to the stack
-                self.cpu.pc = join_u8(h, l);
-                6
-            }
-            0xCE => {
-                let imm = self.next_opcode();
-                self.cpu.adc_ra(imm);
-                2
-            }
-            0xCF => {
-                self.stack_push_u16(self.cpu.pc);
-                self.cpu.pc = 0x08;
-                4
-            }
-            0xD0 => {
-                if self.cpu.get_flag(CFlag::CY) == 0 {
-                    self.cpu.pc = self.stack_pop_u16();
-                    5
-                } else {
-                    2
-                }
-            }
-            0xD1 => {
-                let de = self.stack_pop_u16();
-                self.cpu.set_de(de);
-                3
-            }
-            0xD2 => {
-                let l = self.next_opcode();
-                let h = self.next_opcode();
-
-                if self.cpu.get_flag(CFlag::CY) == 0 {
-                    self.cpu.pc = join_u8(h, l);
-                    4
-                } else {
-                    3
-                }
-            }
-            0xD4 => {
-                let l = self.next_opcode();
-                let h = self.next_opcode();
-
-                if self.cpu.get_flag(CFlag::CY) == 0 {
-                    self.stack_push_u16(self.cpu.pc);
-                    self.cpu.pc = join_u8(h, l);
-                    6
-                } else {
-                    3
-                }
-            }
-
-            0xD5 => {
-                self.stack_push_u16(self.cpu.de());
-                4
-            }
-            0xD6 => {
-                let op = self.next_opcode();
-                self.cpu.ra = self.cpu.sub_ra(op);
-                2
-            }
-            0xD7 => {
-                self.stack_push_u16(self.cpu.pc);
-                self.cpu.pc = 0x10;
-                4
-            }
-            0xD8 => {
-                if self.cpu.get_flag(CFlag::CY) == 0x1 {
-                    self.cpu.pc = self.stack_pop_u16();
-                    5
-                } else {
-                    2
-                }
-            }
-            0xD9 => {
-                self.cpu.pc = self.stack_pop_u16();
-                self.cpu.ime = true;
-                4
-            }
-            0xDA => {
-                let l = self.next_opcode();
-                let h = self.next_opcode();
-                if self.cpu.get_flag(CFlag::CY) == 0x1 {
-                    self.cpu.pc = join_u8(h, l);
-                    4
-                } else {
-                    3
-                }
-            }
-            0xDC => {
-                let l = self.next_opcode();
-                let h = self.next_opcode();
-                if self.cpu.get_flag(CFlag::CY) == 0x1 {
-                    self.stack_push_u16(self.cpu.pc);
-                    self.cpu.pc = join_u8(h, l);
-                    6
-                } else {
-                    3
-                }
-            }
-            0xDE => {
-                let val = self.next_opcode();
-                self.cpu.ra = self.cpu.sbc_ra(val);
-                2
-            }
-            0xDF => {
-                self.stack_push_u16(self.cpu.pc);
-                self.cpu.pc = 0x18;
-                4
-            }
-            0xE0 => {
-                let addr = 0xFF00 + self.next_opcode() as u16;
-                self.set(addr, self.cpu.ra);
-                3
-            }
-            0xE1 => {
-                let hl = self.stack_pop_u16();
-                self.cpu.set_hl(hl);
-                3
-            }
-            0xE2 => {
-                let addr = 0xFF00 + self.cpu.rc as u16;
-                self.set(addr, self.cpu.ra);
-                2
-            }
-            0xE5 => {
-                self.stack_push_u16(self.cpu.hl());
-                4
-            }
-
-            0xE6 => {
-                let op = self.next_opcode();
-                self.cpu.and_ra(op);
-                2
-            }
-            0xE7 => {
-                self.stack_push_u16(self.cpu.pc);
-                self.cpu.pc = 0x20;
-                4
-            }
-            0xE8 => {
-                let op = self.next_opcode() as i8;
-                self.cpu.sp = self.cpu.add_u16_i8(self.cpu.sp, op);
-                self.cpu.set_flag(CFlag::Z, 0);
-                4
-            }
-
-            0xE9 => {
-                self.cpu.pc = self.cpu.hl();
-                1
-            }
-            0xEA => {
-                let l = self.next_opcode();
-                let h = self.next_opcode();
-                let addr = join_u8(h, l);
-                self.set(addr, self.cpu.ra);
-                4
-            }
-            0xEE => {
-                let op = self.next_opcode();
-                self.cpu.xor_ra(op);
-                2
-            }
-            0xEF => {
-                self.stack_push_u16(self.cpu.pc);
-                self.cpu.pc = 0x28;
-                4
-            }
-            0xF0 => {
-                let addr = 0xFF00 + self.next_opcode() as u16;
-                self.cpu.ra = self.get(addr);
-                3
-            }
-            0xF1 => {
-                let val = self.stack_pop_u16();
-                self.cpu.set_af(val);
-                3
-            }
-            0xF2 => {
-                let addr = 0xFF00 + self.cpu.rc as u16;
-                self.cpu.ra = self.get(addr);
-                2
-            }
-            0xF3 => {
-                // DI
-                self.cpu.ime = false;
-                1
-            }
-            0xF5 => {
-                let af = self.cpu.af();
-                self.stack_push_u16(af);
-                4
-            }
-            0xF6 => {
-                self.cpu.ra |= self.next_opcode();
-                self.cpu.set_flag(CFlag::Z, (self.cpu.ra == 0) as u8);
-                self.cpu.set_flag(CFlag::S, 0);
-                self.cpu.set_flag(CFlag::H, 0);
-                self.cpu.set_flag(CFlag::CY, 0);
-                2
-            }
-            0xF7 => {
-                self.stack_push_u16(self.cpu.pc);
-                self.cpu.pc = 0x30;
-                4
-            }
-            0xF8 => {
-                let op = self.next_opcode() as i8;
-                let res = self.cpu.add_u16_i8(self.cpu.sp, op);
-                self.cpu.set_hl(res);
-                self.cpu.set_flag(CFlag::Z, 0);
-                3
-            }
-            0xF9 => {
-                self.cpu.sp = self.cpu.hl();
-                2
-            }
-            0xFA => {
-                let l = self.next_opcode();
-                let h = self.next_opcode();
-                self.cpu.ra = self.get(join_u8(h, l));
-                4
-            }
-            0xFB => {
-                // EI
-                self.cpu.ime = true;
-                1
-            }
-            0xFE => {
-                let imm = self.next_opcode();
-                self.cpu.sub_ra(imm);
-                2
-            }
-            0xFF => {
-                self.stack_push_u16(self.cpu.pc);
-                self.cpu.pc = 0x38;
-                4
-            }
-            _ => {
-                panic!("ERROR: Opcode 0x{} not implemented!", b64(opcode));
-            }
-        };
-    }
-
-    fn eval_cb(&mut self, opcode: u8) -> u8 {
-        return match opcode {
-            0x00 => {
-                self.cpu.rb = self.cpu.rlc(self.cpu.rb);
-                2
-            }
-            0x01 => {
-                self.cpu.rc = self.cpu.rlc(self.cpu.rc);
-                2
-            }
-            0x02 => {
-                self.cpu.rd = self.cpu.rlc(self.cpu.rd);
-                2
-            }
-            0x03 => {
-                self.cpu.re = self.cpu.rlc(self.cpu.re);
-                2
-            }
-            0x04 => {
-                self.cpu.rh = self.cpu.rlc(self.cpu.rh);
-                2
-            }
-            0x05 => {
-                self.cpu.rl = self.cpu.rlc(self.cpu.rl);
-                2
-            }
-            0x06 => {
-                let hl = self.get(self.cpu.hl());
-                let hl = self.cpu.rlc(hl);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0x07 => {
-                self.cpu.ra = self.cpu.rlc(self.cpu.ra);
-                2
-            }
-
-            0x08 => {
-                self.cpu.rb = self.cpu.rrc(self.cpu.rb);
-                2
-            }
-            0x09 => {
-                self.cpu.rc = self.cpu.rrc(self.cpu.rc);
-                2
-            }
-            0x0A => {
-                self.cpu.rd = self.cpu.rrc(self.cpu.rd);
-                2
-            }
-            0x0B => {
-                self.cpu.re = self.cpu.rrc(self.cpu.re);
-                2
-            }
-            0x0C => {
-                self.cpu.rh = self.cpu.rrc(self.cpu.rh);
-                2
-            }
-            0x0D => {
-                self.cpu.rl = self.cpu.rrc(self.cpu.rl);
-                2
-            }
-            0x0E => {
-                let hl = self.get(self.cpu.hl());
-                let hl = self.cpu.rrc(hl);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0x0F => {
-                self.cpu.ra = self.cpu.rrc(self.cpu.ra);
-                2
-            }
-
-            0x10 => {
-                self.cpu.rb = self.cpu.rl(self.cpu.rb);
-                2
-            }
-            0x11 => {
-                self.cpu.rc = self.cpu.rl(self.cpu.rc);
-                2
-            }
-            0x12 => {
-                self.cpu.rd = self.cpu.rl(self.cpu.rd);
-                2
-            }
-            0x13 => {
-                self.cpu.re = self.cpu.rl(self.cpu.re);
-                2
-            }
-            0x14 => {
-                self.cpu.rh = self.cpu.rl(self.cpu.rh);
-                2
-            }
-            0x15 => {
-                self.cpu.rl = self.cpu.rl(self.cpu.rl);
-                2
-            }
-            0x16 => {
-                let hl = self.get(self.cpu.hl());
-                let hl = self.cpu.rl(hl);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0x17 => {
-                self.cpu.ra = self.cpu.rl(self.cpu.ra);
-                2
-            }
-
-            0x18 => {
-                self.cpu.rb = self.cpu.rr(self.cpu.rb);
-                2
-            }
-            0x19 => {
-                self.cpu.rc = self.cpu.rr(self.cpu.rc);
-                2
-            }
-            0x1A => {
-                self.cpu.rd = self.cpu.rr(self.cpu.rd);
-                2
-            }
-            0x1B => {
-                self.cpu.re = self.cpu.rr(self.cpu.re);
-                2
-            }
-            0x1C => {
-                self.cpu.rh = self.cpu.rr(self.cpu.rh);
-                2
-            }
-            0x1D => {
-                self.cpu.rl = self.cpu.rr(self.cpu.rl);
-                2
-            }
-            0x1E => {
-                let hl = self.get(self.cpu.hl());
-                let hl = self.cpu.rr(hl);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0x1F => {
-                self.cpu.ra = self.cpu.rr(self.cpu.ra);
-                2
-            }
-
-            0x20 => {
-                self.cpu.rb = self.cpu.sla(self.cpu.rb);
-                2
-            }
-            0x21 => {
-                self.cpu.rc = self.cpu.sla(self.cpu.rc);
-                2
-            }
-            0x22 => {
-                self.cpu.rd = self.cpu.sla(self.cpu.rd);
-                2
-            }
-            0x23 => {
-                self.cpu.re = self.cpu.sla(self.cpu.re);
-                2
-            }
-            0x24 => {
-                self.cpu.rh = self.cpu.sla(self.cpu.rh);
-                2
-            }
-            0x25 => {
-                self.cpu.rl = self.cpu.sla(self.cpu.rl);
-                2
-            }
-            0x26 => {
-                let hl = self.get(self.cpu.hl());
-                let hl = self.cpu.sla(hl);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0x27 => {
-                self.cpu.ra = self.cpu.sla(self.cpu.ra);
-                2
-            }
-
-            0x28 => {
-                self.cpu.rb = self.cpu.sra(self.cpu.rb);
-                2
-            }
-            0x29 => {
-                self.cpu.rc = self.cpu.sra(self.cpu.rc);
-                2
-            }
-            0x2A => {
-                self.cpu.rd = self.cpu.sra(self.cpu.rd);
-                2
-            }
-            0x2B => {
-                self.cpu.re = self.cpu.sra(self.cpu.re);
-                2
-            }
-            0x2C => {
-                self.cpu.rh = self.cpu.sra(self.cpu.rh);
-                2
-            }
-            0x2D => {
-                self.cpu.rl = self.cpu.sra(self.cpu.rl);
-                2
-            }
-            0x2E => {
-                let hl = self.get(self.cpu.hl());
-                let hl = self.cpu.sra(hl);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0x2F => {
-                self.cpu.ra = self.cpu.sra(self.cpu.ra);
-                2
-            }
-
-            0x30 => {
-                self.cpu.rb = self.cpu.swap(self.cpu.rb);
-                2
-            }
-            0x31 => {
-                self.cpu.rc = self.cpu.swap(self.cpu.rc);
-                2
-            }
-            0x32 => {
-                self.cpu.rd = self.cpu.swap(self.cpu.rd);
-                2
-            }
-            0x33 => {
-                self.cpu.re = self.cpu.swap(self.cpu.re);
-                2
-            }
-            0x34 => {
-                self.cpu.rh = self.cpu.swap(self.cpu.rh);
-                2
-            }
-            0x35 => {
-                self.cpu.rl = self.cpu.swap(self.cpu.rl);
-                2
-            }
-            0x36 => {
-                let val = self.cpu.swap(self.get(self.cpu.hl()));
-                self.set(self.cpu.hl(), val);
-                4
-            }
-            0x37 => {
-                self.cpu.ra = self.cpu.swap(self.cpu.ra);
-                2
-            }
-
-            0x38 => {
-                self.cpu.rb = self.cpu.srl(self.cpu.rb);
-                2
-            }
-            0x39 => {
-                self.cpu.rc = self.cpu.srl(self.cpu.rc);
-                2
-            }
-            0x3A => {
-                self.cpu.rd = self.cpu.srl(self.cpu.rd);
-                2
-            }
-            0x3B => {
-                self.cpu.re = self.cpu.srl(self.cpu.re);
-                2
-            }
-            0x3C => {
-                self.cpu.rh = self.cpu.srl(self.cpu.rh);
-                2
-            }
-            0x3D => {
-                self.cpu.rl = self.cpu.srl(self.cpu.rl);
-                2
-            }
-            0x3E => {
-                let hl = self.get(self.cpu.hl());
-                let hl = self.cpu.srl(hl);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0x3F => {
-                self.cpu.ra = self.cpu.srl(self.cpu.ra);
-                2
-            }
-
-            0x40 => {
-                self.cpu.bit(self.cpu.rb, 0);
-                2
-            }
-            0x41 => {
-                self.cpu.bit(self.cpu.rc, 0);
-                2
-            }
-            0x42 => {
-                self.cpu.bit(self.cpu.rd, 0);
-                2
-            }
-            0x43 => {
-                self.cpu.bit(self.cpu.re, 0);
-                2
-            }
-            0x44 => {
-                self.cpu.bit(self.cpu.rh, 0);
-                2
-            }
-            0x45 => {
-                self.cpu.bit(self.cpu.rl, 0);
-                2
-            }
-            0x46 => {
-                let hl = self.get(self.cpu.hl());
-                self.cpu.bit(hl, 0);
-                3
-            }
-            0x47 => {
-                self.cpu.bit(self.cpu.ra, 0);
-                2
-            }
-
-            0x48 => {
-                self.cpu.bit(self.cpu.rb, 1);
-                2
-            }
-            0x49 => {
-                self.cpu.bit(self.cpu.rc, 1);
-                2
-            }
-            0x4A => {
-                self.cpu.bit(self.cpu.rd, 1);
-                2
-            }
-            0x4B => {
-                self.cpu.bit(self.cpu.re, 1);
-                2
-            }
-            0x4C => {
-                self.cpu.bit(self.cpu.rh, 1);
-                2
-            }
-            0x4D => {
-                self.cpu.bit(self.cpu.rl, 1);
-                2
-            }
-            0x4E => {
-                let hl = self.get(self.cpu.hl());
-                self.cpu.bit(hl, 1);
-                3
-            }
-            0x4F => {
-                self.cpu.bit(self.cpu.ra, 1);
-                2
-            }
-
-            0x50 => {
-                self.cpu.bit(self.cpu.rb, 2);
-                2
-            }
-            0x51 => {
-                self.cpu.bit(self.cpu.rc, 2);
-                2
-            }
-            0x52 => {
-                self.cpu.bit(self.cpu.rd, 2);
-                2
-            }
-            0x53 => {
-                self.cpu.bit(self.cpu.re, 2);
-                2
-            }
-            0x54 => {
-                self.cpu.bit(self.cpu.rh, 2);
-                2
-            }
-            0x55 => {
-                self.cpu.bit(self.cpu.rl, 2);
-                2
-            }
-            0x56 => {
-                let hl = self.get(self.cpu.hl());
-                self.cpu.bit(hl, 2);
-                3
-            }
-            0x57 => {
-                self.cpu.bit(self.cpu.ra, 2);
-                2
-            }
-
-            0x58 => {
-                self.cpu.bit(self.cpu.rb, 3);
-                2
-            }
-            0x59 => {
-                self.cpu.bit(self.cpu.rc, 3);
-                2
-            }
-            0x5A => {
-                self.cpu.bit(self.cpu.rd, 3);
-                2
-            }
-            0x5B => {
-                self.cpu.bit(self.cpu.re, 3);
-                2
-            }
-            0x5C => {
-                self.cpu.bit(self.cpu.rh, 3);
-                2
-            }
-            0x5D => {
-                self.cpu.bit(self.cpu.rl, 3);
-                2
-            }
-            0x5E => {
-                let hl = self.get(self.cpu.hl());
-                self.cpu.bit(hl, 3);
-                3
-            }
-            0x5F => {
-                self.cpu.bit(self.cpu.ra, 3);
-                2
-            }
-
-            0x60 => {
-                self.cpu.bit(self.cpu.rb, 4);
-                2
-            }
-            0x61 => {
-                self.cpu.bit(self.cpu.rc, 4);
-                2
-            }
-            0x62 => {
-                self.cpu.bit(self.cpu.rd, 4);
-                2
-            }
-            0x63 => {
-                self.cpu.bit(self.cpu.re, 4);
-                2
-            }
-            0x64 => {
-                self.cpu.bit(self.cpu.rh, 4);
-                2
-            }
-            0x65 => {
-                self.cpu.bit(self.cpu.rl, 4);
-                2
-            }
-            0x66 => {
-                let hl = self.get(self.cpu.hl());
-                self.cpu.bit(hl, 4);
-                3
-            }
-            0x67 => {
-                self.cpu.bit(self.cpu.ra, 4);
-                2
-            }
-
-            0x68 => {
-                self.cpu.bit(self.cpu.rb, 5);
-                2
-            }
-            0x69 => {
-                self.cpu.bit(self.cpu.rc, 5);
-                2
-            }
-            0x6A => {
-                self.cpu.bit(self.cpu.rd, 5);
-                2
-            }
-            0x6B => {
-                self.cpu.bit(self.cpu.re, 5);
-                2
-            }
-            0x6C => {
-                self.cpu.bit(self.cpu.rh, 5);
-                2
-            }
-            0x6D => {
-                self.cpu.bit(self.cpu.rl, 5);
-                2
-            }
-            0x6E => {
-                let hl = self.get(self.cpu.hl());
-                self.cpu.bit(hl, 5);
-                3
-            }
-            0x6F => {
-                self.cpu.bit(self.cpu.ra, 5);
-                2
-            }
-
-            0x70 => {
-                self.cpu.bit(self.cpu.rb, 6);
-                2
-            }
-            0x71 => {
-                self.cpu.bit(self.cpu.rc, 6);
-                2
-            }
-            0x72 => {
-                self.cpu.bit(self.cpu.rd, 6);
-                2
-            }
-            0x73 => {
-                self.cpu.bit(self.cpu.re, 6);
-                2
-            }
-            0x74 => {
-                self.cpu.bit(self.cpu.rh, 6);
-                2
-            }
-            0x75 => {
-                self.cpu.bit(self.cpu.rl, 6);
-                2
-            }
-            0x76 => {
-                let hl = self.get(self.cpu.hl());
-                self.cpu.bit(hl, 6);
-                3
-            }
-            0x77 => {
-                self.cpu.bit(self.cpu.ra, 6);
-                2
-            }
-
-            0x78 => {
-                self.cpu.bit(self.cpu.rb, 7);
-                2
-            }
-            0x79 => {
-                self.cpu.bit(self.cpu.rc, 7);
-                2
-            }
-            0x7A => {
-                self.cpu.bit(self.cpu.rd, 7);
-                2
-            }
-            0x7B => {
-                self.cpu.bit(self.cpu.re, 7);
-                2
-            }
-            0x7C => {
-                self.cpu.bit(self.cpu.rh, 7);
-                2
-            }
-            0x7D => {
-                self.cpu.bit(self.cpu.rl, 7);
-                2
-            }
-            0x7E => {
-                let hl = self.get(self.cpu.hl());
-                self.cpu.bit(hl, 7);
-                3
-            }
-            0x7F => {
-                self.cpu.bit(self.cpu.ra, 7);
-                2
-            }
-
-            0x80 => res(&mut self.cpu.rb, 0),
-            0x81 => res(&mut self.cpu.rc, 0),
-            0x82 => res(&mut self.cpu.rd, 0),
-            0x83 => res(&mut self.cpu.re, 0),
-            0x84 => res(&mut self.cpu.rh, 0),
-            0x85 => res(&mut self.cpu.rl, 0),
-            0x86 => {
-                let hl = self.get(self.cpu.hl());
-                let hl = set_bit(hl, 0, false);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0x87 => res(&mut self.cpu.ra, 0),
-
-            0x88 => res(&mut self.cpu.rb, 1),
-            0x89 => res(&mut self.cpu.rc, 1),
-            0x8A => res(&mut self.cpu.rd, 1),
-            0x8B => res(&mut self.cpu.re, 1),
-            0x8C => res(&mut self.cpu.rh, 1),
-            0x8D => res(&mut self.cpu.rl, 1),
-            0x8E => {
-                let hl = self.get(self.cpu.hl());
-                let hl = set_bit(hl, 1, false);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0x8F => res(&mut self.cpu.ra, 1),
-
-            0x90 => res(&mut self.cpu.rb, 2),
-            0x91 => res(&mut self.cpu.rc, 2),
-            0x92 => res(&mut self.cpu.rd, 2),
-            0x93 => res(&mut self.cpu.re, 2),
-            0x94 => res(&mut self.cpu.rh, 2),
-            0x95 => res(&mut self.cpu.rl, 2),
-            0x96 => {
-                let hl = self.get(self.cpu.hl());
-                let hl = set_bit(hl, 2, false);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0x97 => res(&mut self.cpu.ra, 2),
-
-            0x98 => res(&mut self.cpu.rb, 3),
-            0x99 => res(&mut self.cpu.rc, 3),
-            0x9A => res(&mut self.cpu.rd, 3),
-            0x9B => res(&mut self.cpu.re, 3),
-            0x9C => res(&mut self.cpu.rh, 3),
-            0x9D => res(&mut self.cpu.rl, 3),
-            0x9E => {
-                let hl = self.get(self.cpu.hl());
-                let hl = set_bit(hl, 3, false);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0x9F => res(&mut self.cpu.ra, 3),
-
-            0xA0 => res(&mut self.cpu.rb, 4),
-            0xA1 => res(&mut self.cpu.rc, 4),
-            0xA2 => res(&mut self.cpu.rd, 4),
-            0xA3 => res(&mut self.cpu.re, 4),
-            0xA4 => res(&mut self.cpu.rh, 4),
-            0xA5 => res(&mut self.cpu.rl, 4),
-            0xA6 => {
-                let hl = self.get(self.cpu.hl());
-                let hl = set_bit(hl, 4, false);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0xA7 => res(&mut self.cpu.ra, 4),
-
-            0xA8 => res(&mut self.cpu.rb, 5),
-            0xA9 => res(&mut self.cpu.rc, 5),
-            0xAA => res(&mut self.cpu.rd, 5),
-            0xAB => res(&mut self.cpu.re, 5),
-            0xAC => res(&mut self.cpu.rh, 5),
-            0xAD => res(&mut self.cpu.rl, 5),
-            0xAE => {
-                let hl = self.get(self.cpu.hl());
-                let hl = set_bit(hl, 5, false);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0xAF => res(&mut self.cpu.ra, 5),
-
-            0xB0 => res(&mut self.cpu.rb, 6),
-            0xB1 => res(&mut self.cpu.rc, 6),
-            0xB2 => res(&mut self.cpu.rd, 6),
-            0xB3 => res(&mut self.cpu.re, 6),
-            0xB4 => res(&mut self.cpu.rh, 6),
-            0xB5 => res(&mut self.cpu.rl, 6),
-            0xB6 => {
-                let hl = self.get(self.cpu.hl());
-                let hl = set_bit(hl, 6, false);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0xB7 => res(&mut self.cpu.ra, 6),
-
-            0xB8 => res(&mut self.cpu.rb, 7),
-            0xB9 => res(&mut self.cpu.rc, 7),
-            0xBA => res(&mut self.cpu.rd, 7),
-            0xBB => res(&mut self.cpu.re, 7),
-            0xBC => res(&mut self.cpu.rh, 7),
-            0xBD => res(&mut self.cpu.rl, 7),
-            0xBE => {
-                let hl = self.get(self.cpu.hl());
-                let hl = set_bit(hl, 7, false);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0xBF => res(&mut self.cpu.ra, 7),
-
-            0xC0 => set(&mut self.cpu.rb, 0),
-            0xC1 => set(&mut self.cpu.rc, 0),
-            0xC2 => set(&mut self.cpu.rd, 0),
-            0xC3 => set(&mut self.cpu.re, 0),
-            0xC4 => set(&mut self.cpu.rh, 0),
-            0xC5 => set(&mut self.cpu.rl, 0),
-            0xC6 => {
-                let hl = self.get(self.cpu.hl());
-                let hl = set_bit(hl, 0, true);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0xC7 => set(&mut self.cpu.ra, 0),
-
-            0xC8 => set(&mut self.cpu.rb, 1),
-            0xC9 => set(&mut self.cpu.rc, 1),
-            0xCA => set(&mut self.cpu.rd, 1),
-            0xCB => set(&mut self.cpu.re, 1),
-            0xCC => set(&mut self.cpu.rh, 1),
-            0xCD => set(&mut self.cpu.rl, 1),
-            0xCE => {
-                let hl = self.get(self.cpu.hl());
-                let hl = set_bit(hl, 1, true);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0xCF => set(&mut self.cpu.ra, 1),
-
-            0xD0 => set(&mut self.cpu.rb, 2),
-            0xD1 => set(&mut self.cpu.rc, 2),
-            0xD2 => set(&mut self.cpu.rd, 2),
-            0xD3 => set(&mut self.cpu.re, 2),
-            0xD4 => set(&mut self.cpu.rh, 2),
-            0xD5 => set(&mut self.cpu.rl, 2),
-            0xD6 => {
-                let hl = self.get(self.cpu.hl());
-                let hl = set_bit(hl, 2, true);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0xD7 => set(&mut self.cpu.ra, 2),
-
-            0xD8 => set(&mut self.cpu.rb, 3),
-            0xD9 => set(&mut self.cpu.rc, 3),
-            0xDA => set(&mut self.cpu.rd, 3),
-            0xDB => set(&mut self.cpu.re, 3),
-            0xDC => set(&mut self.cpu.rh, 3),
-            0xDD => set(&mut self.cpu.rl, 3),
-            0xDE => {
-                let hl = self.get(self.cpu.hl());
-                let hl = set_bit(hl, 3, true);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0xDF => set(&mut self.cpu.ra, 3),
-
-            0xE0 => set(&mut self.cpu.rb, 4),
-            0xE1 => set(&mut self.cpu.rc, 4),
-            0xE2 => set(&mut self.cpu.rd, 4),
-            0xE3 => set(&mut self.cpu.re, 4),
-            0xE4 => set(&mut self.cpu.rh, 4),
-            0xE5 => set(&mut self.cpu.rl, 4),
-            0xE6 => {
-                let hl = self.get(self.cpu.hl());
-                let hl = set_bit(hl, 4, true);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0xE7 => set(&mut self.cpu.ra, 4),
-
-            0xE8 => set(&mut self.cpu.rb, 5),
-            0xE9 => set(&mut self.cpu.rc, 5),
-            0xEA => set(&mut self.cpu.rd, 5),
-            0xEB => set(&mut self.cpu.re, 5),
-            0xEC => set(&mut self.cpu.rh, 5),
-            0xED => set(&mut self.cpu.rl, 5),
-            0xEE => {
-                let hl = self.get(self.cpu.hl());
-                let hl = set_bit(hl, 5, true);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0xEF => set(&mut self.cpu.ra, 5),
-
-            0xF0 => set(&mut self.cpu.rb, 6),
-            0xF1 => set(&mut self.cpu.rc, 6),
-            0xF2 => set(&mut self.cpu.rd, 6),
-            0xF3 => set(&mut self.cpu.re, 6),
-            0xF4 => set(&mut self.cpu.rh, 6),
-            0xF5 => set(&mut self.cpu.rl, 6),
-            0xF6 => {
-                let hl = self.get(self.cpu.hl());
-                let hl = set_bit(hl, 6, true);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0xF7 => set(&mut self.cpu.ra, 6),
-
-            0xF8 => set(&mut self.cpu.rb, 7),
-            0xF9 => set(&mut self.cpu.rc, 7),
-            0xFA => set(&mut self.cpu.rd, 7),
-            0xFB => set(&mut self.cpu.re, 7),
-            0xFC => set(&mut self.cpu.rh, 7),
-            0xFD => set(&mut self.cpu.rl, 7),
-            0xFE => {
-                let hl = self.get(self.cpu.hl());
-                let hl = set_bit(hl, 7, true);
-                self.set(self.cpu.hl(), hl);
-                4
-            }
-            0xFF => set(&mut self.cpu.ra, 7),
-
-            _ => {
-                panic!("ERROR: Opcode CB{} not implemented", b64(opcode));
-            }
-        };
+        }
+        self.set_r8(z, val);
     }
 
     fn boot_rom_disabled(&self) -> bool {
@@ -2324,8 +1171,10 @@ impl Runtime<'_> {
     // RAM
 
     // STACK
+    /// Pushes after an internal cycle, the writes land on the last two cycles of PUSH and CALL.
     fn stack_push_u16(&mut self, value: u16) {
         // println!("Push: {}", value);
+        self.cycle();
         let (h, l) = split_u16(value);
         self.stack_push(h);
         self.stack_push(l);
@@ -2333,10 +1182,10 @@ impl Runtime<'_> {
 
     fn stack_push(&mut self, value: u8) {
         self.cpu.sp -= 1;
-        self.set(self.cpu.sp, value);
+        self.write(self.cpu.sp, value);
     }
     fn stack_pop(&mut self) -> u8 {
-        let val = self.get(self.cpu.sp);
+        let val = self.read(self.cpu.sp);
         self.cpu.sp += 1;
         return val;
     }
@@ -2347,6 +1196,35 @@ impl Runtime<'_> {
         // println!("Pop: {}", val);
         return val;
     }
+
+    /// Pushes PC and jumps to `addr`, used by CALL, RST and the interrupts.
+    fn call(&mut self, addr: u16) {
+        self.stack_push_u16(self.cpu.pc);
+
+        // frames at or below the new one have been abandoned (e.g. SP was reset)
+        while self.calls.last().is_some_and(|f| f.sp <= self.cpu.sp) {
+            self.calls.pop();
+        }
+        if self.calls.len() == MAX_CALLS {
+            self.calls.remove(0);
+        }
+        self.calls.push(CallFrame {
+            target: addr,
+            bank: self.memory.bank_of(addr),
+            ret: self.cpu.pc,
+            sp: self.cpu.sp,
+        });
+        self.cpu.pc = addr;
+    }
+
+    /// Pops PC, used by RET and RETI.
+    fn ret(&mut self) {
+        // also drops the frames of routines that returned without RET
+        while self.calls.last().is_some_and(|f| f.sp <= self.cpu.sp) {
+            self.calls.pop();
+        }
+        self.cpu.pc = self.stack_pop_u16();
+    }
 }
 
 fn add_u16(a: u16, b: u16) -> (u8, u8, u16) {
@@ -2495,4 +1373,327 @@ mod tests {
         assert_eq!(cpu.ra, 0x12);
         assert_eq!(cpu.rf, 0x34 & 0xF0);
     }
+
+    fn with_program(program: &[u8], f: impl FnOnce(&mut Runtime)) {
+        let mut game_rom = vec![0; 0x8000];
+        game_rom[0x100..0x100 + program.len()].copy_from_slice(program);
+        let bootstrap = vec![0; 0x100];
+        let mut rom = crate::mbc::RomMBC::new(&game_rom);
+        let mut rt = Runtime::noboot(&bootstrap, &mut rom);
+        f(&mut rt);
+    }
+
+    #[test]
+    fn test_call_stack_follows_call_and_ret() {
+        // CALL 0104, NOP, RET
+        with_program(&[0xCD, 0x04, 0x01, 0x00, 0xC9], |rt| {
+            rt.tick();
+            assert_eq!(
+                rt.calls,
+                vec![CallFrame {
+                    target: 0x104,
+                    bank: 0,
+                    ret: 0x103,
+                    sp: 0xFFFC
+                }]
+            );
+
+            rt.tick();
+            assert_eq!(rt.cpu.pc, 0x103);
+            assert!(rt.calls.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_reads_land_on_their_machine_cycle() {
+        with_program(&[
+            0xF0, 0x05, // LDH A,(TIMA): read on cycle 3
+            0xFA, 0x05, 0xFF, // LD A,(TIMA): on cycle 4
+        ], |rt| {
+            // TIMA increments every 4 machine cycles
+            rt.set(registers::TAC, 0b101);
+
+            assert_eq!(rt.tick(), 3);
+            assert_eq!(rt.cpu.ra, 0);
+
+            rt.memory.timer = crate::timer::Timer::new();
+            rt.set(registers::TAC, 0b101);
+            assert_eq!(rt.tick(), 4);
+            assert_eq!(rt.cpu.ra, 1);
+        });
+    }
+
+    #[test]
+    fn test_push_writes_after_an_internal_cycle() {
+        // PUSH BC with SP on TIMA: B is written to DIV (0xFF04) on cycle 3, one cycle before the end
+        with_program(&[0xC5, 0x00, 0x00, 0x00], |rt| {
+            rt.cpu.sp = 0xFF05;
+            rt.set(registers::TAC, 0b101);
+
+            assert_eq!(rt.tick(), 4);
+            rt.set(registers::TIMA, 0);
+            rt.tick();
+            rt.tick();
+            rt.sync();
+            assert_eq!(rt.get(registers::TIMA), 0);
+            rt.tick();
+            rt.sync();
+            assert_eq!(rt.get(registers::TIMA), 1);
+        });
+    }
+
+    #[test]
+    fn test_apu_steps_on_the_ticks_of_the_div_apu() {
+        // NOPs: the DIV-APU ticks every 8192 dots, 2048 machine cycles
+        with_program(&[], |rt| {
+            for _ in 0..2047 {
+                rt.tick();
+                assert!(!rt.take_apu());
+            }
+            rt.tick();
+            assert!(rt.take_apu());
+            rt.tick();
+            assert!(!rt.take_apu());
+
+            rt.set(registers::NR50, 0x77);
+            assert!(rt.take_apu());
+        });
+    }
+
+    /// Machine cycles of the first instruction of `program`, with the flags set, HL and SP in WRAM.
+    fn cycles_of(program: &[u8], flags: u8) -> u8 {
+        let mut cycles = 0;
+        with_program(program, |rt| {
+            rt.cpu.rf = flags;
+            rt.cpu.rh = 0xC0;
+            rt.cpu.rl = 0x00;
+            rt.cpu.sp = 0xDFF0;
+            cycles = rt.tick();
+        });
+        return cycles;
+    }
+
+    #[test]
+    fn test_branches_take_their_documented_cycles() {
+        const Z: u8 = 0x80;
+        // (program, cycles when taken, cycles when not taken), from the pan docs
+        let branches: [(&[u8], u8, u8); 4] = [
+            (&[0x28, 0x05], 3, 2),       // JR Z,r8
+            (&[0xCA, 0x00, 0x02], 4, 3), // JP Z,a16
+            (&[0xCC, 0x00, 0x02], 6, 3), // CALL Z,a16
+            (&[0xC8], 5, 2),             // RET Z
+        ];
+        for (program, taken, not_taken) in branches {
+            assert_eq!(cycles_of(program, Z), taken, "{:02X} taken", program[0]);
+            assert_eq!(cycles_of(program, 0), not_taken, "{:02X} not taken", program[0]);
+        }
+    }
+
+    #[test]
+    fn test_instructions_take_their_documented_cycles() {
+        let instructions: [(&[u8], u8); 20] = [
+            (&[0x18, 0x05], 3),       // JR r8
+            (&[0xC3, 0x00, 0x02], 4), // JP a16
+            (&[0xE9], 1),             // JP HL
+            (&[0xCD, 0x00, 0x02], 6), // CALL a16
+            (&[0xC9], 4),             // RET
+            (&[0xD9], 4),             // RETI
+            (&[0xFF], 4),             // RST 38H
+            (&[0xC5], 4),             // PUSH BC
+            (&[0xC1], 3),             // POP BC
+            (&[0x80], 1),             // ADD A,B
+            (&[0xC6, 0x01], 2),       // ADD A,d8
+            (&[0x86], 2),             // ADD A,(HL)
+            (&[0x34], 3),             // INC (HL)
+            (&[0x36, 0x01], 3),       // LD (HL),d8
+            (&[0x09], 2),             // ADD HL,BC
+            (&[0xE8, 0x01], 4),       // ADD SP,r8
+            (&[0xF8, 0x01], 3),       // LD HL,SP+r8
+            (&[0x08, 0x00, 0xC0], 5), // LD (a16),SP
+            (&[0xCB, 0x46], 3),       // BIT 0,(HL)
+            (&[0xCB, 0x86], 4),       // RES 0,(HL)
+        ];
+        for (program, cycles) in instructions {
+            assert_eq!(cycles_of(program, 0), cycles, "{:02X?}", program);
+        }
+    }
+
+    #[test]
+    fn test_stop_skips_its_second_byte() {
+        // STOP, then INC A
+        with_program(&[0x10, 0x3C, 0x3C], |rt| {
+            rt.cpu.ra = 0;
+            assert_eq!(rt.tick(), 1);
+            assert_eq!(rt.cpu.pc, 0x102);
+            rt.tick();
+            assert_eq!(rt.cpu.ra, 1);
+        });
+    }
+
+    #[test]
+    fn test_ei_takes_effect_after_the_next_instruction() {
+        // EI, NOP, NOP with the timer interrupt pending
+        with_program(&[0xFB, 0x00, 0x00], |rt| {
+            rt.set(registers::IE, 0b100);
+            rt.set(registers::IF, 0b100);
+
+            rt.tick();
+            rt.tick();
+            assert_eq!(rt.cpu.pc, 0x102);
+            // dispatch
+            assert_eq!(rt.tick(), 5);
+            assert_eq!(rt.cpu.pc, 0x50);
+            assert_eq!(rt.get(registers::IF) & 0b100, 0);
+            assert_eq!(rt.get(0xFFFC), 0x02);
+            assert_eq!(rt.get(0xFFFD), 0x01);
+        });
+    }
+
+    #[test]
+    fn test_di_cancels_a_pending_ei() {
+        with_program(&[0xFB, 0xF3, 0x00], |rt| {
+            rt.set(registers::IE, 0b100);
+            rt.set(registers::IF, 0b100);
+
+            rt.tick();
+            rt.tick();
+            rt.tick();
+            assert_eq!(rt.cpu.pc, 0x103);
+            assert!(!rt.cpu.ime());
+        });
+    }
+
+    #[test]
+    fn test_halt_bug_fetches_the_next_byte_twice() {
+        // HALT, INC A with IME off and an interrupt pending
+        with_program(&[0x76, 0x3C, 0x00], |rt| {
+            rt.set(registers::IE, 0b100);
+            rt.set(registers::IF, 0b100);
+            rt.cpu.ra = 0;
+
+            rt.tick();
+            assert!(!rt.cpu.halted());
+            rt.tick();
+            rt.tick();
+            assert_eq!(rt.cpu.ra, 2);
+            assert_eq!(rt.cpu.pc, 0x102);
+        });
+    }
+
+    #[test]
+    fn test_halt_waits_for_an_interrupt() {
+        with_program(&[0x76, 0x00], |rt| {
+            rt.set(registers::IE, 0b100);
+
+            rt.tick();
+            rt.tick();
+            assert!(rt.cpu.halted());
+            assert_eq!(rt.cpu.pc, 0x101);
+
+            // IME is off: the cpu wakes up and resumes after HALT
+            rt.set(registers::IF, 0b100);
+            rt.tick();
+            rt.tick();
+            assert!(!rt.cpu.halted());
+            assert_eq!(rt.cpu.pc, 0x102);
+        });
+    }
+
+    #[test]
+    fn test_dma_leaves_only_hram_to_the_cpu() {
+        with_program(&[], |rt| {
+            // LDH A,($90) then LD A,($C000), run from HRAM
+            for (i, byte) in [0xF0, 0x90, 0xFA, 0x00, 0xC0].iter().enumerate() {
+                rt.set(0xFF80 + i as u16, *byte);
+            }
+            rt.set(0xFF90, 0x42);
+            rt.set(0xC000, 0x12);
+            rt.cpu.pc = 0xFF80;
+
+            rt.set(0xFF46, 0xC0);
+            rt.tick();
+            assert_eq!(rt.cpu.ra, 0x42);
+            rt.tick();
+            assert_eq!(rt.cpu.ra, 0xFF);
+
+            while rt.memory.dma_active() {
+                rt.cycle();
+            }
+            assert_eq!(rt.get(0xFE00), 0x12);
+        });
+    }
+
+    #[test]
+    fn test_cpu_sees_the_io_register_masks() {
+        // LD A,(a16) then LD (a16),A
+        with_program(&[
+            0xFA, 0x0F, 0xFF, 0xFA, 0x03, 0xFF, 0xEA, 0x44, 0xFF, 0xEA, 0xA0, 0xFE, 0xFA, 0xA0,
+            0xFE,
+        ], |rt| {
+            rt.set(registers::IF, 0x04);
+
+            rt.tick();
+            assert_eq!(rt.cpu.ra, 0xE4);
+            // unmapped
+            rt.tick();
+            assert_eq!(rt.cpu.ra, 0xFF);
+            // LY is read only
+            let ly = rt.get(registers::LY);
+            rt.tick();
+            assert_eq!(rt.get(registers::LY), ly);
+            // FEA0-FEFF isn't usable
+            rt.tick();
+            rt.tick();
+            assert_eq!(rt.cpu.ra, 0x00);
+        });
+    }
+
+    #[test]
+    fn test_illegal_opcode_locks_the_cpu_up() {
+        with_program(&[0x00, 0xD3, 0x00], |rt| {
+            rt.set(registers::IE, 0b100);
+            rt.cpu.ime = true;
+
+            rt.tick();
+            assert_eq!(rt.take_lockup(), None);
+            rt.tick();
+            assert_eq!(rt.take_lockup(), Some(Lockup { pc: 0x101, opcode: 0xD3 }));
+            assert!(rt.cpu.locked());
+
+            // not even an interrupt gets it out
+            rt.set(registers::IF, 0b100);
+            assert_eq!(rt.tick(), 1);
+            assert_eq!(rt.cpu.pc, 0x102);
+            assert_eq!(rt.take_lockup(), None);
+        });
+    }
+
+    #[test]
+    fn test_watchpoint_reports_cpu_writes() {
+        use crate::memory::{Watch, WatchHit, Watchpoint};
+
+        // LD (C010),A
+        with_program(&[0xEA, 0x10, 0xC0], |rt| {
+            rt.memory.watchpoints.push(Watchpoint {
+                kind: Watch::Change,
+                start: 0xC000,
+                end: 0xC0FF,
+            });
+
+            // accesses outside of the cpu are ignored
+            rt.set(0xC010, 0x05);
+            assert_eq!(rt.memory.take_watch_hit(), None);
+
+            rt.tick();
+            assert_eq!(
+                rt.memory.take_watch_hit(),
+                Some(WatchHit {
+                    kind: Watch::Change,
+                    addr: 0xC010,
+                    old: 0x05,
+                    new: 0x01
+                })
+            );
+        });
+    }
 }
@@ -0,0 +1,111 @@
+//! Cycle scheduler: the components tell when they next need to run and are only caught up then,
+//! or when the cpu accesses their registers.
+use crate::state::{Reader, Snapshot};
+use std::io;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    // end of the current step of the ppu (mode change, fetch of 8 pixels)
+    Ppu,
+    // reload of TIMA after an overflow, with the timer interrupt
+    Timer,
+    // tick of the DIV-APU, the apu steps its envelopes, sweeps and lengths
+    Apu,
+    // end of a serial transfer on the internal clock, with the serial interrupt
+    Serial,
+}
+
+const EVENTS: usize = 4;
+const IDLE: u64 = u64::MAX;
+
+pub struct Scheduler {
+    // dots since power on
+    pub now: u64,
+    // when each component needs to run next
+    at: [u64; EVENTS],
+    // when each component was last caught up
+    synced: [u64; EVENTS],
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        // everything runs on the first cycle
+        return Scheduler {
+            now: 0,
+            at: [0; EVENTS],
+            synced: [0; EVENTS],
+        };
+    }
+
+    /// Runs `event` in `dots`, or never.
+    pub fn schedule(&mut self, event: Event, dots: Option<u32>) {
+        self.at[event as usize] = match dots {
+            Some(dots) => self.now + dots as u64,
+            None => IDLE,
+        };
+    }
+
+    /// The earliest event which is due, it is unscheduled.
+    pub fn pop(&mut self) -> Option<Event> {
+        let (idx, at) = self.at.iter().enumerate().min_by_key(|(_, at)| **at)?;
+        if *at > self.now {
+            return None;
+        }
+        self.at[idx] = IDLE;
+        return Some([Event::Ppu, Event::Timer, Event::Apu, Event::Serial][idx]);
+    }
+
+    /// Dots the component has to catch up since the last call.
+    pub fn sync(&mut self, event: Event) -> u32 {
+        let dots = self.now - self.synced[event as usize];
+        self.synced[event as usize] = self.now;
+        return dots as u32;
+    }
+}
+
+impl Snapshot for Scheduler {
+    fn save(&self, out: &mut Vec<u8>) {
+        for val in [self.now].iter().chain(&self.at).chain(&self.synced) {
+            out.extend_from_slice(&val.to_le_bytes());
+        }
+    }
+
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()> {
+        let mut buf = [0; 8];
+        inp.bytes(&mut buf)?;
+        self.now = u64::from_le_bytes(buf);
+        for val in self.at.iter_mut().chain(self.synced.iter_mut()) {
+            inp.bytes(&mut buf)?;
+            *val = u64::from_le_bytes(buf);
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_are_popped_once_due() {
+        let mut scheduler = Scheduler::new();
+        assert_eq!(scheduler.pop(), Some(Event::Ppu));
+        assert_eq!(scheduler.pop(), Some(Event::Timer));
+        assert_eq!(scheduler.pop(), Some(Event::Apu));
+        assert_eq!(scheduler.pop(), Some(Event::Serial));
+        assert_eq!(scheduler.pop(), None);
+
+        scheduler.schedule(Event::Ppu, Some(80));
+        scheduler.schedule(Event::Timer, None);
+        scheduler.schedule(Event::Apu, Some(8192));
+        scheduler.now = 76;
+        assert_eq!(scheduler.pop(), None);
+        scheduler.now = 80;
+        assert_eq!(scheduler.sync(Event::Ppu), 80);
+        assert_eq!(scheduler.pop(), Some(Event::Ppu));
+        assert_eq!(scheduler.pop(), None);
+
+        scheduler.now = 84;
+        assert_eq!(scheduler.sync(Event::Ppu), 4);
+    }
+}
@@ -0,0 +1,197 @@
+//! Screenshot comparison of the `Display` against reference images, for the ppu test roms.
+//! Roms and their reference image (same name, `.png`) live in `roms/ppu/`.
+use crate::headless::{Headless, LD_B_B};
+use crate::mbc::RomMBC;
+use crate::memory::Memory;
+use crate::ppu::color_from_code;
+use crate::runtime::Runtime;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+// actual and diff images of failed comparisons are written here.
+const OUTPUT_DIR: &str = "target/screenshots";
+
+/// Runs until the rom executes the `LD B,B` breakpoint, or for `max_frames` frames.
+fn run_until_breakpoint(emu: &mut Headless, max_frames: u64) {
+    while emu.frames < max_frames {
+        let breakpoint = emu.rt.get(emu.rt.cpu().pc) == LD_B_B;
+        emu.step();
+        if breakpoint {
+            return;
+        }
+    }
+}
+
+fn to_rgb(pixels: &[u8]) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(pixels.len() * 3);
+    for code in pixels {
+        let color = color_from_code(*code as usize);
+        rgb.extend_from_slice(&[color.r, color.g, color.b]);
+    }
+    return rgb;
+}
+
+fn luminance(r: u8, g: u8, b: u8) -> i32 {
+    return (r as i32 * 299 + g as i32 * 587 + b as i32 * 114) / 1000;
+}
+
+/// Maps a color to the closest shade of the palette, reference images use their own palette.
+fn to_code(r: u8, g: u8, b: u8) -> u8 {
+    let lum = luminance(r, g, b);
+    return (0..4u8)
+        .min_by_key(|code| {
+            let c = color_from_code(*code as usize);
+            (luminance(c.r, c.g, c.b) - lum).abs()
+        })
+        .unwrap();
+}
+
+fn write_png(path: &Path, width: u32, height: u32, rgb: &[u8]) {
+    let file = File::create(path).unwrap();
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header().unwrap().write_image_data(rgb).unwrap();
+}
+
+/// Decodes a png into color codes.
+fn read_png(path: &Path) -> (u32, u32, Vec<u8>) {
+    let file =
+        File::open(path).unwrap_or_else(|_| panic!("Reference `{}´ not found", path.display()));
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+
+    let mut reader = decoder.read_info().unwrap();
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).unwrap();
+
+    let channels = info.color_type.samples();
+    let codes = buf[..info.buffer_size()]
+        .chunks(channels)
+        .map(|px| match channels {
+            1 | 2 => to_code(px[0], px[0], px[0]),
+            _ => to_code(px[0], px[1], px[2]),
+        })
+        .collect();
+    return (info.width, info.height, codes);
+}
+
+/// Compares the color codes of a frame against a reference image. On failure, writes the frame
+/// and a diff image (differing pixels in red) to `OUTPUT_DIR`.
+pub fn compare(pixels: &[u8], width: u32, height: u32, reference: &Path) -> Result<(), String> {
+    let (ref_width, ref_height, expected) = read_png(reference);
+    if (ref_width, ref_height) != (width, height) {
+        return Err(format!(
+            "Reference is {}x{}, frame is {}x{}",
+            ref_width, ref_height, width, height
+        ));
+    }
+
+    let mut diff = Vec::with_capacity(pixels.len() * 3);
+    let mut mismatches = 0;
+    for (got, exp) in pixels.iter().zip(expected.iter()) {
+        if got == exp {
+            // matching pixels are faded, so that the differences stand out.
+            let c = color_from_code(*got as usize);
+            let faded = 192 + (luminance(c.r, c.g, c.b) / 4) as u8;
+            diff.extend_from_slice(&[faded, faded, faded]);
+        } else {
+            mismatches += 1;
+            diff.extend_from_slice(&[255, 0, 0]);
+        }
+    }
+
+    if mismatches == 0 {
+        return Ok(());
+    }
+
+    let name = reference.file_stem().unwrap().to_string_lossy();
+    fs::create_dir_all(OUTPUT_DIR).unwrap();
+    let actual_path = PathBuf::from(OUTPUT_DIR).join(format!("{}.png", name));
+    let diff_path = PathBuf::from(OUTPUT_DIR).join(format!("{}.diff.png", name));
+    write_png(&actual_path, width, height, &to_rgb(pixels));
+    write_png(&diff_path, width, height, &diff);
+
+    return Err(format!(
+        "{} pixels differ from `{}´, see `{}´ and `{}´",
+        mismatches,
+        reference.display(),
+        actual_path.display(),
+        diff_path.display()
+    ));
+}
+
+/// Boots `rom`, runs it until the breakpoint and compares the frame with the `.png` next to it.
+fn screenshot_test(rom: &str, max_frames: u64) {
+    let rom = Path::new(rom);
+    let game_rom = fs::read(rom).unwrap_or_else(|_| panic!("Rom `{}´ not found", rom.display()));
+    let bootstrap = fs::read("DMG_ROM.bin").unwrap();
+
+    let mut mbc = RomMBC::new(&game_rom);
+    let rt = Runtime::load(&bootstrap, &mut mbc);
+    let mut emu = Headless::new(rt);
+    run_until_breakpoint(&mut emu, max_frames);
+
+    let display = &emu.rt.display;
+    let result = compare(
+        display.pixels(),
+        display.width() as u32,
+        display.height() as u32,
+        &rom.with_extension("png"),
+    );
+    if let Err(msg) = result {
+        panic!("{}", msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! screenshot_test {
+        ($name:ident, $rom:expr) => {
+            #[test]
+            #[ignore = "requires the rom and its reference image in roms/ppu/"]
+            fn $name() {
+                screenshot_test($rom, 60 * 10);
+            }
+        };
+    }
+
+    screenshot_test!(test_dmg_acid2, "roms/ppu/dmg-acid2.gb");
+    screenshot_test!(test_mealybug_m2_win_en_toggle, "roms/ppu/m2_win_en_toggle.gb");
+    screenshot_test!(test_mealybug_m3_bgp_change, "roms/ppu/m3_bgp_change.gb");
+    screenshot_test!(test_mealybug_m3_lcdc_bg_en_change, "roms/ppu/m3_lcdc_bg_en_change.gb");
+    screenshot_test!(test_mealybug_m3_scx_low_3_bits, "roms/ppu/m3_scx_low_3_bits.gb");
+
+    #[test]
+    fn test_compare_accepts_identical_frame() {
+        let pixels: Vec<u8> = (0..160 * 144).map(|i| (i % 4) as u8).collect();
+        let reference = std::env::temp_dir().join("gbc_identical.png");
+        write_png(&reference, 160, 144, &to_rgb(&pixels));
+
+        assert_eq!(compare(&pixels, 160, 144, &reference), Ok(()));
+    }
+
+    #[test]
+    fn test_compare_writes_diff_on_mismatch() {
+        let mut pixels: Vec<u8> = vec![0; 160 * 144];
+        let reference = std::env::temp_dir().join("gbc_mismatch.png");
+        write_png(&reference, 160, 144, &to_rgb(&pixels));
+
+        pixels[10] = 3;
+        pixels[20] = 2;
+        let err = compare(&pixels, 160, 144, &reference).unwrap_err();
+        assert!(err.starts_with("2 pixels differ"), "{}", err);
+        assert!(Path::new(OUTPUT_DIR).join("gbc_mismatch.diff.png").exists());
+    }
+
+    #[test]
+    fn test_reference_palette_maps_to_closest_shade() {
+        assert_eq!(to_code(0xFF, 0xFF, 0xFF), 0);
+        assert_eq!(to_code(0xAA, 0xAA, 0xAA), 1);
+        assert_eq!(to_code(0x55, 0x55, 0x55), 2);
+        assert_eq!(to_code(0x00, 0x00, 0x00), 3);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::byteop::*;
+use crate::memory::IoDevice;
+use crate::registers;
+use crate::state::{put_u16, Reader, Snapshot};
+use std::io;
+
+// the internal clock shifts a bit every 512 dots (8192Hz), a byte takes 8 of them
+const TRANSFER_DOTS: u16 = 8 * 512;
+
+/// Serial port: SB is shifted out on the internal clock, nothing is plugged in so 1s come back.
+/// The shift clock isn't aligned to the system counter.
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    // dots left in the transfer, 0 when there is none
+    remaining: u16,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            sb: 0,
+            sc: 0,
+            remaining: 0,
+        }
+    }
+
+    /// Advances the transfer by `dots`, returns true when it completes (serial interrupt).
+    pub fn tick(&mut self, dots: u32) -> bool {
+        if self.remaining == 0 {
+            return false;
+        }
+        if dots < self.remaining as u32 {
+            self.remaining -= dots as u16;
+            return false;
+        }
+        self.remaining = 0;
+        self.sb = 0xFF;
+        self.sc = set_bit(self.sc, 7, false);
+        return true;
+    }
+
+    /// Dots until the end of the transfer, None while there is none.
+    pub fn next_event(&self) -> Option<u32> {
+        if self.remaining == 0 {
+            return None;
+        }
+        return Some(self.remaining as u32);
+    }
+}
+
+impl IoDevice for Serial {
+    fn read_io(&self, addr: u16) -> u8 {
+        return match addr {
+            registers::SB => self.sb,
+            _ => self.sc,
+        };
+    }
+
+    fn write_io(&mut self, addr: u16, val: u8) {
+        match addr {
+            registers::SB => self.sb = val,
+            _ => {
+                self.sc = val & 0x81;
+                // with the external clock, the transfer waits for a partner that never comes
+                self.remaining = if self.sc == 0x81 { TRANSFER_DOTS } else { 0 };
+            }
+        }
+    }
+}
+
+impl Snapshot for Serial {
+    fn save(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[self.sb, self.sc]);
+        put_u16(out, self.remaining);
+    }
+
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()> {
+        self.sb = inp.u8()?;
+        self.sc = inp.u8()?;
+        self.remaining = inp.u16()?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transfer_completes_after_8_bits() {
+        let mut serial = Serial::new();
+        serial.write_io(registers::SB, 0x42);
+        serial.write_io(registers::SC, 0x81);
+        assert_eq!(serial.next_event(), Some(4096));
+
+        assert!(!serial.tick(4095));
+        assert_eq!(serial.read_io(registers::SC), 0x81);
+        assert!(serial.tick(1));
+        assert_eq!(serial.read_io(registers::SC), 0x01);
+        assert_eq!(serial.read_io(registers::SB), 0xFF);
+        assert_eq!(serial.next_event(), None);
+    }
+
+    #[test]
+    fn test_external_clock_never_completes() {
+        let mut serial = Serial::new();
+        serial.write_io(registers::SC, 0x80);
+        assert_eq!(serial.next_event(), None);
+        assert!(!serial.tick(10000));
+        assert_eq!(serial.read_io(registers::SC), 0x80);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::apu::APU;
+use crate::runtime::Runtime;
+use std::io;
+
+const MAGIC: &[u8; 4] = b"GBCS";
+// bumped whenever a component saves something else
+const VERSION: u8 = 1;
+
+/// Components that can be written to, and restored from, a machine snapshot.
+/// Values are stored little endian, in the order they are saved.
+pub trait Snapshot {
+    fn save(&self, out: &mut Vec<u8>);
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()>;
+}
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    pub fn new(buf: &[u8]) -> Reader<'_> {
+        Reader { buf, pos: 0 }
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        let mut val = [0];
+        self.bytes(&mut val)?;
+        return Ok(val[0]);
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        let l = self.u8()? as u16;
+        let h = self.u8()? as u16;
+        return Ok((h << 8) + l);
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        return Ok(self.u8()? != 0);
+    }
+
+    pub fn f32(&mut self) -> io::Result<f32> {
+        let mut buf = [0; 4];
+        self.bytes(&mut buf)?;
+        return Ok(f32::from_le_bytes(buf));
+    }
+
+    pub fn bytes(&mut self, out: &mut [u8]) -> io::Result<()> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + out.len())
+            .ok_or(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated state",
+            ))?;
+        out.copy_from_slice(bytes);
+        self.pos += out.len();
+        return Ok(());
+    }
+}
+
+pub fn put_u16(out: &mut Vec<u8>, val: u16) {
+    out.extend_from_slice(&val.to_le_bytes());
+}
+
+pub fn put_f32(out: &mut Vec<u8>, val: f32) {
+    out.extend_from_slice(&val.to_le_bytes());
+}
+
+/// Snapshot of the whole machine: cpu, memory, timer, ppu, the last drawn frame and the voices
+/// of the apu.
+pub fn save_machine(rt: &Runtime, apu: &APU) -> Vec<u8> {
+    let mut out = Vec::with_capacity(0x20000);
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    rt.save(&mut out);
+    apu.save(&mut out);
+    return out;
+}
+
+fn load(inp: &mut Reader, rt: &mut Runtime, apu: &mut APU) -> io::Result<()> {
+    rt.load(inp)?;
+    return apu.load(inp);
+}
+
+/// Restores a snapshot of `save_machine`. On error the machine is left as it was.
+pub fn load_machine(state: &[u8], rt: &mut Runtime, apu: &mut APU) -> io::Result<()> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    if state.len() < 5 || &state[..4] != MAGIC {
+        return Err(invalid("not a state file"));
+    }
+    if state[4] != VERSION {
+        return Err(invalid("state saved by another version"));
+    }
+
+    let backup = save_machine(rt, apu);
+    let calls = rt.calls.clone();
+    let mut inp = Reader::new(&state[5..]);
+    // the size of the external ram depends on the cartridge
+    let result = match load(&mut inp, rt, apu) {
+        Ok(()) if inp.pos != inp.buf.len() => Err(invalid("state of another rom")),
+        result => result,
+    };
+    if result.is_err() {
+        load(&mut Reader::new(&backup[5..]), rt, apu).unwrap();
+        rt.calls = calls;
+    }
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reader_reads_values_in_order() {
+        let mut out = vec![0x12];
+        put_u16(&mut out, 0xBEEF);
+        out.extend_from_slice(&[1, 2, 3]);
+
+        let mut inp = Reader::new(&out);
+        assert_eq!(inp.u8().unwrap(), 0x12);
+        assert_eq!(inp.u16().unwrap(), 0xBEEF);
+
+        let mut bytes = [0; 3];
+        inp.bytes(&mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2, 3]);
+        assert!(inp.u8().is_err());
+    }
+
+    #[test]
+    fn test_bad_states_leave_the_machine_alone() {
+        let game_rom = vec![0; 0x8000];
+        let bootstrap = vec![0; 0x100];
+        let mut rom = crate::mbc::RomMBC::new(&game_rom);
+        let mut rt = Runtime::noboot(&bootstrap, &mut rom);
+        let mut apu = APU::new();
+        let state = save_machine(&rt, &apu);
+        rt.tick();
+        let ticked = save_machine(&rt, &apu);
+
+        assert!(load_machine(&state[..state.len() / 2], &mut rt, &mut apu).is_err());
+        assert!(load_machine(b"GBCS", &mut rt, &mut apu).is_err());
+        assert!(load_machine(&[&state[..], &[0]].concat(), &mut rt, &mut apu).is_err());
+        let mut stale = state.clone();
+        stale[4] = VERSION + 1;
+        assert!(load_machine(&stale, &mut rt, &mut apu).is_err());
+        assert_eq!(save_machine(&rt, &apu), ticked);
+
+        load_machine(&state, &mut rt, &mut apu).unwrap();
+        assert_eq!(save_machine(&rt, &apu), state);
+    }
+}
@@ -0,0 +1,109 @@
+//! Symbol files generated by rgblink: one `bank:address label` line per symbol, `;` comments.
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+/// Bank of `addr` as numbered in symbol files, `rom_bank` is the one mapped at 0x4000.
+pub fn bank_of(addr: u16, rom_bank: u8) -> u8 {
+    return match addr {
+        0x4000..=0x7FFF => rom_bank,
+        0xD000..=0xDFFF => 1,
+        _ => 0,
+    };
+}
+
+pub struct Symbols {
+    labels: HashMap<(u8, u16), String>,
+    addresses: HashMap<String, (u8, u16)>,
+}
+
+impl Symbols {
+    pub fn parse(text: &str) -> Symbols {
+        let mut symbols = Symbols {
+            labels: HashMap::new(),
+            addresses: HashMap::new(),
+        };
+
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap().trim();
+            let Some((location, label)) = line.split_once(char::is_whitespace) else {
+                continue;
+            };
+            let Some((bank, addr)) = location.split_once(':') else {
+                continue;
+            };
+            let (Ok(bank), Ok(addr)) = (u8::from_str_radix(bank, 16), u16::from_str_radix(addr, 16))
+            else {
+                continue;
+            };
+
+            let label = label.trim().to_string();
+            // the first label of an address is kept, usually the global one
+            symbols.labels.entry((bank, addr)).or_insert(label.clone());
+            symbols.addresses.insert(label, (bank, addr));
+        }
+        return symbols;
+    }
+
+    pub fn load(filename: &str) -> io::Result<Symbols> {
+        return Ok(Symbols::parse(&fs::read_to_string(filename)?));
+    }
+
+    pub fn len(&self) -> usize {
+        return self.addresses.len();
+    }
+
+    pub fn label(&self, bank: u8, addr: u16) -> Option<&str> {
+        return self.labels.get(&(bank, addr)).map(|label| label.as_str());
+    }
+
+    pub fn lookup(&self, label: &str) -> Option<(u8, u16)> {
+        return self.addresses.get(label).copied();
+    }
+
+    /// Closest label at or before `addr` in the same bank, with the offset from it: `Label+3`.
+    pub fn describe(&self, bank: u8, addr: u16) -> Option<String> {
+        let ((_, start), label) = self
+            .labels
+            .iter()
+            .filter(|((b, a), _)| *b == bank && *a <= addr && addr - *a < 0x4000)
+            .max_by_key(|((_, a), _)| *a)?;
+
+        return Some(match addr - start {
+            0 => label.clone(),
+            offset => format!("{}+{}", label, offset),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYM: &str = "\
+; File generated by rgblink
+00:0150 Main
+00:0158 Main.loop
+01:4000 PlayerUpdate
+00:c000 wPlayerX
+";
+
+    #[test]
+    fn test_parse_labels() {
+        let symbols = Symbols::parse(SYM);
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(symbols.label(0, 0x150), Some("Main"));
+        assert_eq!(symbols.label(1, 0x4000), Some("PlayerUpdate"));
+        assert_eq!(symbols.label(2, 0x4000), None);
+        assert_eq!(symbols.lookup("wPlayerX"), Some((0, 0xC000)));
+    }
+
+    #[test]
+    fn test_describe_uses_closest_label() {
+        let symbols = Symbols::parse(SYM);
+        assert_eq!(symbols.describe(0, 0x150), Some("Main".to_string()));
+        assert_eq!(symbols.describe(0, 0x153), Some("Main+3".to_string()));
+        assert_eq!(symbols.describe(0, 0x15A), Some("Main.loop+2".to_string()));
+        assert_eq!(symbols.describe(1, 0x3FFF), None);
+    }
+}
@@ -1,202 +1,386 @@
 use crate::byteop::*;
+use crate::memory::IoDevice;
 use crate::registers;
-use crate::memory::Memory;
+use crate::state::{put_u16, Reader, Snapshot};
+use std::io;
 
-pub struct Timer {
-    internal_ticks: u16,
+// bit of the system counter whose falling edge increments TIMA, by TAC clock select
+const TIMA_BITS: [u32; 4] = [1 << 9, 1 << 3, 1 << 5, 1 << 7];
+// bit of the system counter (bit 4 of DIV) whose falling edge ticks the DIV-APU
+const DIV_APU_BIT: u32 = 1 << 12;
 
+/// DIV is the upper byte of a 16-bit system counter incremented every dot, TIMA counts the
+/// falling edges of one of its bits.
+pub struct Timer {
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    // dots left until TIMA is reloaded from TMA after an overflow, TIMA reads 0 meanwhile
+    overflow: u8,
+    // dots left in the cycle of the reload, TIMA writes are ignored and TMA writes go through
+    reloaded: u8,
+
+    // DIV-APU ticks since the start of the current instruction
     pub delta_div: u8,
 }
 
 impl Timer {
     pub fn new() -> Timer {
         Timer {
-            internal_ticks: 0,
+            counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflow: 0,
+            reloaded: 0,
             delta_div: 0,
         }
     }
 
-    pub fn tick(&mut self, mem: &mut impl Memory, ticks: u8) {
-        let internal_ticks = self.internal_ticks;
-        self.internal_ticks = self.internal_ticks.wrapping_add(ticks as u16);
-
-        let div = mem.get(registers::DIV);
-
-        let timer_incr = timer_increment(internal_ticks, ticks, 3);
+    /// Advances the timer by `ticks` dots, returns true when TIMA overflows (timer interrupt).
+    pub fn tick(&mut self, ticks: u32) -> bool {
+        let mut interrupt = false;
+        let mut ticks = ticks;
+        while ticks > 0 {
+            // the reload goes dot by dot, otherwise nothing happens until TIMA increments
+            let mut dots = ticks;
+            if self.overflow > 0 || self.reloaded > 0 {
+                dots = 1;
+                if self.reloaded > 0 {
+                    self.reloaded -= 1;
+                }
+                if self.overflow > 0 {
+                    self.overflow -= 1;
+                    if self.overflow == 0 {
+                        self.tima = self.tma;
+                        self.reloaded = 4;
+                        interrupt = true;
+                    }
+                }
+            } else if self.enabled() {
+                dots = dots.min(to_falling_edge(self.counter, self.tima_bit()));
+            }
 
-        // apu tick every time bit 4 goes from 1 to 0
-        self.delta_div = ((div & 0b11111) + timer_incr) >> 5;
+            let counter = self.counter as u32;
+            self.delta_div += falling_edges(counter, dots, DIV_APU_BIT) as u8;
+            self.counter = (counter + dots) as u16;
+            if self.enabled() && falling_edges(counter, dots, self.tima_bit()) > 0 {
+                self.increment();
+            }
+            ticks -= dots;
+        }
+        return interrupt;
+    }
 
-        let div = div.wrapping_add(timer_incr);
+    /// Dots until the timer interrupt, None while the timer is stopped.
+    pub fn next_event(&self) -> Option<u32> {
+        if self.overflow > 0 {
+            return Some(self.overflow as u32);
+        }
+        if !self.enabled() {
+            return None;
+        }
+        let bit = self.tima_bit();
+        let increments = to_falling_edge(self.counter, bit) + (0xFF - self.tima) as u32 * 2 * bit;
+        // the reload comes a machine cycle after the overflow
+        return Some(increments + 4);
+    }
 
-        mem.hwset(registers::DIV, div as u8);
+    /// Dots until the next tick of the DIV-APU.
+    pub fn next_div_apu(&self) -> u32 {
+        return to_falling_edge(self.counter, DIV_APU_BIT);
+    }
 
-        let tima = mem.get(registers::TIMA);
-        let tma = mem.get(registers::TMA);
-        let tac = mem.get(registers::TAC);
+    fn enabled(&self) -> bool {
+        return get_bit(self.tac, 2) == 1;
+    }
 
-        let timer_speed = tac & 0b11;
+    fn tima_bit(&self) -> u32 {
+        return TIMA_BITS[(self.tac & 0b11) as usize];
+    }
 
-        if get_bit(tac, 2) == 0x1 {
-            let incr = timer_increment(internal_ticks, ticks, timer_speed);
-            let interrupt = (tima as u16 + incr as u16) > 0xFF;
+    /// Input of the TIMA edge detector, the selected counter bit gated by the enable bit.
+    fn signal(&self) -> bool {
+        return self.enabled() && self.counter as u32 & self.tima_bit() != 0;
+    }
 
-            let tima = if interrupt {
-                let int_flag = mem.get(registers::IF) | 0b100;
-                mem.set(registers::IF, int_flag);
+    fn increment(&mut self) {
+        if self.tima == 0xFF {
+            // the reload and the interrupt come one machine cycle later
+            self.tima = 0;
+            self.overflow = 4;
+        } else {
+            self.tima += 1;
+        }
+    }
+}
 
-                tma + ((incr - (0xFF - tima + 1)) & (0xFF - tma))
-            } else {
-                tima + incr
-            };
+impl IoDevice for Timer {
+    fn read_io(&self, addr: u16) -> u8 {
+        return match addr {
+            registers::DIV => (self.counter >> 8) as u8,
+            registers::TIMA => self.tima,
+            registers::TMA => self.tma,
+            _ => self.tac,
+        };
+    }
 
-            mem.set(registers::TIMA, tima);
+    fn write_io(&mut self, addr: u16, val: u8) {
+        match addr {
+            // any write resets the whole counter, a selected bit going low increments TIMA
+            registers::DIV => {
+                let signal = self.signal();
+                if self.counter as u32 & DIV_APU_BIT != 0 {
+                    self.delta_div += 1;
+                }
+                self.counter = 0;
+                if signal {
+                    self.increment();
+                }
+            }
+            registers::TIMA => {
+                if self.reloaded == 0 {
+                    // during the overflow cycle it cancels the reload and the interrupt
+                    self.overflow = 0;
+                    self.tima = val;
+                }
+            }
+            registers::TMA => {
+                self.tma = val;
+                if self.reloaded > 0 {
+                    self.tima = val;
+                }
+            }
+            _ => {
+                // switching the clock or disabling it can be seen as a falling edge
+                let signal = self.signal();
+                self.tac = val & 0b111;
+                if signal && !self.signal() {
+                    self.increment();
+                }
+            }
         }
     }
 }
 
-fn timer_increment(curr_cycles: u16, elapsed: u8, speed: u8) -> u8 {
-    let shifts = match speed {
-        0 => 8 + 2, // 4x slower
-        1 => 8 - 4, // 16x faster
-        2 => 8 - 2, // 2x faster
-        3 => 8,     // 1x (same as div)
-        _ => panic!("Unhandled speed"),
-    };
+impl Snapshot for Timer {
+    fn save(&self, out: &mut Vec<u8>) {
+        put_u16(out, self.counter);
+        out.extend_from_slice(&[self.tima, self.tma, self.tac, self.overflow, self.reloaded]);
+        out.push(self.delta_div);
+    }
+
+    fn load(&mut self, inp: &mut Reader) -> io::Result<()> {
+        self.counter = inp.u16()?;
+        self.tima = inp.u8()?;
+        self.tma = inp.u8()?;
+        self.tac = inp.u8()?;
+        self.overflow = inp.u8()?;
+        self.reloaded = inp.u8()?;
+        self.delta_div = inp.u8()?;
+        return Ok(());
+    }
+}
 
-    let mask = (1 << shifts) - 1;
-    let mut curr_cycles = curr_cycles & mask;
-    curr_cycles += elapsed as u16;
+/// Dots until `bit` of the counter goes from 1 to 0.
+fn to_falling_edge(counter: u16, bit: u32) -> u32 {
+    return 2 * bit - counter as u32 % (2 * bit);
+}
 
-    return (curr_cycles >> shifts) as u8;
+/// Times `bit` goes from 1 to 0 while the counter advances by `dots`.
+fn falling_edges(counter: u32, dots: u32, bit: u32) -> u32 {
+    return (counter + dots) / (2 * bit) - counter / (2 * bit);
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    struct Mem {
-        memory: Vec<u8>,
-    }
-    impl Mem {
-        fn new() -> Mem {
-            Mem {
-                memory: vec![0; 0xFFFF],
-            }
-        }
-    }
-    impl Memory for Mem {
-        fn get(&self, addr: u16) -> u8 {
-            self.memory[addr as usize]
-        }
-        fn set(&mut self, addr: u16, val: u8) {
-            self.memory[addr as usize] = val;
-        }
-        fn hwset(&mut self, addr: u16, val: u8) {
-            self.memory[addr as usize] = val;
-        }
-    }
-
     #[test]
     fn test_div_increment() {
         let mut timer = Timer::new();
-        let mut mem = Mem::new();
 
-        timer.tick(&mut mem, 255);
-        assert_eq!(mem.get(registers::DIV), 0);
+        timer.tick(255);
+        assert_eq!(timer.read_io(registers::DIV), 0);
 
-        timer.tick(&mut mem, 1);
-        assert_eq!(mem.get(registers::DIV), 1);
+        timer.tick(1);
+        assert_eq!(timer.read_io(registers::DIV), 1);
     }
 
     #[test]
     fn test_invokes_interrupt() {
         let mut timer = Timer::new();
-        let mut mem = Mem::new();
-        mem.set(registers::TAC, 0b111);
+        timer.write_io(registers::TAC, 0b111);
 
-        timer.tick(&mut mem, 255);
-        assert_eq!(mem.get(registers::DIV), 0);
+        timer.tick(255);
+        assert_eq!(timer.read_io(registers::DIV), 0);
 
-        timer.tick(&mut mem, 1);
-        assert_eq!(mem.get(registers::DIV), 1);
+        timer.tick(1);
+        assert_eq!(timer.read_io(registers::DIV), 1);
     }
 
     #[test]
     fn test_does_not_increment_tima_if_tac_enable_is_0() {
         let mut timer = Timer::new();
-        let mut mem = Mem::new();
-        mem.set(registers::TAC, 0b011);
-        timer.tick(&mut mem, 255);
-        timer.tick(&mut mem, 1);
+        timer.write_io(registers::TAC, 0b011);
+        timer.tick(255);
+        timer.tick(1);
 
-        let tima = mem.get(registers::TIMA);
+        let tima = timer.read_io(registers::TIMA);
         assert_eq!(tima, 0);
     }
 
     #[test]
     fn test_does_increment_tima_if_tac_enable_is_1() {
         let mut timer = Timer::new();
-        let mut mem = Mem::new();
-        mem.set(registers::TAC, 0b111);
-        timer.tick(&mut mem, 255);
-        timer.tick(&mut mem, 1);
+        timer.write_io(registers::TAC, 0b111);
+        timer.tick(255);
+        timer.tick(1);
 
-        let tima = mem.get(registers::TIMA);
+        let tima = timer.read_io(registers::TIMA);
         assert_eq!(tima, 1);
     }
 
     #[test]
     fn test_tima_restarts_from_tma() {
         let mut timer = Timer::new();
-        let mut mem = Mem::new();
-        mem.set(registers::TAC, 0b111);
-        mem.set(registers::TIMA, 0xFF);
-        mem.set(registers::TMA, 0xFE);
+        timer.write_io(registers::TAC, 0b111);
+        timer.write_io(registers::TIMA, 0xFF);
+        timer.write_io(registers::TMA, 0xFE);
 
-        timer.tick(&mut mem, 255);
-        timer.tick(&mut mem, 1);
+        timer.tick(255);
+        assert!(!timer.tick(1));
+        assert_eq!(timer.read_io(registers::TIMA), 0);
 
-        let tima = mem.get(registers::TIMA);
+        // reloaded a machine cycle later
+        assert!(timer.tick(4));
+        let tima = timer.read_io(registers::TIMA);
         assert_eq!(tima, 0xFE);
     }
 
     #[test]
     fn test_tima_restarts_from_tma_f0() {
         let mut timer = Timer::new();
-        let mut mem = Mem::new();
-        mem.set(registers::TAC, 0b111);
-        mem.set(registers::TIMA, 0xFF);
-        mem.set(registers::TMA, 0xF0);
+        timer.write_io(registers::TAC, 0b111);
+        timer.write_io(registers::TIMA, 0xFF);
+        timer.write_io(registers::TMA, 0xF0);
 
-        timer.tick(&mut mem, 255);
-        timer.tick(&mut mem, 1);
+        timer.tick(255);
+        timer.tick(1);
+        timer.tick(4);
 
-        let tima = mem.get(registers::TIMA);
+        let tima = timer.read_io(registers::TIMA);
         assert_eq!(tima, 0xF0);
     }
 
     #[test]
     fn test_tima() {
         let mut timer = Timer::new();
-        let mut mem = Mem::new();
-        mem.set(registers::TAC, 0b101);
-        mem.set(registers::TIMA, 0);
-        mem.set(registers::IF, 0);
+        timer.write_io(registers::TAC, 0b101);
+        timer.write_io(registers::TIMA, 0);
+        let mut interrupt = false;
+
+        for _ in 0..4 {
+            interrupt |= timer.tick(250);
+            interrupt |= timer.tick(250);
+        }
+        interrupt |= timer.tick(250);
+
+        assert!(!interrupt);
 
         for _ in 0..4 {
-            timer.tick(&mut mem, 250);
-            timer.tick(&mut mem, 250);
+            interrupt |= timer.tick(250);
+            interrupt |= timer.tick(250);
         }
-        timer.tick(&mut mem, 250);
 
-        assert_eq!(mem.get(0xFF0F), 0);
+        assert!(interrupt);
+    }
 
+    #[test]
+    fn test_writing_div_resets_it() {
+        let mut timer = Timer::new();
         for _ in 0..4 {
-            timer.tick(&mut mem, 250);
-            timer.tick(&mut mem, 250);
+            timer.tick(255);
         }
+        assert_eq!(timer.read_io(registers::DIV), 3);
+
+        timer.write_io(registers::DIV, 0x42);
+        assert_eq!(timer.read_io(registers::DIV), 0);
+    }
+
+    #[test]
+    fn test_writing_div_can_increment_tima() {
+        let mut timer = Timer::new();
+        timer.write_io(registers::TAC, 0b101);
+        // bit 3 of the counter is set
+        timer.tick(8);
+        timer.write_io(registers::DIV, 0);
+        assert_eq!(timer.read_io(registers::TIMA), 1);
+
+        timer.tick(4);
+        timer.write_io(registers::DIV, 0);
+        assert_eq!(timer.read_io(registers::TIMA), 1);
+    }
+
+    #[test]
+    fn test_disabling_tac_can_increment_tima() {
+        let mut timer = Timer::new();
+        timer.write_io(registers::TAC, 0b101);
+        timer.tick(8);
+        timer.write_io(registers::TAC, 0b001);
+        assert_eq!(timer.read_io(registers::TIMA), 1);
+
+        // bit 9 is low, switching to it is a falling edge too
+        timer.write_io(registers::TAC, 0b101);
+        timer.write_io(registers::TAC, 0b100);
+        assert_eq!(timer.read_io(registers::TIMA), 2);
+    }
+
+    #[test]
+    fn test_writing_tima_cancels_the_reload() {
+        let mut timer = Timer::new();
+        timer.write_io(registers::TAC, 0b101);
+        timer.write_io(registers::TIMA, 0xFF);
+        timer.write_io(registers::TMA, 0x80);
+
+        timer.tick(16);
+        timer.write_io(registers::TIMA, 0x10);
+        assert!(!timer.tick(4));
+        assert_eq!(timer.read_io(registers::TIMA), 0x10);
+    }
+
+    #[test]
+    fn test_tima_follows_tma_during_the_reload() {
+        let mut timer = Timer::new();
+        timer.write_io(registers::TAC, 0b101);
+        timer.write_io(registers::TIMA, 0xFF);
+        timer.write_io(registers::TMA, 0x80);
+
+        timer.tick(16);
+        assert!(timer.tick(4));
+        // TIMA writes are lost, TMA writes land in TIMA
+        timer.write_io(registers::TIMA, 0x10);
+        assert_eq!(timer.read_io(registers::TIMA), 0x80);
+        timer.write_io(registers::TMA, 0x20);
+        assert_eq!(timer.read_io(registers::TIMA), 0x20);
+
+        timer.tick(4);
+        timer.write_io(registers::TIMA, 0x10);
+        assert_eq!(timer.read_io(registers::TIMA), 0x10);
+    }
 
-        assert_eq!(mem.get(0xFF0F), 0b100);
+    #[test]
+    fn test_next_event_is_the_reload() {
+        let mut timer = Timer::new();
+        assert_eq!(timer.next_event(), None);
+
+        timer.write_io(registers::TAC, 0b101);
+        timer.write_io(registers::TIMA, 0xFE);
+        timer.tick(4);
+        let dots = timer.next_event().unwrap();
+        assert_eq!(dots, 12 + 16 + 4);
+        assert!(!timer.tick(dots - 1));
+        assert!(timer.tick(1));
     }
 }
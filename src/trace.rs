@@ -0,0 +1,101 @@
+//! Execution trace in the gameboy-doctor format, one line with the registers and the next 4 bytes
+//! at PC before every instruction: https://github.com/robert/gameboy-doctor
+use crate::byteop::b64;
+use crate::memory::{Memory, MMU};
+use crate::runtime::CpuRegisters;
+use crate::symbols::Symbols;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::rc::Rc;
+
+/// Lines are buffered, and written out at the latest when the trace is dropped.
+pub struct Trace {
+    out: BufWriter<File>,
+    // only instructions with PC in `start..=end` are logged
+    pub pc_range: Option<(u16, u16)>,
+    // only instructions executed within `start..end` machine cycles are logged
+    pub cycle_window: Option<(u64, u64)>,
+    // machine cycles elapsed since the start of the trace
+    pub cycles: u64,
+    // labelled addresses get a ` ; label` suffix
+    pub symbols: Option<Rc<Symbols>>,
+}
+
+/// Range `start-end` of hexadecimal addresses, for the command line.
+pub fn parse_pc_range(arg: &str) -> Result<(u16, u16), String> {
+    let (start, end) = arg.split_once('-').ok_or("expected `start-end`")?;
+    let parse = |val: &str| {
+        u16::from_str_radix(val.trim_start_matches("0x"), 16)
+            .map_err(|_| format!("invalid address `{}´", val))
+    };
+    return Ok((parse(start)?, parse(end)?));
+}
+
+/// Range `start-end` of machine cycles, for the command line.
+pub fn parse_cycle_window(arg: &str) -> Result<(u64, u64), String> {
+    let (start, end) = arg.split_once('-').ok_or("expected `start-end`")?;
+    let parse = |val: &str| val.parse().map_err(|_| format!("invalid cycle `{}´", val));
+    return Ok((parse(start)?, parse(end)?));
+}
+
+impl Trace {
+    pub fn new(filename: &str) -> io::Result<Trace> {
+        let file = File::create(filename)?;
+        return Ok(Trace {
+            out: BufWriter::new(file),
+            pc_range: None,
+            cycle_window: None,
+            cycles: 0,
+            symbols: None,
+        });
+    }
+
+    pub fn log(&mut self, cpu: &CpuRegisters, mem: &MMU) {
+        if let Some((start, end)) = self.pc_range {
+            if cpu.pc < start || cpu.pc > end {
+                return;
+            }
+        }
+        if let Some((start, end)) = self.cycle_window {
+            if self.cycles < start || self.cycles >= end {
+                return;
+            }
+        }
+
+        let pc = cpu.pc;
+        write!(
+            self.out,
+            "{:?} PCMEM:{},{},{},{}",
+            cpu,
+            b64(mem.get(pc)),
+            b64(mem.get(pc.wrapping_add(1))),
+            b64(mem.get(pc.wrapping_add(2))),
+            b64(mem.get(pc.wrapping_add(3))),
+        )
+        .expect("Unable to write the trace");
+
+        let label = match &self.symbols {
+            Some(symbols) => symbols.label(mem.bank_of(pc), pc),
+            None => None,
+        };
+        match label {
+            Some(label) => writeln!(self.out, " ; {}", label),
+            None => writeln!(self.out),
+        }
+        .expect("Unable to write the trace");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ranges() {
+        assert_eq!(parse_pc_range("0150-01FF"), Ok((0x150, 0x1FF)));
+        assert_eq!(parse_pc_range("0x4000-0x7FFF"), Ok((0x4000, 0x7FFF)));
+        assert!(parse_pc_range("0150").is_err());
+        assert_eq!(parse_cycle_window("100-2000"), Ok((100, 2000)));
+        assert!(parse_cycle_window("a-b").is_err());
+    }
+}
@@ -0,0 +1,206 @@
+//! Debug view of the video ram: the 384 tiles, both background maps with the SCX/SCY viewport,
+//! and the window layer, all drawn with the current BGP palette.
+use crate::byteop::get_bit;
+use crate::memory::Memory;
+use crate::ppu::{color_from_code, tile_addr};
+use crate::registers;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+// maps side by side on top, tiles and window below
+const GAP: usize = 8;
+const MAP_SIZE: usize = 256;
+pub const WIDTH: usize = MAP_SIZE * 2 + GAP;
+pub const HEIGHT: usize = MAP_SIZE + GAP + TILES_HEIGHT;
+
+const TILES_PER_ROW: usize = 16;
+const TILES_HEIGHT: usize = 384 / TILES_PER_ROW * 8;
+const WINDOW_X: usize = TILES_PER_ROW * 8 + GAP;
+
+const BACKGROUND: Color = Color::RGB(40, 40, 60);
+const VIEWPORT: Color = Color::RGB(255, 0, 0);
+
+/// Color id (0..=3) of pixel `x`, `y` of the tile at `addr`.
+fn tile_pixel(mem: &impl Memory, addr: u16, x: usize, y: usize) -> u8 {
+    let l = get_bit(mem.get(addr + y as u16 * 2), 7 - x as u8);
+    let h = get_bit(mem.get(addr + y as u16 * 2 + 1), 7 - x as u8);
+    return (h << 1) + l;
+}
+
+pub struct VramView {
+    pub pixels: Vec<Color>,
+    bgp: u8,
+}
+
+impl VramView {
+    pub fn new() -> VramView {
+        VramView {
+            pixels: vec![BACKGROUND; WIDTH * HEIGHT],
+            bgp: 0,
+        }
+    }
+
+    fn set(&mut self, x: usize, y: usize, color: Color) {
+        self.pixels[x + y * WIDTH] = color;
+    }
+
+    fn set_shade(&mut self, x: usize, y: usize, id: u8) {
+        let shade = (self.bgp >> (id * 2)) & 0b11;
+        self.set(x, y, color_from_code(shade as usize));
+    }
+
+    fn draw_tiles(&mut self, mem: &impl Memory) {
+        for tile in 0..384 {
+            let addr = 0x8000 + tile as u16 * 16;
+            let (left, top) = (
+                (tile % TILES_PER_ROW) * 8,
+                MAP_SIZE + GAP + (tile / TILES_PER_ROW) * 8,
+            );
+            for y in 0..8 {
+                for x in 0..8 {
+                    self.set_shade(left + x, top + y, tile_pixel(mem, addr, x, y));
+                }
+            }
+        }
+    }
+
+    /// Draws `width`x`height` pixels of the tile map at `map`, starting from its top left corner.
+    fn draw_map(
+        &mut self,
+        mem: &impl Memory,
+        map: u16,
+        left: usize,
+        top: usize,
+        size: (usize, usize),
+    ) {
+        let mode_8800 = get_bit(mem.get(registers::LCDC), 4) == 0;
+        for y in 0..size.1 {
+            for x in 0..size.0 {
+                let tile = mem.get(map + (y / 8 * 32 + x / 8) as u16);
+                let id = tile_pixel(mem, tile_addr(tile, mode_8800), x % 8, y % 8);
+                self.set_shade(left + x, top + y, id);
+            }
+        }
+    }
+
+    /// Outline of the 160x144 screen over the background map, wrapping around its edges.
+    fn draw_viewport(&mut self, left: usize, scx: u8, scy: u8) {
+        for i in 0..160 {
+            let x = (scx as usize + i) % MAP_SIZE;
+            self.set(left + x, scy as usize, VIEWPORT);
+            self.set(left + x, (scy as usize + 143) % MAP_SIZE, VIEWPORT);
+        }
+        for i in 0..144 {
+            let y = (scy as usize + i) % MAP_SIZE;
+            self.set(left + scx as usize, y, VIEWPORT);
+            self.set(left + (scx as usize + 159) % MAP_SIZE, y, VIEWPORT);
+        }
+    }
+
+    pub fn update(&mut self, mem: &impl Memory) {
+        let lcdc = mem.get(registers::LCDC);
+        self.bgp = mem.get(registers::BGP);
+
+        self.draw_tiles(mem);
+        self.draw_map(mem, 0x9800, 0, 0, (MAP_SIZE, MAP_SIZE));
+        self.draw_map(mem, 0x9C00, MAP_SIZE + GAP, 0, (MAP_SIZE, MAP_SIZE));
+
+        let bg_left = if get_bit(lcdc, 3) == 0 {
+            0
+        } else {
+            MAP_SIZE + GAP
+        };
+        self.draw_viewport(bg_left, mem.get(registers::SCX), mem.get(registers::SCY));
+
+        let window_map = if get_bit(lcdc, 6) == 0 {
+            0x9800
+        } else {
+            0x9C00
+        };
+        self.draw_map(mem, window_map, WINDOW_X, MAP_SIZE + GAP, (160, 144));
+    }
+
+    pub fn render(&self, canvas: &mut Canvas<Window>) {
+        present(canvas, &self.pixels, WIDTH);
+    }
+}
+
+/// Draws the rows of `width` pixels, one batch of rectangles per color.
+pub fn present(canvas: &mut Canvas<Window>, pixels: &[Color], width: usize) {
+    let mut batches: Vec<(Color, Vec<Rect>)> = vec![];
+    for (i, color) in pixels.iter().enumerate() {
+        let rect = Rect::new((i % width) as i32, (i / width) as i32, 1, 1);
+        match batches.iter_mut().find(|(c, _)| c == color) {
+            Some((_, rects)) => rects.push(rect),
+            None => batches.push((*color, vec![rect])),
+        }
+    }
+
+    for (color, rects) in batches {
+        canvas.set_draw_color(color);
+        canvas.fill_rects(&rects).unwrap();
+    }
+    canvas.present();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Ram(Vec<u8>);
+
+    impl Memory for Ram {
+        fn get(&self, addr: u16) -> u8 {
+            return self.0[addr as usize];
+        }
+        fn set(&mut self, addr: u16, val: u8) {
+            self.0[addr as usize] = val;
+        }
+    }
+
+    fn ram() -> Ram {
+        let mut ram = Ram(vec![0; 0x10000]);
+        // identity palette, 0x8000 addressing
+        ram.set(registers::BGP, 0b11100100);
+        ram.set(registers::LCDC, 0b10010001);
+        // tile 1: first row of color 3
+        ram.set(0x8010, 0xFF);
+        ram.set(0x8011, 0xFF);
+        return ram;
+    }
+
+    #[test]
+    fn test_tiles_use_bgp() {
+        let mut ram = ram();
+        let mut view = VramView::new();
+        view.update(&ram);
+        let tile_1 = 8 + (MAP_SIZE + GAP) * WIDTH;
+        assert_eq!(view.pixels[tile_1], color_from_code(3));
+
+        // inverted palette
+        ram.set(registers::BGP, 0b00011011);
+        view.update(&ram);
+        assert_eq!(view.pixels[tile_1], color_from_code(0));
+    }
+
+    #[test]
+    fn test_map_draws_tiles_and_viewport() {
+        let mut ram = ram();
+        ram.set(0x9801, 1);
+        ram.set(registers::SCX, 100);
+        ram.set(registers::SCY, 50);
+
+        let mut view = VramView::new();
+        view.update(&ram);
+        assert_eq!(view.pixels[8], color_from_code(3));
+        assert_eq!(view.pixels[7], color_from_code(0));
+
+        assert_eq!(view.pixels[100 + 50 * WIDTH], VIEWPORT);
+        // the right edge wraps around the map
+        assert_eq!(view.pixels[(100 + 159) % 256 + 60 * WIDTH], VIEWPORT);
+        // the viewport is drawn only over the map in use
+        assert_ne!(view.pixels[MAP_SIZE + GAP + 100 + 50 * WIDTH], VIEWPORT);
+    }
+}